@@ -1 +1,456 @@
-// TODO: implement later
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    mem,
+};
+
+use firestore::{
+    errors::FirestoreError, FirestoreConsistencySelector, FirestoreDb, FirestoreDbOptions,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use serde_json::Value;
+
+use super::memory::{check_precondition, merge_fields, Document};
+use crate::transaction::{
+    BatchTrxOpErr, Batchable, Coalesced, FullOpenTrxHandle, PlannedOp, Precondition, RetryPolicy,
+    TimerClock, Transaction, TrxCommitErr, TrxDbErr, TrxErrCode, TrxRevertErr,
+};
+
+thread_local! {
+    pub static FIRESTORE_HANDLE: OnceCell<FirestoreDbRef> = OnceCell::new();
+}
+
+/// This needs to be called once at startup before creating any transactions.
+///
+/// Reads `GOOGLE_PROJECT_ID`, and optionally `FIRESTORE_API_URL`.
+/// Setting `FIRESTORE_EMULATOR_HOST` connects to the local Firestore emulator instead.
+pub async fn init_firestore() -> Result<FirestoreDbRef, String> {
+    let db = FirestoreDbRef::from_env().await?;
+    FIRESTORE_HANDLE.with(|cell| {
+        let _ = cell.set(db.clone());
+    });
+    Ok(db)
+}
+
+pub fn get_firestore() -> FirestoreDbRef {
+    FIRESTORE_HANDLE.with(|cell| {
+        cell.get()
+            .expect("firestore must be initialized before calling `get_firestore()`")
+            .clone()
+    })
+}
+
+fn config_env_var(name: &str) -> Result<String, String> {
+    std::env::var(name).map_err(|e| format!("{name}: {e}"))
+}
+
+/// Native counterpart of `FireDbRef`, committing transactions through the Firestore API
+#[derive(Clone)]
+pub struct FirestoreDbRef(FirestoreDb);
+
+impl FirestoreDbRef {
+    pub fn new(db: FirestoreDb) -> Self {
+        Self(db)
+    }
+
+    pub async fn from_env() -> Result<Self, String> {
+        let google_project_id = config_env_var("GOOGLE_PROJECT_ID")?;
+        let options = FirestoreDbOptions::new(google_project_id)
+            .with_max_retries(3)
+            .opt_firebase_api_url(std::env::var("FIRESTORE_API_URL").ok());
+
+        log::debug!("Trying to connect to FirestoreDb");
+        let db = FirestoreDb::with_options(options)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Self(db))
+    }
+
+    pub fn db(&self) -> &FirestoreDb {
+        &self.0
+    }
+
+    /// Transaction retrying transient commit failures with the default `RetryPolicy`
+    pub fn trx(&self, name: &str) -> Transaction<FirestoreDbRef> {
+        let mut transaction = Transaction::new(self.clone(), name);
+        transaction.set_retry_policy(RetryPolicy::default(), TimerClock);
+        transaction
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Option<Document>, TrxDbErr> {
+        read_document(&self.0, path).await
+    }
+}
+
+/// Splits `collection/id[/collection/id...]` into the full parent path, the collection and the id
+fn split_path(db: &FirestoreDb, path: &str) -> Result<(String, String, String), TrxDbErr> {
+    let mut segments = path.trim_matches('/').rsplitn(3, '/');
+    let (Some(id), Some(collection)) = (segments.next(), segments.next()) else {
+        let msg = format!("Invalid document path({path})");
+        return Err(TrxDbErr::new(TrxErrCode::InvalidArgument, msg).with_path(path));
+    };
+    let parent = match segments.next() {
+        Some(parent) => format!("{}/{}", db.get_documents_path(), parent),
+        None => db.get_documents_path().to_string(),
+    };
+    Ok((parent, collection.to_string(), id.to_string()))
+}
+
+/// Leaf field paths of `data`, so a merge-set deep merges nested maps like `{ merge: true }` does
+fn merge_field_paths(data: &Document, prefix: &str, paths: &mut Vec<String>) {
+    for (key, value) in data {
+        let path = if prefix.is_empty() {
+            format!("`{}`", key.replace('`', "\\`"))
+        } else {
+            format!("{prefix}.`{}`", key.replace('`', "\\`"))
+        };
+        match value {
+            Value::Object(map) if !map.is_empty() => merge_field_paths(map, &path, paths),
+            _ => paths.push(path),
+        }
+    }
+}
+
+/// Estimated size of a value, following the Firestore storage size rules
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Null | Value::Bool(_) => 1,
+        Value::Number(_) => 8,
+        Value::String(s) => s.len() + 1,
+        Value::Array(values) => values.iter().map(value_size).sum(),
+        Value::Object(map) => document_size(map),
+    }
+}
+
+fn document_size(data: &Document) -> usize {
+    data.iter().map(|(k, v)| k.len() + 1 + value_size(v)).sum()
+}
+
+fn db_err(e: FirestoreError) -> TrxDbErr {
+    let code = match &e {
+        FirestoreError::DataConflictError(_) => TrxErrCode::AlreadyExists,
+        FirestoreError::DataNotFoundError(_) => TrxErrCode::NotFound,
+        FirestoreError::InvalidParametersError(_) => TrxErrCode::InvalidArgument,
+        FirestoreError::NetworkError(_) => TrxErrCode::Unavailable,
+        FirestoreError::DatabaseError(e) if e.retry_possible => TrxErrCode::Unavailable,
+        _ => TrxErrCode::Unknown,
+    };
+    TrxDbErr::new(code, e.to_string())
+}
+
+async fn read_document(db: &FirestoreDb, path: &str) -> Result<Option<Document>, TrxDbErr> {
+    let (parent, collection, id) = split_path(db, path)?;
+    db.fluent()
+        .select()
+        .by_id_in(&collection)
+        .parent(&parent)
+        .obj::<Document>()
+        .one(&id)
+        .await
+        .map_err(|e| db_err(e).with_path(path))
+}
+
+#[derive(Debug, Clone)]
+pub enum FirestoreOp {
+    SetForRef {
+        path: String,
+        data: Document,
+        merge: bool,
+    },
+    /// A merge-set, creating the document if it does not exist, which fails if its
+    /// precondition does not hold
+    Update {
+        path: String,
+        data: Document,
+        precondition: Option<Precondition>,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+impl FirestoreOp {
+    pub fn path(&self) -> &str {
+        match self {
+            FirestoreOp::SetForRef { path, .. }
+            | FirestoreOp::Update { path, .. }
+            | FirestoreOp::Delete { path } => path,
+        }
+    }
+}
+
+/// Transaction operations, mirroring `TrxFireOp`
+#[derive(Debug)]
+pub enum FirestoreTrxOp {
+    Insert {
+        path: String,
+        data: Document,
+    },
+    Update {
+        path: String,
+        data: Document,
+        precondition: Option<Precondition>,
+    },
+    SetForRef {
+        path: String,
+        data: Document,
+        merge: bool,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+pub struct FirestoreBatch {
+    db: FirestoreDb,
+    ops: RefCell<Vec<FirestoreOp>>,
+    /// Documents as they were before this batch was committed, `None` if they did not exist
+    before: RefCell<HashMap<String, Option<Document>>>,
+}
+
+impl FirestoreBatch {
+    /// Writes are sent in a single commit, so they are applied atomically like a `WriteBatch`.
+    /// Preconditions are checked against documents read within the same Firestore transaction,
+    /// so the commit fails if any of them changed before the writes landed.
+    async fn commit(&self, ops: Vec<FirestoreOp>) -> Result<(), TrxCommitErr> {
+        let db = &self.db;
+        let mut transaction = db.begin_transaction().await.map_err(db_err)?;
+
+        // preconditions are checked against the documents as they were before the batch
+        let reader = db.clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+            transaction.transaction_id().clone(),
+        ));
+        for (i, op) in ops.iter().enumerate() {
+            let FirestoreOp::Update {
+                path,
+                precondition: Some(precondition),
+                ..
+            } = op
+            else {
+                continue;
+            };
+            let doc = read_document(&reader, path)
+                .await
+                .map_err(|e| e.with_op_index(i))?;
+            check_precondition(path, precondition, doc.as_ref()).map_err(|e| e.with_op_index(i))?;
+        }
+
+        for (i, op) in ops.into_iter().enumerate() {
+            let (parent, collection, id) =
+                split_path(db, op.path()).map_err(|e| e.with_op_index(i))?;
+            match op {
+                FirestoreOp::SetForRef { data, merge, .. } => {
+                    let mut fields = Vec::new();
+                    if merge {
+                        merge_field_paths(&data, "", &mut fields);
+                    }
+                    let update = db.fluent().update();
+                    let update = if merge { update.fields(fields) } else { update };
+                    update
+                        .in_col(&collection)
+                        .document_id(&id)
+                        .parent(&parent)
+                        .object(&data)
+                        .add_to_transaction(&mut transaction)
+                        .map_err(|e| db_err(e).with_op_index(i))?;
+                }
+                FirestoreOp::Update { data, .. } => {
+                    let mut fields = Vec::new();
+                    merge_field_paths(&data, "", &mut fields);
+                    db.fluent()
+                        .update()
+                        .fields(fields)
+                        .in_col(&collection)
+                        .document_id(&id)
+                        .parent(&parent)
+                        .object(&data)
+                        .add_to_transaction(&mut transaction)
+                        .map_err(|e| db_err(e).with_op_index(i))?;
+                }
+                FirestoreOp::Delete { .. } => {
+                    db.fluent()
+                        .delete()
+                        .from(&collection)
+                        .document_id(&id)
+                        .parent(&parent)
+                        .add_to_transaction(&mut transaction)
+                        .map_err(|e| db_err(e).with_op_index(i))?;
+                }
+            }
+        }
+
+        transaction.commit().await.map_err(db_err)?;
+        Ok(())
+    }
+}
+
+impl Batchable for FirestoreDbRef {
+    type Op = FirestoreOp;
+    type TrxOp = FirestoreTrxOp;
+    type Child = FirestoreBatch;
+    type Extras = ();
+
+    /// Firestore rejects requests over 10 MiB, keep some room for the estimation errors
+    const BYTE_LIMIT: usize = 9 * 1024 * 1024;
+
+    fn child(&self) -> Result<FirestoreBatch, String> {
+        Ok(FirestoreBatch {
+            db: self.0.clone(),
+            ops: Default::default(),
+            before: Default::default(),
+        })
+    }
+
+    fn insert(child: &FirestoreBatch, op: FirestoreOp) {
+        child.ops.borrow_mut().push(op);
+    }
+
+    fn commit(child: &FirestoreBatch) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+        async {
+            let ops = child.ops.borrow().clone();
+            child.commit(ops).await
+        }
+        .boxed_local()
+    }
+
+    fn op_path(op: &FirestoreOp) -> String {
+        op.path().to_string()
+    }
+
+    fn op_precondition(op: &FirestoreOp) -> Option<&Precondition> {
+        match op {
+            FirestoreOp::Update { precondition, .. } => precondition.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn op_size(op: &FirestoreOp) -> usize {
+        // document name, plus the fixed overhead of a document
+        let size = op.path().len() + 1 + 32;
+        match op {
+            FirestoreOp::SetForRef { data, .. } | FirestoreOp::Update { data, .. } => {
+                size + document_size(data)
+            }
+            FirestoreOp::Delete { .. } => size,
+        }
+    }
+
+    fn explain_op(op: &FirestoreOp) -> PlannedOp {
+        match op {
+            FirestoreOp::SetForRef { path, data, merge } => PlannedOp::new(path, "set")
+                .with_merge(*merge)
+                .with_payload(Value::Object(data.clone())),
+            FirestoreOp::Update {
+                path,
+                data,
+                precondition,
+            } => PlannedOp::new(path, "update")
+                .with_merge(true)
+                .with_payload(Value::Object(data.clone()))
+                .with_precondition(precondition.clone()),
+            FirestoreOp::Delete { path } => PlannedOp::new(path, "delete"),
+        }
+    }
+
+    fn coalesce(prev: &mut FirestoreOp, next: FirestoreOp) -> Coalesced<FirestoreOp> {
+        use FirestoreOp::*;
+        match (&mut *prev, next) {
+            // an update still has to check its precondition
+            (Update { .. }, next @ SetForRef { merge: false, .. })
+            | (Delete { .. }, next @ Update { .. }) => return Coalesced::Separate(next),
+            (_, next @ (Delete { .. } | SetForRef { merge: false, .. })) => *prev = next,
+            (
+                SetForRef { data, .. } | Update { data, .. },
+                SetForRef { data: next, .. } | Update { data: next, .. },
+            ) => merge_fields(data, next),
+            (Delete { path }, SetForRef { data, .. }) => {
+                let path = mem::take(path);
+                *prev = SetForRef {
+                    path,
+                    data,
+                    merge: false,
+                };
+            }
+        }
+        Coalesced::Merged
+    }
+
+    fn capture(child: &FirestoreBatch) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+        async {
+            let paths: Vec<String> = child
+                .ops
+                .borrow()
+                .iter()
+                .map(|op| op.path().to_string())
+                .collect();
+            for path in paths {
+                if child.before.borrow().contains_key(&path) {
+                    continue;
+                }
+                let doc = read_document(&child.db, &path)
+                    .await
+                    .map_err(TrxCommitErr::Db)?;
+                child.before.borrow_mut().insert(path, doc);
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn revert(child: &FirestoreBatch) -> LocalBoxFuture<'_, Result<(), TrxRevertErr>> {
+        async {
+            let before = std::mem::take(&mut *child.before.borrow_mut());
+            if before.is_empty() {
+                return Err(TrxRevertErr::Unsupported);
+            }
+            let paths: Vec<String> = before.keys().cloned().collect();
+            let ops = before
+                .into_iter()
+                .map(|(path, doc)| match doc {
+                    Some(data) => FirestoreOp::SetForRef {
+                        path,
+                        data,
+                        merge: false,
+                    },
+                    None => FirestoreOp::Delete { path },
+                })
+                .collect();
+
+            match child.commit(ops).await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    log::error!("Unable to revert batch: {e}");
+                    Err(TrxRevertErr::Unrestored(paths))
+                }
+            }
+        }
+        .boxed_local()
+    }
+
+    fn process_trx_op(
+        txh: FullOpenTrxHandle<Self>,
+        op: FirestoreTrxOp,
+    ) -> Result<(), BatchTrxOpErr> {
+        let op = match op {
+            FirestoreTrxOp::Insert { path, data } => FirestoreOp::SetForRef {
+                path,
+                data,
+                merge: true,
+            },
+            FirestoreTrxOp::Update {
+                path,
+                data,
+                precondition,
+            } => FirestoreOp::Update {
+                path,
+                data,
+                precondition,
+            },
+            FirestoreTrxOp::SetForRef { path, data, merge } => {
+                FirestoreOp::SetForRef { path, data, merge }
+            }
+            FirestoreTrxOp::Delete { path } => FirestoreOp::Delete { path },
+        };
+        txh.batch_operation(op)
+    }
+}
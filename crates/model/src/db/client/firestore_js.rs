@@ -1,15 +1,23 @@
 use crate::{
-    db::firestore_js::js_firebase::TrxFireOp,
+    db::{firestore_js::js_firebase::TrxFireOp, indexed_db::IndexedDbQueue},
     entity::{DocumentRef, JsEntity},
-    session_manager::use_session_manager,
+    session_manager::{use_session_manager, RetryFn},
+    timer::JsClock,
     transaction::{
-        ActiveTrxStore, Open, Ref, Transaction, TrxApplyErr, TrxHandle, TrxRef, Unknown,
+        ActiveTrxStore, BatchTrxOpErr, OfflineQueue, OpId, Open, Ref, RetryPolicy, Transaction,
+        TrxActivity, TrxApplyErr, TrxCommitErr, TrxErrCode, TrxHandle, TrxRef, TrxSummary, Unknown,
     },
 };
 
 use futures::FutureExt;
 use js_sys::{Array, JsString};
-use std::{cell::OnceCell, ops::Deref};
+use observable_react::JsObservable;
+use std::{
+    cell::{Cell, OnceCell},
+    ops::Deref,
+    rc::Rc,
+    time::Duration,
+};
 use wasm_bindgen::prelude::*;
 
 mod closures {
@@ -163,15 +171,36 @@ type OnAccessDenied = (msg: string) => void;
 
 mod js_firebase {
     use futures::{future::LocalBoxFuture, lock::Mutex, FutureExt};
-    use std::{collections::HashSet, fmt::Debug, sync::Arc};
-    use wasm_bindgen::prelude::{wasm_bindgen, JsValue};
+    use std::{collections::HashSet, fmt::Debug, rc::Rc, sync::Arc, time::Duration};
+    use wasm_bindgen::{
+        prelude::{wasm_bindgen, JsValue},
+        JsCast,
+    };
 
     use crate::{
         entity::{DocumentRef, JsEntity},
-        transaction::{BatchTrxOpErr, Batchable, FullOpenTrxHandle, TrxCommitErr},
+        transaction::{
+            BatchTrxOpErr, Batchable, Coalesced, FullOpenTrxHandle, PlannedOp, Precondition,
+            TraceSink, TrxCommitErr, TrxEvent, TrxRevertErr,
+        },
     };
 
-    use super::sanitize_object_without_undefined;
+    use super::{js_trx_error, js_value_size, merge_js_objects, strip_undefined};
+
+    /// Strip the undefined values of `data`, reporting each of them as a warning
+    fn sanitize_data(
+        txh: &FullOpenTrxHandle<FireDbRef>,
+        path: &str,
+        data: js_sys::Object,
+    ) -> js_sys::Object {
+        let (data, stripped) = strip_undefined(data);
+        for key in stripped {
+            txh.add_warning(format!(
+                "Key {key} of {path} had an undefined value, this may not have been intentional"
+            ));
+        }
+        data
+    }
 
     #[wasm_bindgen]
     extern "C" {
@@ -188,10 +217,89 @@ mod js_firebase {
         pub fn new() -> FireBatch;
         #[wasm_bindgen(method)]
         fn set(this: &FireBatch, doc_ref: DocumentRef, data: js_sys::Object, merge: bool);
+        /// Merge-set `data`, committed only if `precondition` holds
+        #[wasm_bindgen(method)]
+        fn update(
+            this: &FireBatch,
+            doc_ref: DocumentRef,
+            data: js_sys::Object,
+            precondition: JsValue,
+        );
         #[wasm_bindgen(method)]
         fn delete(this: &FireBatch, doc_ref: DocumentRef);
+        #[wasm_bindgen(method, catch)]
+        async fn commit(this: &FireBatch) -> Result<JsValue, JsValue>;
+        #[wasm_bindgen(method, catch)]
+        async fn capture(this: &FireBatch) -> Result<JsValue, JsValue>;
+        /// Resolves to the paths of the documents that could not be restored
         #[wasm_bindgen(method)]
-        async fn commit(this: &FireBatch);
+        async fn revert(this: &FireBatch) -> JsValue;
+
+        /// Serialize a set, or a delete without `data`, for the offline queue
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="encodeOp")]
+        fn encode_op(
+            doc_ref: &DocumentRef,
+            data: Option<js_sys::Object>,
+            merge: bool,
+            precondition: JsValue,
+        ) -> Option<String>;
+        /// Returns `{ docRef, data?, merge, precondition?, delete }`
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="decodeOp", catch)]
+        fn decode_op(encoded: &str) -> Result<JsValue, JsValue>;
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="appliedMarker")]
+        fn applied_marker(key: &str) -> DocumentRef;
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="isApplied", catch)]
+        async fn is_applied(key: &str) -> Result<JsValue, JsValue>;
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="clearApplied", catch)]
+        async fn clear_applied(key: &str) -> Result<JsValue, JsValue>;
+
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="isFieldValue")]
+        pub fn is_field_value(value: &JsValue) -> bool;
+        /// The field value sentinel applying `next` on top of `current`, or undefined if the
+        /// outcome depends on the stored document
+        #[wasm_bindgen(js_namespace = edvocommon, static_method_of = FireBatch, js_name="combineFieldValues")]
+        pub fn combine_field_values(current: &JsValue, next: &JsValue) -> JsValue;
+    }
+
+    /// `{ exists: true }` or `{ updatedAt: millis }`, as expected by `FireBatch.update`
+    pub fn precondition_to_js(precondition: &Precondition) -> JsValue {
+        let js = js_sys::Object::new();
+        let _ = match precondition {
+            Precondition::Exists => js_sys::Reflect::set(&js, &"exists".into(), &true.into()),
+            Precondition::UpdatedAt(millis) => {
+                js_sys::Reflect::set(&js, &"updatedAt".into(), &(*millis as f64).into())
+            }
+        };
+        js.into()
+    }
+
+    /// Inverse of `precondition_to_js`, `updatedAt` being either milliseconds or a `Timestamp`
+    pub fn precondition_from_js(value: &JsValue) -> Result<Precondition, String> {
+        let get = |key: &str| js_sys::Reflect::get(value, &key.into()).unwrap_or_default();
+        let updated_at = get("updatedAt");
+        if let Some(millis) = updated_at.as_f64() {
+            return Ok(Precondition::UpdatedAt(millis as i64));
+        }
+        let to_millis = js_sys::Reflect::get(&updated_at, &"toMillis".into()).unwrap_or_default();
+        if let Some(to_millis) = to_millis.dyn_ref::<js_sys::Function>() {
+            return match to_millis.call0(&updated_at).ok().and_then(|m| m.as_f64()) {
+                Some(millis) => Ok(Precondition::UpdatedAt(millis as i64)),
+                None => Err(format!("Invalid updatedAt precondition: {updated_at:?}")),
+            };
+        }
+        if get("exists").is_truthy() {
+            return Ok(Precondition::Exists);
+        }
+        Err(format!("Invalid precondition: {value:?}"))
+    }
+
+    /// JSON form of the data of an op, for dry runs
+    fn json_payload(data: &js_sys::Object) -> serde_json::Value {
+        js_sys::JSON::stringify(data)
+            .ok()
+            .and_then(|json| json.as_string())
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
     }
 
     impl Default for FireBatch {
@@ -206,6 +314,8 @@ mod js_firebase {
     #[derive(Default)]
     pub struct JsFireBatchExtras {
         inserteds: Arc<Mutex<HashSet<String>>>,
+        /// Documents inserted by the enclosing transactions, for the extras of a savepoint
+        parent_inserteds: Vec<Arc<Mutex<HashSet<String>>>>,
     }
 
     impl JsFireBatchExtras {
@@ -215,8 +325,9 @@ mod js_firebase {
         }
 
         fn has_inserted(&self, path: &str) -> bool {
-            let set = self.inserteds.try_lock().unwrap();
-            set.contains(path)
+            std::iter::once(&self.inserteds)
+                .chain(&self.parent_inserteds)
+                .any(|set| set.try_lock().unwrap().contains(path))
         }
     }
 
@@ -232,17 +343,40 @@ mod js_firebase {
         type Child = FireBatch;
         type Extras = JsFireBatchExtras;
 
+        /// Firestore rejects requests over 10 MiB, keep some room for the estimation errors
+        const BYTE_LIMIT: usize = 9 * 1024 * 1024;
+
         fn child(&self) -> Result<FireBatch, String> {
             Ok(FireBatch::new())
         }
 
+        fn savepoint_extras(parent: &JsFireBatchExtras) -> JsFireBatchExtras {
+            let mut parent_inserteds = vec![parent.inserteds.clone()];
+            parent_inserteds.extend(parent.parent_inserteds.iter().cloned());
+            JsFireBatchExtras {
+                inserteds: Default::default(),
+                parent_inserteds,
+            }
+        }
+
+        fn release_extras(savepoint: &JsFireBatchExtras, parent: &JsFireBatchExtras) {
+            let inserteds = std::mem::take(&mut *savepoint.inserteds.try_lock().unwrap());
+            parent.inserteds.try_lock().unwrap().extend(inserteds);
+        }
+
         fn insert(child: &FireBatch, op: Self::Op) {
             match op {
+                FireOp::Insert { doc_ref, data } => child.set(doc_ref, data, true),
                 FireOp::SetForRef {
                     doc_ref,
                     data,
                     merge,
                 } => child.set(doc_ref, data, merge),
+                FireOp::Update {
+                    doc_ref,
+                    data,
+                    precondition,
+                } => child.update(doc_ref, data, precondition_to_js(&precondition)),
                 FireOp::Delete { doc_ref } => child.delete(doc_ref),
             }
         }
@@ -253,7 +387,7 @@ mod js_firebase {
             match op {
                 TrxFireOp::Insert { entity, data } => {
                     let path = entity.path();
-                    let data = sanitize_object_without_undefined(data);
+                    let data = sanitize_data(&txh, &path, data);
                     let doc_ref = entity.doc_ref();
 
                     let _ = txh.add_on_commit_hook(Box::new(move || {
@@ -261,21 +395,21 @@ mod js_firebase {
                         None
                     }));
 
-                    txh.batch_operation(FireOp::SetForRef {
-                        doc_ref,
-                        data,
-                        merge: true,
-                    })?;
+                    txh.batch_operation(FireOp::Insert { doc_ref, data })?;
                     txh.extras().add_inserted(path);
                 }
-                TrxFireOp::Update { entity, data } => {
+                TrxFireOp::Update {
+                    entity,
+                    data,
+                    precondition,
+                } => {
                     let path = entity.path();
                     if !entity.is_editable() {
                         return Err(BatchTrxOpErr::AccessDenied(format!(
                             "Attempt to update non-editable entity({path})"
                         )));
                     }
-                    let data = sanitize_object_without_undefined(data);
+                    let data = sanitize_data(&txh, &path, data);
                     if let Ok(false) = js_sys::Reflect::has(&data, &"updatedAt".into()) {
                         let _ = js_sys::Reflect::set(&data, &"updatedAt".into(), &firebase_now());
                     }
@@ -288,11 +422,18 @@ mod js_firebase {
                             }
 
                             let doc_ref = entity.doc_ref();
-                            txh.process_op(TrxFireOp::SetForRef {
-                                doc_ref,
-                                data,
-                                merge: true,
-                            })
+                            match precondition {
+                                Some(precondition) => txh.batch_operation(FireOp::Update {
+                                    doc_ref,
+                                    data,
+                                    precondition,
+                                }),
+                                None => txh.process_op(TrxFireOp::SetForRef {
+                                    doc_ref,
+                                    data,
+                                    merge: true,
+                                }),
+                            }
                         }
                     };
                     txh.add_future_op(future);
@@ -321,54 +462,263 @@ mod js_firebase {
         }
         fn commit(child: &FireBatch) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
             async {
-                child.commit().await;
-                Ok(())
+                match child.commit().await {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(js_trx_error::to_commit_err(&e)),
+                }
             }
             .boxed_local()
         }
 
-        // TODO: make this reusable
-        fn trace_messages(level: u8, messages: &[&dyn std::fmt::Display]) {
-            #[wasm_bindgen]
-            extern "C" {
-                type TraceState;
+        fn op_path(op: &FireOp) -> String {
+            match op {
+                FireOp::Insert { doc_ref, .. }
+                | FireOp::SetForRef { doc_ref, .. }
+                | FireOp::Update { doc_ref, .. }
+                | FireOp::Delete { doc_ref } => doc_ref.path(),
+            }
+        }
 
-                #[wasm_bindgen(method, getter)]
-                fn level(this: &TraceState) -> u8;
-                #[wasm_bindgen(method, getter)]
-                fn regex(this: &TraceState) -> js_sys::RegExp;
+        fn op_precondition(op: &FireOp) -> Option<&Precondition> {
+            match op {
+                FireOp::Update { precondition, .. } => Some(precondition),
+                _ => None,
+            }
+        }
 
-                #[wasm_bindgen(js_namespace = globalThis, js_name = "getTraceState")]
-                fn get_trace_state() -> TraceState;
+        fn explain_op(op: &FireOp) -> PlannedOp {
+            match op {
+                FireOp::Insert { doc_ref, data } => PlannedOp::new(doc_ref.path(), "set")
+                    .with_merge(true)
+                    .with_payload(json_payload(data)),
+                FireOp::SetForRef {
+                    doc_ref,
+                    data,
+                    merge,
+                } => PlannedOp::new(doc_ref.path(), "set")
+                    .with_merge(*merge)
+                    .with_payload(json_payload(data)),
+                FireOp::Update {
+                    doc_ref,
+                    data,
+                    precondition,
+                } => PlannedOp::new(doc_ref.path(), "update")
+                    .with_merge(true)
+                    .with_payload(json_payload(data))
+                    .with_precondition(Some(precondition.clone())),
+                FireOp::Delete { doc_ref } => PlannedOp::new(doc_ref.path(), "delete"),
             }
+        }
 
-            let trace_state = get_trace_state();
-            if trace_state.level() < level {
-                return;
+        fn op_size(op: &FireOp) -> usize {
+            // document name, plus the fixed overhead of a document
+            let size = Self::op_path(op).len() + 1 + 32;
+            match op {
+                FireOp::Insert { data, .. }
+                | FireOp::SetForRef { data, .. }
+                | FireOp::Update { data, .. } => size + js_value_size(data),
+                FireOp::Delete { .. } => size,
             }
-            let mut msgs = Vec::new();
-            let mut found = false;
-            for msg in messages {
-                let msg = msg.to_string();
-                if !found {
-                    found = trace_state.regex().test(&msg);
+        }
+
+        fn coalesce(prev: &mut FireOp, next: FireOp) -> Coalesced<FireOp> {
+            use FireOp::*;
+            match (&mut *prev, next) {
+                // ops with a precondition are not coalesced
+                (Update { .. }, next) | (_, next @ Update { .. }) => {
+                    return Coalesced::Separate(next)
+                }
+                // the document is never created
+                (Insert { .. }, Delete { .. }) => return Coalesced::Cancelled,
+                (_, next @ (Delete { .. } | SetForRef { merge: false, .. })) => *prev = next,
+                (Delete { doc_ref }, Insert { data, .. } | SetForRef { data, .. }) => {
+                    *prev = SetForRef {
+                        doc_ref: doc_ref.clone(),
+                        data,
+                        merge: false,
+                    }
+                }
+                (Insert { data, .. } | SetForRef { data, .. }, next) => {
+                    let merged = match &next {
+                        Insert { data: next, .. } | SetForRef { data: next, .. } => {
+                            merge_js_objects(data, next)
+                        }
+                        _ => None,
+                    };
+                    // field value sentinels do not always combine, the writes then stay apart
+                    match merged {
+                        Some(merged) => *data = merged,
+                        None => return Coalesced::Separate(next),
+                    }
                 }
-                msgs.push(msg)
             }
-            if !found {
-                return;
+            Coalesced::Merged
+        }
+
+        fn capture(child: &FireBatch) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+            async {
+                match child.capture().await {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("Unable to capture the batch documents: {e:?}").into()),
+                }
             }
-            log::info!("level({level}): {msgs:?}");
+            .boxed_local()
+        }
+
+        fn revert(child: &FireBatch) -> LocalBoxFuture<'_, Result<(), TrxRevertErr>> {
+            async {
+                let unrestored: Vec<String> = js_sys::Array::from(&child.revert().await)
+                    .iter()
+                    .filter_map(|path| path.as_string())
+                    .collect();
+                if unrestored.is_empty() {
+                    Ok(())
+                } else {
+                    Err(TrxRevertErr::Unrestored(unrestored))
+                }
+            }
+            .boxed_local()
+        }
+
+        fn trace_sink(&self) -> Rc<dyn TraceSink> {
+            Rc::new(JsTraceSink)
+        }
+
+        fn encode_op(op: &FireOp) -> Option<String> {
+            match op {
+                FireOp::Insert { doc_ref, data } => {
+                    FireBatch::encode_op(doc_ref, Some(data.clone()), true, JsValue::UNDEFINED)
+                }
+                FireOp::SetForRef {
+                    doc_ref,
+                    data,
+                    merge,
+                } => FireBatch::encode_op(doc_ref, Some(data.clone()), *merge, JsValue::UNDEFINED),
+                FireOp::Update {
+                    doc_ref,
+                    data,
+                    precondition,
+                } => FireBatch::encode_op(
+                    doc_ref,
+                    Some(data.clone()),
+                    true,
+                    precondition_to_js(precondition),
+                ),
+                FireOp::Delete { doc_ref } => {
+                    FireBatch::encode_op(doc_ref, None, false, JsValue::UNDEFINED)
+                }
+            }
+        }
+
+        fn decode_op(encoded: &str) -> Option<FireOp> {
+            let decoded = FireBatch::decode_op(encoded).ok()?;
+            let get = |key: &str| js_sys::Reflect::get(&decoded, &key.into()).ok();
+            let doc_ref: DocumentRef = get("docRef")?.unchecked_into();
+            let precondition = get("precondition").filter(|p| p.is_object());
+            let data = get("data").filter(|data| data.is_object());
+            match (data, get("delete").and_then(|delete| delete.as_bool())) {
+                (None, Some(true)) => Some(FireOp::Delete { doc_ref }),
+                (Some(data), None | Some(false)) if precondition.is_some() => {
+                    Some(FireOp::Update {
+                        doc_ref,
+                        data: data.unchecked_into(),
+                        precondition: precondition_from_js(&precondition?).ok()?,
+                    })
+                }
+                (Some(data), None | Some(false)) => Some(FireOp::SetForRef {
+                    doc_ref,
+                    data: data.unchecked_into(),
+                    merge: get("merge")?.as_bool()?,
+                }),
+                // a corrupt entry, which must not turn into a delete
+                _ => None,
+            }
+        }
+
+        fn applied_marker(key: &str) -> Option<FireOp> {
+            let data = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&data, &"appliedAt".into(), &firebase_now());
+            Some(FireOp::SetForRef {
+                doc_ref: FireBatch::applied_marker(key),
+                data,
+                merge: false,
+            })
+        }
+
+        fn is_applied(&self, key: String) -> LocalBoxFuture<'_, Result<bool, TrxCommitErr>> {
+            async move {
+                match FireBatch::is_applied(&key).await {
+                    Ok(applied) => Ok(applied.is_truthy()),
+                    Err(e) => Err(js_trx_error::to_db_err(&e).into()),
+                }
+            }
+            .boxed_local()
+        }
+
+        fn clear_applied(&self, key: String) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+            async move {
+                match FireBatch::clear_applied(&key).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(js_trx_error::to_db_err(&e).into()),
+                }
+            }
+            .boxed_local()
         }
     }
 
-    #[derive(Debug)]
+    #[wasm_bindgen]
+    extern "C" {
+        type TraceState;
+
+        #[wasm_bindgen(method, getter)]
+        fn level(this: &TraceState) -> u8;
+        #[wasm_bindgen(method, getter)]
+        fn regex(this: &TraceState) -> js_sys::RegExp;
+
+        #[wasm_bindgen(js_namespace = globalThis, js_name = "getTraceState")]
+        fn get_trace_state() -> TraceState;
+    }
+
+    /// Default sink of `FireDbRef`, logging the events whose level and transaction name or
+    /// message match `globalThis.getTraceState()`
+    pub struct JsTraceSink;
+
+    impl TraceSink for JsTraceSink {
+        fn enabled(&self, level: u8) -> bool {
+            get_trace_state().level() >= level
+        }
+
+        fn record(&self, _id: u32, trx: &str, event: &TrxEvent) {
+            let regex = get_trace_state().regex();
+            let msg = event.to_string();
+            if regex.test(trx) || regex.test(&msg) {
+                log::info!("level({}): {trx}: {msg}", event.level());
+            }
+        }
+
+        fn now(&self) -> Duration {
+            crate::utils::clock::now()
+        }
+    }
+
+    #[derive(Debug, Clone)]
     pub enum FireOp {
+        /// Merge-set of a document inserted by the transaction
+        Insert {
+            doc_ref: DocumentRef,
+            data: js_sys::Object,
+        },
         SetForRef {
             doc_ref: DocumentRef,
             data: js_sys::Object,
             merge: bool,
         },
+        /// Merge-set committed only if its precondition holds
+        Update {
+            doc_ref: DocumentRef,
+            data: js_sys::Object,
+            precondition: Precondition,
+        },
         Delete {
             doc_ref: DocumentRef,
         },
@@ -383,6 +733,7 @@ mod js_firebase {
         Update {
             entity: JsEntity,
             data: js_sys::Object,
+            precondition: Option<Precondition>,
         },
         SetForRef {
             doc_ref: DocumentRef,
@@ -394,11 +745,13 @@ mod js_firebase {
         },
     }
 }
-pub use js_firebase::FireBatch;
+pub use js_firebase::{FireBatch, JsTraceSink};
 
 #[wasm_bindgen(js_name = "Transaction")]
 pub struct JsTransaction {
     trx: Option<Transaction<FireDbRef>>,
+    /// Name of the transaction, tracked by the session manager
+    name: String,
     was_cleaned_up: bool,
 }
 
@@ -419,6 +772,17 @@ impl Deref for JsTrxRef {
     }
 }
 
+#[wasm_bindgen(typescript_custom_section)]
+const TRX_PRECONDITION: &str = r#"
+type TrxPrecondition = { exists: true } | { updatedAt: number | { toMillis(): number } };
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "TrxPrecondition")]
+    pub type JsTrxPrecondition;
+}
+
 mod js_trx_state {
     use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
@@ -433,6 +797,7 @@ type TrxState =
     | 'applied'
     | 'committing'
     | 'committed'
+    | 'queued'
     | 'applied';
 "#;
 
@@ -450,6 +815,7 @@ type TrxState =
                 TrxState::Failed => "failed",
                 TrxState::Committing => "committing",
                 TrxState::Committed => "committed",
+                TrxState::Queued => "queued",
             };
             JsValue::from(state).into()
         }
@@ -457,14 +823,130 @@ type TrxState =
 }
 pub use js_trx_state::JsTrxState;
 
+mod js_trx_error {
+    use js_sys::Reflect;
+    use std::error::Error;
+    use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+    use super::js_firebase::{precondition_from_js, precondition_to_js};
+    use crate::transaction::{TrxApplyErr, TrxCommitErr, TrxConflict, TrxDbErr, TrxErrCode};
+
+    #[wasm_bindgen(typescript_custom_section)]
+    const JS_TRX_ERROR: &str = r#"
+type TrxErrorCode =
+    | 'cancelled'
+    | 'unknown'
+    | 'invalid-argument'
+    | 'deadline-exceeded'
+    | 'not-found'
+    | 'already-exists'
+    | 'permission-denied'
+    | 'resource-exhausted'
+    | 'failed-precondition'
+    | 'aborted'
+    | 'internal'
+    | 'unavailable'
+    | 'unauthenticated'
+    | 'closed'
+    | 'unreverted';
+
+interface TrxError extends Error {
+    readonly code: TrxErrorCode;
+    readonly transaction: string;
+    readonly path?: string;
+    readonly opIndex?: number;
+    readonly cause?: Error;
+    /** Set on 'failed-precondition' conflicts, the stored document or null if it does not exist */
+    readonly current?: {} | null;
+    readonly precondition?: TrxPrecondition;
+}
+"#;
+
+    /// Build a JS `TrxError`, with the source chain of `err` as its `cause`
+    pub fn from_apply_err(name: &str, err: &TrxApplyErr) -> JsValue {
+        let js_err = new(name, err.code(), &err.to_string());
+        if let Some(path) = err.path() {
+            set(&js_err, "path", &path.into());
+        }
+        if let Some(op_index) = err.op_index() {
+            set(&js_err, "opIndex", &(op_index as u32).into());
+        }
+        if let Some(conflict) = err.conflict() {
+            let current = conflict.current::<JsValue>().cloned();
+            set(&js_err, "current", &current.unwrap_or(JsValue::NULL));
+            let precondition = precondition_to_js(&conflict.precondition);
+            set(&js_err, "precondition", &precondition);
+        }
+
+        let mut parent = js_err.clone();
+        let mut source = err.source();
+        while let Some(e) = source {
+            let cause: JsValue = js_sys::Error::new(&e.to_string()).into();
+            set(&parent, "cause", &cause);
+            parent = cause;
+            source = e.source();
+        }
+        js_err
+    }
+
+    pub fn new(name: &str, code: TrxErrCode, msg: &str) -> JsValue {
+        let js_err: JsValue = js_sys::Error::new(&format!("Transaction({name}): {msg}")).into();
+        set(&js_err, "code", &code.as_str().into());
+        set(&js_err, "transaction", &name.into());
+        js_err
+    }
+
+    fn set(target: &JsValue, key: &str, value: &JsValue) {
+        let _ = Reflect::set(target, &key.into(), value);
+    }
+
+    /// Read the conflict thrown by `FireBatch.commit`, or else the error thrown by Firestore
+    pub fn to_commit_err(err: &JsValue) -> TrxCommitErr {
+        let get = |key: &str| Reflect::get(err, &key.into()).unwrap_or_default();
+        let (Some(path), Ok(precondition)) = (
+            get("path").as_string(),
+            precondition_from_js(&get("precondition")),
+        ) else {
+            return to_db_err(err).into();
+        };
+        let conflict = TrxConflict::new(path, precondition);
+        let current = get("current");
+        match current.is_object() {
+            true => conflict.with_current(current).into(),
+            false => conflict.into(),
+        }
+    }
+
+    /// Read the `code` and `message` of an error thrown by Firestore
+    pub fn to_db_err(err: &JsValue) -> TrxDbErr {
+        let get = |key: &str| {
+            Reflect::get(err, &key.into())
+                .ok()
+                .and_then(|value| value.as_string())
+        };
+        let code = match get("code") {
+            Some(code) => TrxErrCode::parse(&code),
+            None => TrxErrCode::Unknown,
+        };
+        let msg = get("message").unwrap_or_else(|| format!("{err:?}"));
+        TrxDbErr::new(code, msg)
+    }
+}
+
 use self::js_firebase::FireDbRef;
 
+/// How long the ops of a transaction may run before it fails, so a promise that never
+/// settles does not leave the session dirty forever
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
 impl JsTransaction {
     fn clean_up(&mut self) {
         if !self.was_cleaned_up {
-            use_session_manager().js_decrement_writes();
+            use_session_manager().trx_finished(&self.name);
             if let Some(trx) = self.trx.take() {
-                let name = trx.name();
+                // dropped first, so the store records it as closed unless it is still in use
+                let name = trx.name().to_string();
+                drop(trx);
                 use_active_trx_store().remove_by_id(&name);
             } else {
                 use_active_trx_store().clean_inactive();
@@ -481,17 +963,23 @@ impl Drop for JsTransaction {
 
 impl JsTransaction {
     pub fn create(name: &str) -> JsTransaction {
-        let trx = Transaction::new(FireDbRef, name);
+        Self::track(Transaction::new(FireDbRef, name))
+    }
+
+    /// Wrap `trx`, tracked by the active transaction store and the session manager
+    fn track(mut trx: Transaction<FireDbRef>) -> JsTransaction {
+        trx.set_retry_policy(RetryPolicy::default(), JsClock);
+        trx.set_timeout(DEFAULT_TIMEOUT, JsClock);
         let active_trx_store = use_active_trx_store();
         active_trx_store.add(&trx);
         drop(active_trx_store);
 
-        let session_manager = use_session_manager();
-        session_manager.js_increment_writes();
-        drop(session_manager);
+        let name = trx.name().to_owned();
+        use_session_manager().trx_started(&name);
 
         JsTransaction {
             trx: Some(trx),
+            name,
             was_cleaned_up: false,
         }
     }
@@ -505,6 +993,23 @@ impl JsTransaction {
             None => JsTransaction::create("tx"),
         }
     }
+
+    /// Milliseconds the ops and pre-commit hooks may run once `apply` is called
+    #[wasm_bindgen(setter)]
+    pub fn set_timeout(&mut self, millis: u32) {
+        if let Some(trx) = self.trx.as_mut() {
+            trx.set_timeout(Duration::from_millis(millis.into()), JsClock);
+        }
+    }
+
+    /// Save the transaction into the offline queue before committing it, so it is committed
+    /// again once the client is back online if its commit does not complete
+    #[wasm_bindgen(js_name = "queueOffline")]
+    pub fn queue_offline(&mut self) {
+        if let Some(trx) = self.trx.as_mut() {
+            trx.set_offline_queue(use_offline_queue());
+        }
+    }
 }
 
 #[wasm_bindgen(js_class = "Transaction")]
@@ -516,18 +1021,125 @@ impl JsTransaction {
 
     /// Because we're trying to maintain the same TS interface, nobody
     /// is going to call .cleanup/drop on this, so we have to do it ourselves at apply time
-    pub async fn apply(&mut self) -> Result<(), String> {
+    pub async fn apply(&mut self) -> Result<JsTrxSummary, JsValue> {
         if let Some(trx) = self.trx.take() {
             let name = trx.name().to_owned();
-            let result = trx.apply().await;
+
+            // the session is reported as retrying from the first retry until the apply settles
+            let retrying = Rc::new(Cell::new(false));
+            trx.add_retry_hook({
+                let (retrying, name) = (retrying.clone(), name.clone());
+                Box::new(move |attempt, delay, err| {
+                    log::warn!(
+                        "Transaction({name}): attempt {attempt} failed, retrying in {delay:?}: {err}"
+                    );
+                    if !retrying.replace(true) {
+                        use_session_manager().increment_retries();
+                    }
+                })
+            });
+
+            let (result, retry) = match trx.apply_retryable().await {
+                Ok(summary) => (Ok(summary), None),
+                Err((err, retry)) => (Err(err), retry),
+            };
+            if retrying.get() {
+                use_session_manager().decrement_retries();
+            }
             self.clean_up();
-            flat_trx_apply_result(&name, result)
+            use_session_manager().set_replays(use_offline_queue().pending_replays());
+            let summary = flat_trx_apply_result(&name, result, retry)?;
+            Ok(JsValue::from(summary).into())
         } else {
-            Err("defer_unique can only be used against pending/preparing transactions".into())
+            let msg = "Transaction was applied already";
+            Err(js_trx_error::new("", TrxErrCode::Closed, msg))
+        }
+    }
+}
+
+#[wasm_bindgen(js_class = "Transaction")]
+impl JsTransaction {
+    /// Cancel the transaction before it is applied, running its abort hooks.
+    /// Use `TrxRef.cancel()` to cancel a transaction which is being applied.
+    pub async fn cancel(&mut self) {
+        if let Some(trx) = self.trx.take() {
+            trx.cancel();
+            let _ = trx.apply().await;
+            self.clean_up();
         }
     }
 }
 
+#[wasm_bindgen(typescript_custom_section)]
+const TRX_SUMMARY: &str = r#"
+interface TrxSummary {
+    name: string;
+    ops: number;
+    batches: number;
+    opsDuration: number;
+    commitDuration: number;
+    hooks: { preCommit: number; onCommit: number; postCommit: number };
+    warnings: string[];
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "TrxSummary")]
+    pub type JsTrxSummary;
+}
+
+impl From<TrxSummary> for JsValue {
+    fn from(value: TrxSummary) -> Self {
+        serde_json::to_string(&value)
+            .ok()
+            .and_then(|json| js_sys::JSON::parse(&json).ok())
+            .unwrap_or(JsValue::NULL)
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const TRX_PLAN: &str = r#"
+interface TrxPlannedOp {
+    path: string;
+    kind: 'set' | 'update' | 'delete';
+    merge: boolean;
+    payload: {} | null;
+    precondition?: { exists: true } | { updatedAt: number };
+}
+
+interface TrxPlan {
+    ops: TrxPlannedOp[];
+    batches: { start: number; end: number }[];
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "TrxPlan")]
+    pub type JsTrxPlan;
+}
+
+#[wasm_bindgen(js_class = "Transaction")]
+impl JsTransaction {
+    /// Run the ops and pre-commit hooks without committing anything, and resolve to what
+    /// `apply` would write. The transaction is aborted afterwards, like `cancel` does.
+    #[wasm_bindgen(js_name = "dryRun")]
+    pub async fn dry_run(&mut self) -> Result<JsTrxPlan, JsValue> {
+        let Some(trx) = self.trx.take() else {
+            let msg = "dryRun can only be used against pending transactions";
+            return Err(js_trx_error::new("", TrxErrCode::Closed, msg));
+        };
+        let name = trx.name().to_owned();
+        let result = trx.dry_run().await;
+        self.clean_up();
+
+        let plan = result.map_err(|err| js_trx_error::from_apply_err(&name, &err))?;
+        let json = serde_json::to_string(&plan).map_err(|e| JsValue::from(e.to_string()))?;
+        Ok(js_sys::JSON::parse(&json)?.into())
+    }
+}
+
 impl JsTransaction {
     pub fn prev_checked(&self) -> Option<Ref<'_, TrxHandle<FireDbRef, Open>>> {
         let trx = self.trx.as_deref()?;
@@ -536,20 +1148,55 @@ impl JsTransaction {
     }
 }
 
-fn flat_trx_apply_result(name: &str, result: Result<(), TrxApplyErr>) -> Result<(), String> {
+/// Settle the result of `JsTransaction::apply`. Failures are reported to the session manager,
+/// alongside `retry` which commits the writes of the transaction again, given only for
+/// transient commit failures by `Transaction::apply_retryable`
+fn flat_trx_apply_result(
+    name: &str,
+    result: Result<TrxSummary, TrxApplyErr>,
+    retry: Option<Transaction<FireDbRef>>,
+) -> Result<TrxSummary, JsValue> {
     let err = match result {
-        Ok(_) => return Ok(()),
+        Ok(summary) => return Ok(summary),
         Err(err) => err,
     };
+    // resolved anyway, with nothing committed yet
+    let uncommitted = |warning: String| TrxSummary {
+        name: name.to_string(),
+        warnings: vec![warning],
+        ..Default::default()
+    };
 
-    match err {
-        TrxApplyErr::AccessDenied(msg) => {
+    match &err {
+        TrxApplyErr::Queued { key, source } => {
+            // the ops are saved, and are committed once the client is back online. The
+            // post-commit or abort hooks of the transaction run then.
+            let warning = format!("{source}, queued for replay as {key}");
+            log::warn!("Transaction({name}): {warning}");
+            return Ok(uncommitted(warning));
+        }
+        TrxApplyErr::Op(BatchTrxOpErr::AccessDenied(msg)) => {
             log::warn!("Transaction({name}): {msg}");
-            on_access_denied::invoke(msg)
+            if on_access_denied::invoke(msg.clone()).is_ok() {
+                return Ok(uncommitted(msg.clone()));
+            }
         }
-        TrxApplyErr::Other(msg) => Err(msg),
-        TrxApplyErr::FailedCheck(err) => Err(err.to_string()),
+        TrxApplyErr::Commit(TrxCommitErr::Unreverted { unrestored, .. }) => {
+            let paths = unrestored.join(", ");
+            log::error!("Transaction({name}): partially committed, unable to revert {paths}");
+        }
+        _ => {}
     }
+    let retry = retry.map(|trx| -> RetryFn {
+        Box::new(move || {
+            Box::pin(async move {
+                // reported to the session manager again if it fails
+                let _ = JsTransaction::track(trx).apply().await;
+            })
+        })
+    });
+    use_session_manager().trx_failed(name, &err, retry);
+    Err(js_trx_error::from_apply_err(name, &err))
 }
 
 #[wasm_bindgen(js_class = "Transaction")]
@@ -608,18 +1255,72 @@ impl JsTrxRef {
         js_firebase::firebase_now()
     }
 
+    /// Cancel the transaction, which fails with a `cancelled` error if it was not committing yet.
+    /// Return false if it was already committed, failed or dropped.
+    pub fn cancel(&self) -> bool {
+        self.trx.cancel()
+    }
+
+    /// Schedule `f`, and return the id of its op so that other ops can depend on it.
+    /// Without `after`, `f` is called right away, otherwise once the ops `after` completed.
     #[wasm_bindgen(js_name = "addOp")]
-    pub fn add_op(&self, _entity: Option<JsEntity>, f: TrxVoidOp) -> Result<(), String> {
-        let _ = self.map(|txh| {
-            if let Some(future) = f.call(&txh) {
-                txh.add_future_op(async move {
-                    future.await;
+    pub fn add_op(
+        &self,
+        _entity: Option<JsEntity>,
+        f: TrxVoidOp,
+        after: Option<Vec<u32>>,
+    ) -> Result<u32, String> {
+        let after: Vec<OpId> = after
+            .unwrap_or_default()
+            .into_iter()
+            .map(OpId::from_u32)
+            .collect();
+        let id = self.flatten_map(|txh| {
+            if after.is_empty() {
+                let future = f.call(txh);
+                return Ok(txh.add_future_op(async move {
+                    if let Some(future) = future {
+                        future.await;
+                    }
                     Ok(())
-                });
+                }));
             }
-        })?;
 
-        Ok(())
+            let txr = self.clone();
+            txh.add_future_op_after(&after, async move {
+                if let Some(future) = txr.map(|txh| f.call(txh))? {
+                    future.await;
+                }
+                Ok(())
+            })
+        });
+
+        match id {
+            Ok(id) => Ok(id.as_u32()),
+            Err(e) => Err(format!("Transaction: {e}")),
+        }
+    }
+
+    /// Schedule `f` once every other op completed, before the pre-commit hooks, unless an op
+    /// was already deferred with the same `key` by this transaction. Return false if `f` was
+    /// dropped.
+    #[wasm_bindgen(js_name = "deferUnique")]
+    pub fn defer_unique(&self, key: String, f: TrxVoidOp) -> Result<bool, String> {
+        let deferred = self.trx.defer_unique(key, move |txh| async move {
+            if let Some(future) = f.call(&*txh) {
+                future.await;
+            }
+            Ok(txh)
+        });
+        deferred.map_err(|e| format!("Transaction: {e}"))
+    }
+
+    /// Make the op `op` wait until the op `on` completed
+    #[wasm_bindgen(js_name = "addDependency")]
+    pub fn add_dependency(&self, op: u32, on: u32) -> Result<(), String> {
+        let result =
+            self.flatten_map(|txh| txh.add_dependency(OpId::from_u32(op), OpId::from_u32(on)));
+        result.map_err(|e| format!("Transaction: {e}"))
     }
 
     #[wasm_bindgen(js_name = "addPostCommitHook")]
@@ -636,16 +1337,19 @@ impl JsTrxRef {
     #[wasm_bindgen(js_name = "addPrecommitHook")]
     pub fn add_pre_commit_hook(&self, f: PrecommitFn) -> Result<(), String> {
         let _: () = self.map(|txh| {
-            txh.add_pre_commit_hook(Box::new(move |trx: &TrxHandle<FireDbRef>| f.call(trx)));
+            txh.add_pre_commit_hook(Box::new(move |trx: &TrxHandle<FireDbRef>| {
+                f.call(trx).map_err(Into::into)
+            }));
         })?;
         Ok(())
     }
 
     #[wasm_bindgen(js_name = "addAbortHook")]
-    pub fn add_abort_hook(&self, _f: TerminalHook) -> Result<(), String> {
-        // TODO
-        //let trx = self.trx.upgrade_prev_checked()?;
-        //trx.add_abort_hook(Box::new(move |_| Some(f.call().boxed_local())));
+    pub fn add_abort_hook(&self, f: TerminalHook) -> Result<(), String> {
+        let _: () = self.map(|trx| {
+            let cb = move || f.call().map(|future| future.boxed_local());
+            trx.add_abort_hook(Box::new(cb));
+        })?;
         Ok(())
     }
 
@@ -653,8 +1357,23 @@ impl JsTrxRef {
         self.process_op(TrxFireOp::Insert { entity, data })
     }
 
-    pub fn update(&self, entity: JsEntity, data: js_sys::Object) -> Result<(), String> {
-        self.process_op(TrxFireOp::Update { entity, data })
+    /// Merge `data` into the entity. With a `precondition`, the transaction fails with a
+    /// `failed-precondition` error carrying the `current` document if it does not hold.
+    pub fn update(
+        &self,
+        entity: JsEntity,
+        data: js_sys::Object,
+        precondition: Option<JsTrxPrecondition>,
+    ) -> Result<(), String> {
+        let precondition = match precondition {
+            Some(precondition) => Some(js_firebase::precondition_from_js(&precondition)?),
+            None => None,
+        };
+        self.process_op(TrxFireOp::Update {
+            entity,
+            data,
+            precondition,
+        })
     }
 
     #[wasm_bindgen(js_name = "setForRef")]
@@ -692,6 +1411,8 @@ impl JsTrxRef {
                 on_access_denied::invoke(msg)
             }
             Other(e) => Err(format!("Transaction({name}): {e}")),
+            Db(e) => Err(format!("Transaction({name}): {e}")),
+            Schedule(e) => Err(format!("Transaction({name}): {e}")),
             ClosedForAddingOps => {
                 let name = txh.name();
                 let msg = format!("Transaction({name}): Can not accept more operations");
@@ -701,6 +1422,49 @@ impl JsTrxRef {
     }
 }
 
+thread_local! {
+  static OFFLINE_QUEUE: OnceCell<OfflineQueue> = OnceCell::new();
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = globalThis, js_name = "addEventListener")]
+    fn add_global_event_listener(kind: &str, listener: &Closure<dyn FnMut()>);
+}
+
+/// The offline queue of the transactions opting in with `queueOffline`. Transactions left
+/// over by a previous session are replayed when it is first used, and the queue is replayed
+/// again whenever the browser comes back online.
+pub fn use_offline_queue() -> OfflineQueue {
+    OFFLINE_QUEUE.with(|cell| {
+        cell.get_or_init(|| {
+            let on_online = Closure::<dyn FnMut()>::new(|| {
+                wasm_bindgen_futures::spawn_local(replay_offline_queue());
+            });
+            add_global_event_listener("online", &on_online);
+            on_online.forget();
+            wasm_bindgen_futures::spawn_local(replay_offline_queue());
+
+            OfflineQueue::new(IndexedDbQueue::default())
+        })
+        .clone()
+    })
+}
+
+/// Commit the transactions of the offline queue which are still waiting for a replay
+#[wasm_bindgen(js_name = "replayOfflineQueue")]
+pub async fn replay_offline_queue() {
+    let queue = use_offline_queue();
+    for (key, outcome) in queue.replay(&FireDbRef).await {
+        match outcome {
+            Ok(_) => log::info!("Transaction({key}) replayed"),
+            Err(TrxApplyErr::Queued { .. }) => {}
+            Err(err) => log::error!("Transaction({key}) dropped, its replay failed: {err}"),
+        }
+    }
+    use_session_manager().set_replays(queue.pending_replays());
+}
+
 thread_local! {
   pub static ACTIVE_TRX_STORE: OnceCell<ActiveTrxStore<FireDbRef>> = OnceCell::new();
 }
@@ -728,6 +1492,39 @@ impl JsActiveTransactions {
             .map(|trx: &TrxHandle<FireDbRef, Unknown>| trx.name().into())
             .into_boxed_slice()
     }
+
+    /// `{ active, history }` records of the transactions, see `TrxActivity`
+    #[wasm_bindgen(getter)]
+    pub fn activity(&self) -> JsObservable {
+        use_active_trx_store().activity().into()
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const TRX_ACTIVITY: &str = r#"
+interface TrxRecord {
+    id: number;
+    name: string;
+    state: TrxState;
+    startedAt: number;
+    commitDuration: number | null;
+    ops: number;
+    failure: TrxErrorCode | null;
+}
+
+interface TrxActivity {
+    active: TrxRecord[];
+    history: TrxRecord[];
+}
+"#;
+
+impl From<TrxActivity> for JsValue {
+    fn from(value: TrxActivity) -> Self {
+        serde_json::to_string(&value)
+            .ok()
+            .and_then(|json| js_sys::JSON::parse(&json).ok())
+            .unwrap_or(JsValue::NULL)
+    }
 }
 
 #[cfg(test)]
@@ -788,21 +1585,94 @@ console::log_any_other(str: &str)
 
 #[wasm_bindgen]
 pub fn sanitize_object_without_undefined(data: js_sys::Object) -> js_sys::Object {
+    let (data, stripped) = strip_undefined(data);
+    for k in stripped {
+        log::warn!("Key {k} had an undefined value, this may not have been intentional")
+    }
+    data
+}
+
+/// Remove the keys of `data` whose value is undefined, and return them alongside
+pub fn strip_undefined(data: js_sys::Object) -> (js_sys::Object, Vec<String>) {
     use js_sys::Object;
 
+    let mut stripped = Vec::new();
     let entries = Object::entries(&data).filter(&mut |entry, __, _| {
         let kv = Array::from(&entry);
 
         let v = kv.at(1);
         let is_undefined = v.is_undefined();
         if is_undefined {
-            let k = kv.at(0).as_string().unwrap();
-            log::warn!("Key {k} had an undefined value, this may not have been intentional")
+            stripped.push(kv.at(0).as_string().unwrap());
         }
         !is_undefined
     });
 
-    Object::from_entries(&entries).unwrap()
+    (Object::from_entries(&entries).unwrap(), stripped)
+}
+
+/// Deep merge `next` into a copy of `prev`, the way a `{ merge: true }` set of `next` would
+/// apply on top of `prev`. Only plain objects are merged: arrays, timestamps and references
+/// are replaced. Field value sentinels, such as array unions, are combined with the value
+/// they apply to by `FireBatch.combineFieldValues`, and `None` is returned if they can not be.
+pub fn merge_js_objects(prev: &js_sys::Object, next: &js_sys::Object) -> Option<js_sys::Object> {
+    use self::js_firebase::FireBatch;
+    use js_sys::{Object, Reflect};
+
+    fn as_plain_object(value: &JsValue) -> Option<&Object> {
+        let object = value.dyn_ref::<Object>()?;
+        let proto: JsValue = Object::get_prototype_of(object).into();
+        let plain_proto: JsValue = Object::get_prototype_of(&Object::new()).into();
+        (proto == plain_proto).then_some(object)
+    }
+
+    let merged = Object::assign(&Object::new(), prev);
+    for entry in Object::entries(next).iter() {
+        let kv = Array::from(&entry);
+        let (key, value) = (kv.get(0), kv.get(1));
+        let current = Reflect::get(&merged, &key).unwrap_or(JsValue::UNDEFINED);
+        let value = match (as_plain_object(&current), as_plain_object(&value)) {
+            (Some(current), Some(next)) => merge_js_objects(current, next)?.into(),
+            _ if current.is_undefined() || !FireBatch::is_field_value(&value) => value,
+            _ => Some(FireBatch::combine_field_values(&current, &value))
+                .filter(|combined| !combined.is_undefined())?,
+        };
+        let _ = Reflect::set(&merged, &key, &value);
+    }
+    Some(merged)
+}
+
+/// Estimated size of a value, following the Firestore storage size rules.
+/// Unknown objects, such as field value sentinels, are measured by their own fields.
+pub fn js_value_size(value: &JsValue) -> usize {
+    use js_sys::{Object, Uint8Array};
+
+    if let Some(s) = value.as_string() {
+        return s.len() + 1;
+    }
+    if value.as_f64().is_some() {
+        return 8;
+    }
+    if value.is_null() || value.is_undefined() || value.as_bool().is_some() {
+        return 1;
+    }
+    if let Some(bytes) = value.dyn_ref::<Uint8Array>() {
+        return bytes.length() as usize;
+    }
+    if let Some(array) = value.dyn_ref::<Array>() {
+        return array.iter().map(|v| js_value_size(&v)).sum();
+    }
+    if let Some(object) = value.dyn_ref::<Object>() {
+        return Object::entries(object)
+            .iter()
+            .map(|entry| {
+                let kv = Array::from(&entry);
+                let key_size = kv.get(0).as_string().map_or(0, |key| key.len() + 1);
+                key_size + js_value_size(&kv.get(1))
+            })
+            .sum();
+    }
+    8
 }
 
 // /// Generates a key like in EdvoObj.key
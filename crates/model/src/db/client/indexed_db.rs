@@ -0,0 +1,87 @@
+use futures::{future::LocalBoxFuture, FutureExt};
+use wasm_bindgen::prelude::*;
+
+use crate::transaction::{Claim, QueueStorage, QueuedTrx};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen]
+    pub type OfflineStore;
+
+    #[wasm_bindgen(js_namespace = edvocommon, static_method_of = OfflineStore, js_name = "open")]
+    fn open() -> OfflineStore;
+    #[wasm_bindgen(method, catch)]
+    async fn put(this: &OfflineStore, key: &str, value: &str) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(method, catch)]
+    async fn delete(this: &OfflineStore, key: &str) -> Result<JsValue, JsValue>;
+    /// Resolves to the JSON of every queued transaction
+    #[wasm_bindgen(method, catch)]
+    async fn values(this: &OfflineStore) -> Result<JsValue, JsValue>;
+    /// Resolves to the function releasing the claim, or undefined if another tab holds it
+    #[wasm_bindgen(method, catch)]
+    async fn claim(this: &OfflineStore, key: &str) -> Result<JsValue, JsValue>;
+}
+
+/// Offline queue storage backed by IndexedDB, through `edvocommon.OfflineStore`
+pub struct IndexedDbQueue(OfflineStore);
+
+impl Default for IndexedDbQueue {
+    fn default() -> Self {
+        Self(OfflineStore::open())
+    }
+}
+
+impl QueueStorage for IndexedDbQueue {
+    fn put(&self, trx: QueuedTrx) -> LocalBoxFuture<'_, Result<(), String>> {
+        async move {
+            let json = serde_json::to_string(&trx).map_err(|e| e.to_string())?;
+            match self.0.put(&trx.key, &json).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("IndexedDB put failed: {e:?}")),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn remove(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>> {
+        async move {
+            match self.0.delete(&key).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("IndexedDB delete failed: {e:?}")),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<Vec<QueuedTrx>, String>> {
+        async move {
+            let values = match self.0.values().await {
+                Ok(values) => js_sys::Array::from(&values),
+                Err(e) => return Err(format!("IndexedDB getAll failed: {e:?}")),
+            };
+            let mut queued = Vec::with_capacity(values.length() as usize);
+            for json in values.iter().filter_map(|value| value.as_string()) {
+                match serde_json::from_str(&json) {
+                    Ok(trx) => queued.push(trx),
+                    Err(e) => log::error!("Skipping corrupt queued transaction: {e}"),
+                }
+            }
+            Ok(queued)
+        }
+        .boxed_local()
+    }
+
+    fn claim(&self, key: String) -> LocalBoxFuture<'_, Result<Option<Claim>, String>> {
+        async move {
+            match self.0.claim(&key).await {
+                Ok(release) => Ok(release.dyn_into::<js_sys::Function>().ok().map(|release| {
+                    Claim::new(move || {
+                        let _ = release.call0(&JsValue::UNDEFINED);
+                    })
+                })),
+                Err(e) => Err(format!("Web lock request failed: {e:?}")),
+            }
+        }
+        .boxed_local()
+    }
+}
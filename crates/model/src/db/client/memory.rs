@@ -0,0 +1,612 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    mem,
+    rc::Rc,
+};
+
+use futures::future::LocalBoxFuture;
+use serde_json::{Map, Value};
+
+use crate::transaction::{
+    BatchTrxOpErr, Batchable, Coalesced, FullOpenTrxHandle, PlannedOp, Precondition, Transaction,
+    TrxCommitErr, TrxConflict, TrxRevertErr,
+};
+
+/// The fields of a stored document
+pub type Document = Map<String, Value>;
+
+/// In-memory database, storing JSON-like documents keyed by path (`collection/id[/collection/id...]`)
+///
+/// It implements `Batchable` with the same write semantics as `FireDbRef`, so transaction
+/// flows can run natively without a browser or Firestore.
+///
+/// ```
+/// use edvo_model::db::memory::{MemoryDb, MemoryTrxOp};
+/// use serde_json::json;
+///
+/// let db = MemoryDb::new();
+/// let transaction = db.trx("example");
+/// let data = json!({ "name": "hello" }).as_object().cloned().unwrap();
+/// transaction.process_op(MemoryTrxOp::Insert { path: "vertex/a".into(), data }).unwrap();
+/// futures::executor::block_on(transaction.apply()).ok().unwrap();
+///
+/// assert_eq!(db.get("vertex/a").unwrap()["name"], "hello");
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryDb(Rc<RefCell<BTreeMap<String, Document>>>);
+
+impl MemoryDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trx(&self, name: &str) -> Transaction<MemoryDb> {
+        Transaction::new(self.clone(), name)
+    }
+
+    pub fn get(&self, path: &str) -> Option<Document> {
+        self.0.borrow().get(path).cloned()
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.0.borrow().contains_key(path)
+    }
+
+    /// Documents directly inside the collection at `collection_path`, ordered by path
+    pub fn list(&self, collection_path: &str) -> Vec<(String, Document)> {
+        let prefix = format!("{}/", collection_path.trim_end_matches('/'));
+        let docs = self.0.borrow();
+        docs.range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .filter(|(path, _)| !path[prefix.len()..].contains('/'))
+            .map(|(path, doc)| (path.clone(), doc.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MemoryOp {
+    SetForRef {
+        path: String,
+        data: Document,
+        merge: bool,
+    },
+    /// A merge-set, creating the document if it does not exist, which fails if its
+    /// precondition does not hold
+    Update {
+        path: String,
+        data: Document,
+        precondition: Option<Precondition>,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+impl MemoryOp {
+    pub fn path(&self) -> &str {
+        match self {
+            MemoryOp::SetForRef { path, .. }
+            | MemoryOp::Update { path, .. }
+            | MemoryOp::Delete { path } => path,
+        }
+    }
+
+    fn apply(self, docs: &mut BTreeMap<String, Document>) {
+        match self {
+            MemoryOp::SetForRef { path, data, merge } => match docs.get_mut(&path) {
+                Some(doc) if merge => merge_fields(doc, data),
+                _ => {
+                    docs.insert(path, data);
+                }
+            },
+            MemoryOp::Update { path, data, .. } => {
+                merge_fields(docs.entry(path).or_default(), data);
+            }
+            MemoryOp::Delete { path } => {
+                docs.remove(&path);
+            }
+        }
+    }
+
+    /// Fail if the precondition of the op does not hold against `docs`
+    fn check(&self, docs: &BTreeMap<String, Document>) -> Result<(), TrxConflict> {
+        let MemoryOp::Update {
+            path,
+            precondition: Some(precondition),
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+        check_precondition(path, precondition, docs.get(path))
+    }
+}
+
+/// Fail if `precondition` does not hold for `doc`, the document stored at `path`
+pub(super) fn check_precondition(
+    path: &str,
+    precondition: &Precondition,
+    doc: Option<&Document>,
+) -> Result<(), TrxConflict> {
+    let holds = match (precondition, doc) {
+        (_, None) => false,
+        (Precondition::Exists, Some(_)) => true,
+        (Precondition::UpdatedAt(millis), Some(doc)) => {
+            doc.get("updatedAt").and_then(Value::as_i64) == Some(*millis)
+        }
+    };
+    match (holds, doc) {
+        (true, _) => Ok(()),
+        (false, Some(doc)) => {
+            Err(TrxConflict::new(path, precondition.clone()).with_current(doc.clone()))
+        }
+        (false, None) => Err(TrxConflict::new(path, precondition.clone())),
+    }
+}
+
+/// Transaction operations, mirroring `TrxFireOp`
+#[derive(Debug)]
+pub enum MemoryTrxOp {
+    Insert {
+        path: String,
+        data: Document,
+    },
+    Update {
+        path: String,
+        data: Document,
+        precondition: Option<Precondition>,
+    },
+    SetForRef {
+        path: String,
+        data: Document,
+        merge: bool,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+/// Merge `data` into `doc` the way Firestore does with `{ merge: true }`:
+/// nested maps are merged recursively, any other value is replaced
+pub(super) fn merge_fields(doc: &mut Document, data: Document) {
+    for (key, value) in data {
+        match (doc.get_mut(&key), value) {
+            (Some(Value::Object(current)), Value::Object(value)) => merge_fields(current, value),
+            (_, value) => {
+                doc.insert(key, value);
+            }
+        }
+    }
+}
+
+pub struct MemoryBatch {
+    db: MemoryDb,
+    ops: RefCell<Vec<MemoryOp>>,
+    /// Documents as they were before this batch was committed, `None` if they did not exist
+    before: RefCell<HashMap<String, Option<Document>>>,
+}
+
+impl MemoryBatch {
+    fn commit(&self) -> Result<(), TrxCommitErr> {
+        let mut docs = self.db.0.borrow_mut();
+        let mut before = self.before.borrow_mut();
+        let mut ops = self.ops.borrow_mut();
+        // preconditions are checked against the documents as they were before the batch
+        for (i, op) in ops.iter().enumerate() {
+            op.check(&docs).map_err(|e| e.with_op_index(i))?;
+        }
+        for op in ops.drain(..) {
+            before
+                .entry(op.path().to_string())
+                .or_insert_with(|| docs.get(op.path()).cloned());
+            op.apply(&mut docs);
+        }
+        Ok(())
+    }
+
+    fn revert(&self) {
+        let mut docs = self.db.0.borrow_mut();
+        restore(&mut docs, self.before.borrow_mut().drain());
+    }
+}
+
+fn restore(
+    docs: &mut BTreeMap<String, Document>,
+    before: impl Iterator<Item = (String, Option<Document>)>,
+) {
+    for (path, doc) in before {
+        match doc {
+            Some(doc) => docs.insert(path, doc),
+            None => docs.remove(&path),
+        };
+    }
+}
+
+impl Batchable for MemoryDb {
+    type Op = MemoryOp;
+    type TrxOp = MemoryTrxOp;
+    type Child = MemoryBatch;
+    type Extras = ();
+
+    fn child(&self) -> Result<MemoryBatch, String> {
+        Ok(MemoryBatch {
+            db: self.clone(),
+            ops: Default::default(),
+            before: Default::default(),
+        })
+    }
+
+    fn insert(child: &MemoryBatch, op: MemoryOp) {
+        child.ops.borrow_mut().push(op);
+    }
+
+    fn commit(child: &MemoryBatch) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+        Box::pin(async { child.commit() })
+    }
+
+    fn op_path(op: &MemoryOp) -> String {
+        op.path().to_string()
+    }
+
+    fn op_precondition(op: &MemoryOp) -> Option<&Precondition> {
+        match op {
+            MemoryOp::Update { precondition, .. } => precondition.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn explain_op(op: &MemoryOp) -> PlannedOp {
+        match op {
+            MemoryOp::SetForRef { path, data, merge } => PlannedOp::new(path, "set")
+                .with_merge(*merge)
+                .with_payload(Value::Object(data.clone())),
+            MemoryOp::Update {
+                path,
+                data,
+                precondition,
+            } => PlannedOp::new(path, "update")
+                .with_merge(true)
+                .with_payload(Value::Object(data.clone()))
+                .with_precondition(precondition.clone()),
+            MemoryOp::Delete { path } => PlannedOp::new(path, "delete"),
+        }
+    }
+
+    fn coalesce(prev: &mut MemoryOp, next: MemoryOp) -> Coalesced<MemoryOp> {
+        use MemoryOp::*;
+        match (&mut *prev, next) {
+            // an update still has to check its precondition
+            (Update { .. }, next @ SetForRef { merge: false, .. })
+            | (Delete { .. }, next @ Update { .. }) => return Coalesced::Separate(next),
+            (_, next @ (Delete { .. } | SetForRef { merge: false, .. })) => *prev = next,
+            (
+                SetForRef { data, .. } | Update { data, .. },
+                SetForRef { data: next, .. } | Update { data: next, .. },
+            ) => merge_fields(data, next),
+            (Delete { path }, SetForRef { data, .. }) => {
+                let path = mem::take(path);
+                *prev = SetForRef {
+                    path,
+                    data,
+                    merge: false,
+                };
+            }
+        }
+        Coalesced::Merged
+    }
+
+    fn revert(child: &MemoryBatch) -> LocalBoxFuture<'_, Result<(), TrxRevertErr>> {
+        Box::pin(async {
+            child.revert();
+            Ok(())
+        })
+    }
+
+    fn process_trx_op(txh: FullOpenTrxHandle<Self>, op: MemoryTrxOp) -> Result<(), BatchTrxOpErr> {
+        let op = match op {
+            MemoryTrxOp::Insert { path, data } => MemoryOp::SetForRef {
+                path,
+                data,
+                merge: true,
+            },
+            MemoryTrxOp::Update {
+                path,
+                data,
+                precondition,
+            } => MemoryOp::Update {
+                path,
+                data,
+                precondition,
+            },
+            MemoryTrxOp::SetForRef { path, data, merge } => {
+                MemoryOp::SetForRef { path, data, merge }
+            }
+            MemoryTrxOp::Delete { path } => MemoryOp::Delete { path },
+        };
+        txh.batch_operation(op)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::{Document, MemoryDb, MemoryTrxOp};
+    use crate::transaction::{Precondition, TrxErrCode};
+
+    fn doc(value: Value) -> Document {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_db_write_semantics() {
+        let db = MemoryDb::new();
+
+        let transaction = db.trx("insert");
+        let insert = |path: &str, data| MemoryTrxOp::Insert {
+            path: path.into(),
+            data,
+        };
+        transaction
+            .process_op(insert(
+                "vertex/a",
+                doc(json!({ "kind": "note", "meta": { "x": 1 } })),
+            ))
+            .unwrap();
+        transaction
+            .process_op(insert("vertex/b", doc(json!({ "kind": "note" }))))
+            .unwrap();
+        assert!(transaction.apply().await.is_ok());
+
+        let transaction = db.trx("edit");
+        transaction
+            .process_op(MemoryTrxOp::Update {
+                path: "vertex/a".into(),
+                data: doc(json!({ "meta": { "y": 2 } })),
+                precondition: None,
+            })
+            .unwrap();
+        transaction
+            .process_op(MemoryTrxOp::SetForRef {
+                path: "vertex/b".into(),
+                data: doc(json!({ "name": "b" })),
+                merge: false,
+            })
+            .unwrap();
+        assert!(transaction.apply().await.is_ok());
+
+        assert_eq!(
+            db.get("vertex/a"),
+            Some(doc(json!({ "kind": "note", "meta": { "x": 1, "y": 2 } })))
+        );
+        assert_eq!(db.get("vertex/b"), Some(doc(json!({ "name": "b" }))));
+
+        let transaction = db.trx("delete");
+        transaction
+            .process_op(MemoryTrxOp::Delete {
+                path: "vertex/b".into(),
+            })
+            .unwrap();
+        assert!(transaction.apply().await.is_ok());
+        assert!(!db.contains("vertex/b"));
+        assert_eq!(db.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_db_update_creates_missing_document() {
+        let db = MemoryDb::new();
+
+        // like the merge-set of Firestore
+        let transaction = db.trx("update-missing");
+        transaction
+            .process_op(MemoryTrxOp::Insert {
+                path: "vertex/a".into(),
+                data: doc(json!({ "kind": "note" })),
+            })
+            .unwrap();
+        transaction
+            .process_op(MemoryTrxOp::Update {
+                path: "vertex/missing".into(),
+                data: doc(json!({ "kind": "note" })),
+                precondition: None,
+            })
+            .unwrap();
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(
+            db.get("vertex/missing"),
+            Some(doc(json!({ "kind": "note" })))
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_db_list_collection() {
+        let db = MemoryDb::new();
+
+        let transaction = db.trx("list");
+        for path in ["vertex/a", "vertex/a/property/p1", "vertex/b", "vertexes/c"] {
+            transaction
+                .process_op(MemoryTrxOp::Insert {
+                    path: path.into(),
+                    data: Document::new(),
+                })
+                .unwrap();
+        }
+        assert!(transaction.apply().await.is_ok());
+
+        let paths: Vec<String> = db.list("vertex").into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, ["vertex/a", "vertex/b"]);
+        let paths: Vec<String> = db
+            .list("vertex/a/property")
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(paths, ["vertex/a/property/p1"]);
+    }
+
+    #[tokio::test]
+    async fn memory_db_coalesces_writes() {
+        let db = MemoryDb::new();
+
+        let transaction = db.trx("existing");
+        transaction
+            .process_op(MemoryTrxOp::Insert {
+                path: "vertex/b".into(),
+                data: doc(json!({ "kind": "note" })),
+            })
+            .unwrap();
+        assert!(transaction.apply().await.is_ok());
+
+        let transaction = db.trx("coalesced");
+        let ops = [
+            MemoryTrxOp::Insert {
+                path: "vertex/a".into(),
+                data: doc(json!({ "meta": { "x": 1 } })),
+            },
+            MemoryTrxOp::Update {
+                path: "vertex/a".into(),
+                data: doc(json!({ "meta": { "y": 2 } })),
+                precondition: None,
+            },
+            MemoryTrxOp::Delete {
+                path: "vertex/b".into(),
+            },
+            MemoryTrxOp::Insert {
+                path: "vertex/b".into(),
+                data: doc(json!({ "name": "b" })),
+            },
+        ];
+        for op in ops {
+            transaction.process_op(op).unwrap();
+        }
+        assert!(transaction.apply().await.is_ok());
+
+        assert_eq!(
+            db.get("vertex/a"),
+            Some(doc(json!({ "meta": { "x": 1, "y": 2 } })))
+        );
+        assert_eq!(db.get("vertex/b"), Some(doc(json!({ "name": "b" }))));
+    }
+
+    #[tokio::test]
+    async fn memory_db_update_preconditions() {
+        let db = MemoryDb::new();
+        let update = |updated_at: i64, precondition| MemoryTrxOp::Update {
+            path: "vertex/a".into(),
+            data: doc(json!({ "name": "a", "updatedAt": updated_at })),
+            precondition: Some(precondition),
+        };
+
+        let transaction = db.trx("insert");
+        transaction
+            .process_op(MemoryTrxOp::Insert {
+                path: "vertex/a".into(),
+                data: doc(json!({ "updatedAt": 1 })),
+            })
+            .unwrap();
+        assert!(transaction.apply().await.is_ok());
+
+        let transaction = db.trx("first-edit");
+        transaction
+            .process_op(update(2, Precondition::UpdatedAt(1)))
+            .unwrap();
+        assert!(transaction.apply().await.is_ok());
+
+        // a second edit based on the same version conflicts, and commits nothing
+        let transaction = db.trx("second-edit");
+        transaction
+            .process_op(MemoryTrxOp::Insert {
+                path: "vertex/b".into(),
+                data: Document::new(),
+            })
+            .unwrap();
+        transaction
+            .process_op(update(3, Precondition::UpdatedAt(1)))
+            .unwrap();
+        let err = transaction.apply().await.err().unwrap();
+        assert_eq!(err.code(), TrxErrCode::FailedPrecondition);
+        let conflict = err.conflict().unwrap();
+        assert_eq!(conflict.path, "vertex/a");
+        assert_eq!(conflict.op_index, Some(1));
+        assert_eq!(
+            conflict.current::<Document>(),
+            Some(&doc(json!({ "name": "a", "updatedAt": 2 })))
+        );
+        assert!(!db.contains("vertex/b"));
+
+        let transaction = db.trx("missing");
+        transaction
+            .process_op(MemoryTrxOp::Update {
+                path: "vertex/missing".into(),
+                data: Document::new(),
+                precondition: Some(Precondition::Exists),
+            })
+            .unwrap();
+        let err = transaction.apply().await.err().unwrap();
+        assert!(err.conflict().unwrap().current.is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_db_dry_run_plan() {
+        let db = MemoryDb::new();
+
+        let transaction = db.trx("duplicate");
+        let ops = [
+            MemoryTrxOp::Insert {
+                path: "vertex/copy".into(),
+                data: doc(json!({ "kind": "note" })),
+            },
+            MemoryTrxOp::Update {
+                path: "vertex/original".into(),
+                data: doc(json!({ "copies": 1 })),
+                precondition: Some(Precondition::UpdatedAt(7)),
+            },
+            MemoryTrxOp::Delete {
+                path: "vertex/draft".into(),
+            },
+        ];
+        for op in ops {
+            transaction.process_op(op).unwrap();
+        }
+
+        let plan = transaction.dry_run().await.unwrap();
+        assert_eq!(
+            serde_json::to_value(plan).unwrap(),
+            json!({
+                "ops": [
+                    {
+                        "path": "vertex/copy",
+                        "kind": "set",
+                        "merge": true,
+                        "payload": { "kind": "note" },
+                    },
+                    {
+                        "path": "vertex/original",
+                        "kind": "update",
+                        "merge": true,
+                        "payload": { "copies": 1 },
+                        "precondition": { "updatedAt": 7 },
+                    },
+                    {
+                        "path": "vertex/draft",
+                        "kind": "delete",
+                        "merge": false,
+                        "payload": null,
+                    },
+                ],
+                "batches": [{ "start": 0, "end": 3 }],
+            })
+        );
+        assert!(db.is_empty());
+    }
+}
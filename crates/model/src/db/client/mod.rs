@@ -1,6 +1,9 @@
-// TODO fix property.rs and restore this
-// #[cfg(target_arch = "wasm32")]
+#[cfg(feature = "js")]
 pub mod firestore_js;
+#[cfg(feature = "js")]
+pub mod indexed_db;
+
+pub mod memory;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod firestore;
@@ -4,6 +4,7 @@
 
 pub mod db;
 
+#[cfg(feature = "js")]
 pub mod entity;
 pub mod property;
 pub mod vertex;
@@ -13,6 +14,7 @@ pub mod vertex;
 pub mod search;
 pub mod session_manager;
 pub mod text_range;
+#[cfg(feature = "js")]
 pub mod timer;
 pub mod transaction;
 pub mod undo;
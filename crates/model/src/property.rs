@@ -1,24 +1,31 @@
-use crate::{
-    db::firestore_js::{JsTransaction, JsTrxRef},
-    entity::JsEntity,
-    search::SearchTokens,
-    timer::CallbackTimer,
-};
 use hex;
-use observable_react::JsObservable;
 use sha2::{Sha256, Digest};
-use std::error::Error;
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::Cell, error::Error, rc::Rc};
 use url::Url;
-use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::future_to_promise;
 
-pub mod content;
+use self::content::{content_type::ContentType, Content};
+use crate::{
+    search::SearchTokens,
+    transaction::{Batchable, TrxHandle},
+};
 
-use content::Content;
+pub mod content;
 
-use self::content::content_type::ContentType;
+#[cfg(feature = "js")]
+use {
+    crate::{
+        db::firestore_js::{JsTransaction, JsTrxRef},
+        entity::JsEntity,
+        text_range::TextContentRange,
+        timer::CallbackTimer,
+    },
+    observable_react::JsObservable,
+    std::cell::RefCell,
+    wasm_bindgen::prelude::*,
+    wasm_bindgen_futures::future_to_promise,
+};
 
+#[cfg(feature = "js")]
 #[wasm_bindgen(typescript_custom_section)]
 const IJsProperty: &'static str = r#"
 export interface IJsProperty extends IJsEntity {
@@ -28,6 +35,7 @@ export interface IJsProperty extends IJsEntity {
 "#;
 
 /** The Property/Entity object from JS */
+#[cfg(feature = "js")]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(typescript_type = "IJsProperty", extends=JsEntity)]
@@ -37,6 +45,7 @@ extern "C" {
     pub fn applyBeforeSaveHooks(this: &JsProperty, trx: JsTrxRef);
 }
 
+#[cfg(feature = "js")]
 #[wasm_bindgen(typescript_custom_section)]
 const IJsUpdateContext: &'static str = r#"
 export interface IJsUpdateContext {
@@ -47,6 +56,7 @@ export interface IJsUpdateContext {
 }
 "#;
 
+#[cfg(feature = "js")]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(typescript_type = "IJsUpdateContext")]
@@ -77,9 +87,9 @@ extern "C" {
 /**
  * FEATURES TODO:
  * Garbage collection for yrs/yjs needs to be turned OFF(?)
- * Undo
  * Relative position indication
  */
+#[cfg(feature = "js")]
 #[wasm_bindgen]
 #[derive(Clone)]
 pub struct Property {
@@ -89,24 +99,47 @@ pub struct Property {
     #[wasm_bindgen(skip)]
     pub content: Rc<Content>,
 }
+#[cfg(feature = "js")]
 struct PropertyInner {
     js_property: JsProperty,
     debounce_timeout: RefCell<Option<CallbackTimer>>,
+    save_retries: SaveRetries,
 }
 
+#[cfg(feature = "js")]
 impl Property {
     pub fn new(js_property: JsProperty, content_type: ContentType, module: Content) -> Property {
         Property {
             inner: Rc::new(PropertyInner {
                 js_property,
                 debounce_timeout: RefCell::default(),
+                save_retries: SaveRetries::default(),
             }),
             content_type,
             content: Rc::new(module),
         }
     }
+
+    fn push_fields(ucx: &UpdateContext, fields: ContentFields) {
+        if let Some(update) = fields.update {
+            ucx.pushToArray("updateArray", update.as_slice());
+        }
+        if let Some(payload) = fields.payload {
+            ucx.setField("payload", payload.into());
+        }
+        let keywords: js_sys::Array = fields
+            .keywords
+            .iter()
+            .map(|k| JsValue::from_str(k))
+            .collect();
+        ucx.setField("keywords", keywords.into());
+        if let Some(match_key) = fields.match_key {
+            ucx.setField("matchKey", match_key.into());
+        }
+    }
 }
 
+#[cfg(feature = "js")]
 #[wasm_bindgen]
 impl Property {
     pub fn create(
@@ -159,33 +192,17 @@ impl Property {
         self.content.obs().into()
     }
 
+    /// Push the content into `ucx`, written by `trx`. Its update is acknowledged once `trx`
+    /// commits, otherwise it is sent again by the next save.
     #[wasm_bindgen(js_name = "pushContent")]
-    pub fn push_content(&self, ucx: &UpdateContext) {
-        if self.content_type == ContentType::TextPlain {
-            let update_to_send = self.content.take_update_to_send();
-            ucx.pushToArray("updateArray", update_to_send.as_slice());
-            let keywords = self.content.obs().value().search_tokens_js();
-            ucx.setField("keywords", keywords.into());
-            let match_key = generate_sha256_hash(&self.content.obs().value().to_lossy_string()).into();
-            ucx.setField("matchKey", match_key);
-        } else if self.content_type == ContentType::TextXUri {
-            let payload: JsValue = self.content.obs().value().to_lossy_string().into();
-            ucx.setField("payload", payload);
-            ucx.setField("keywords", js_sys::Array::new().into());
-            let url = self.content.obs().value().to_lossy_string();
-            let normalized_url = normalize_url(&url);
-            match normalized_url {
-                Ok(url) => {
-                    let match_key = generate_sha256_hash(&url).into();
-                    ucx.setField("matchKey", match_key);
-                }
-                Err(_) => {}
+    pub fn push_content(&self, trx: &JsTrxRef, ucx: &UpdateContext) {
+        let fields = ContentFields::new(&self.content_type, &self.content, || {
+            match trx.upgrade_checked() {
+                Ok(txh) => self.content.update_to_send(&txh, || {}),
+                Err(_) => self.content.prepare_update().update,
             }
-        } else {
-            let payload: JsValue = self.content.obs().value().to_lossy_string().into();
-            ucx.setField("payload", payload);
-            ucx.setField("keywords", js_sys::Array::new().into());
-        }
+        });
+        Self::push_fields(ucx, fields);
     }
 
     /// Save now
@@ -193,7 +210,24 @@ impl Property {
         // Clear timer and also don't leak memory (debounce_timeout: Some() contains Rc to self.content)
         self.inner.debounce_timeout.borrow_mut().take();
         let ucx = UpdateContext::init();
-        self.push_content(&ucx);
+        let fields = match trx.upgrade_checked() {
+            Ok(txh) => {
+                let this = self.clone();
+                let retries = &self.inner.save_retries;
+                save_content(
+                    &txh,
+                    &self.content_type,
+                    &self.content,
+                    retries,
+                    move || this.debounce_save(),
+                )
+            }
+            // the save fails, its changes are sent by the next one
+            Err(_) => ContentFields::new(&self.content_type, &self.content, || {
+                self.content.prepare_update().update
+            }),
+        };
+        Self::push_fields(&ucx, fields);
 
         let _ = trx.update(self.inner.js_property.clone().into(), ucx.data());
         self.inner.js_property.applyBeforeSaveHooks(trx.clone());
@@ -214,6 +248,8 @@ impl Property {
 
         let cb = move || {
             let mut owned_trx = JsTransaction::create("tx-property-debounced");
+            // the edits are kept across reloads while the client is offline
+            owned_trx.queue_offline();
             let this = Property {
                 inner: inner.upgrade()?,
                 content_type,
@@ -264,6 +300,107 @@ impl Property {
     pub fn replace_content(&self, content: String) {
         self.content.replace_content(content);
     }
+
+    /// Revert the last local edits of the content, see `Content::undo`
+    pub fn undo(&self) -> Option<TextContentRange> {
+        self.content.undo()
+    }
+
+    pub fn redo(&self) -> Option<TextContentRange> {
+        self.content.redo()
+    }
+}
+
+/// The fields of the property document written by a save of its content
+#[derive(Debug, Default, PartialEq)]
+pub struct ContentFields {
+    /// The update appended to `updateArray`, for the content saved as yrs updates
+    pub update: Option<Vec<u8>>,
+    pub payload: Option<String>,
+    pub keywords: Vec<String>,
+    pub match_key: Option<String>,
+}
+
+impl ContentFields {
+    /// The fields saving `content`, `update_to_send` being called only for the content
+    /// saved as yrs updates
+    pub fn new(
+        content_type: &ContentType,
+        content: &Content,
+        update_to_send: impl FnOnce() -> Vec<u8>,
+    ) -> ContentFields {
+        let reader = content.obs();
+        let state = reader.value();
+        let text = state.to_lossy_string();
+        match content_type {
+            ContentType::TextPlain => ContentFields {
+                update: Some(update_to_send()),
+                payload: None,
+                keywords: state.search_tokens(),
+                match_key: Some(generate_sha256_hash(&text)),
+            },
+            ContentType::TextXUri => ContentFields {
+                match_key: normalize_url(&text)
+                    .ok()
+                    .map(|url| generate_sha256_hash(&url)),
+                payload: Some(text),
+                ..Default::default()
+            },
+            ContentType::Other(_) => ContentFields {
+                payload: Some(text),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Failed saves retried in a row, before waiting for the next edit to save again
+const MAX_SAVE_RETRIES: u32 = 3;
+
+/// Counts the saves of a property which failed in a row
+#[derive(Clone, Default)]
+pub struct SaveRetries(Rc<Cell<u32>>);
+
+impl SaveRetries {
+    /// Count a failed save, whether it should be retried
+    pub fn failed(&self) -> bool {
+        let failed = self.0.get() + 1;
+        self.0.set(failed);
+        if failed > MAX_SAVE_RETRIES {
+            log::error!("Property content could not be saved, {failed} saves failed in a row");
+        }
+        failed <= MAX_SAVE_RETRIES
+    }
+
+    pub fn succeeded(&self) {
+        self.0.set(0);
+    }
+}
+
+/// Prepare the fields saving `content` in the transaction `txh`. Its update is acknowledged
+/// once the transaction commits. If the transaction fails, the update is sent again by the
+/// next save, and `retry` is called unless too many saves failed in a row.
+pub fn save_content<B: Batchable>(
+    txh: &TrxHandle<B>,
+    content_type: &ContentType,
+    content: &Rc<Content>,
+    retries: &SaveRetries,
+    retry: impl FnOnce() + 'static,
+) -> ContentFields {
+    let fields = ContentFields::new(content_type, content, || {
+        let retries = retries.clone();
+        content.update_to_send(txh, move || {
+            if retries.failed() {
+                retry()
+            }
+        })
+    });
+    let retries = retries.clone();
+    txh.add_post_commit_hook(Box::new(move || {
+        retries.succeeded();
+        None
+    }));
+    fields
 }
 
 /// Convert a string into a SHA256 hash
@@ -300,6 +437,82 @@ pub fn normalize_url(url: &str) -> Result<String, Box<dyn Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::memory::{Document, MemoryDb, MemoryTrxOp};
+    use crate::transaction::Precondition;
+
+    async fn save(db: &MemoryDb, content: &Rc<Content>, retries: &SaveRetries) -> Rc<Cell<bool>> {
+        let retried = Rc::new(Cell::new(false));
+        let transaction = db.trx("save");
+        let fields = {
+            let retried = retried.clone();
+            let content_type = ContentType::TextPlain;
+            save_content(&transaction, &content_type, content, retries, move || {
+                retried.set(true)
+            })
+        };
+        // the property document is required to exist, so the commit fails until it does
+        let update = fields.update.unwrap();
+        let op = MemoryTrxOp::Update {
+            path: "property/p".into(),
+            data: Document::from_iter([("updateArray".into(), update.into())]),
+            precondition: Some(Precondition::Exists),
+        };
+        transaction.process_op(op).unwrap();
+        let _ = transaction.apply().await;
+        retried
+    }
+
+    #[tokio::test]
+    async fn failed_saves_are_retried_a_few_times_in_a_row() {
+        let db = MemoryDb::new();
+        let content = Rc::new(Content::from("hello"));
+        let retries = SaveRetries::default();
+        for _ in 0..MAX_SAVE_RETRIES {
+            assert!(save(&db, &content, &retries).await.get());
+        }
+        assert!(!save(&db, &content, &retries).await.get());
+        assert!(!content.is_acknowledged());
+
+        let create = db.trx("create");
+        let data = Document::new();
+        create
+            .process_op(MemoryTrxOp::Insert {
+                path: "property/p".into(),
+                data,
+            })
+            .unwrap();
+        create.apply().await.unwrap();
+        let retried = save(&db, &content, &retries).await;
+        assert!(!retried.get());
+        assert!(content.is_acknowledged());
+        assert_eq!(retries.0.get(), 0);
+    }
+
+    #[test]
+    fn text_fields_carry_the_update_and_search_tokens() {
+        let content = Content::from("Hello World");
+        let fields = ContentFields::new(&ContentType::TextPlain, &content, || vec![1, 2]);
+        assert_eq!(fields.update, Some(vec![1, 2]));
+        assert_eq!(fields.payload, None);
+        assert_eq!(fields.keywords, ["hello", "world"]);
+        assert_eq!(fields.match_key, Some(generate_sha256_hash("Hello World")));
+    }
+
+    #[test]
+    fn uri_fields_match_on_the_normalized_url() {
+        let uri = ContentType::TextXUri;
+        let url = " HTTP://Example.com/Path ";
+        let content = Content::from(url);
+        let fields = ContentFields::new(&uri, &content, || panic!("a url is not saved as updates"));
+        assert_eq!(fields.update, None);
+        assert_eq!(fields.payload.as_deref(), Some(url));
+        assert!(fields.keywords.is_empty());
+        let expected = generate_sha256_hash("http://example.com/Path");
+        assert_eq!(fields.match_key, Some(expected));
+
+        let fields = ContentFields::new(&uri, &Content::from("not a url"), Vec::new);
+        assert_eq!(fields.match_key, None);
+    }
 
     #[test]
     fn test_normalize_url_deals_with_origin() {
@@ -1,4 +1,4 @@
-use super::grapheme_indices_utf16_offsets;
+use super::{grapheme_indices_utf16_offsets, TextFormat};
 use crate::utils::helpers::len_utf16_str;
 
 use std::{ops::Deref, rc::Rc, sync::Arc};
@@ -33,6 +33,8 @@ impl IntoIterator for DataChunks {
 #[derive(Debug, Clone, PartialEq)]
 pub enum DataChunk {
     Text(Arc<str>),
+    /// Text with inline formatting, plain text being a `Text` chunk
+    Formatted(Arc<str>, TextFormat),
     Edge(String),
     // Binary()
     Unknown,
@@ -43,13 +45,41 @@ impl DataChunk {
         DataChunk::Text(text.into())
     }
 
+    /// Formatted text, or a `Text` chunk if `format` is plain
+    pub fn formatted(text: impl Into<Arc<str>>, format: TextFormat) -> DataChunk {
+        if format.is_plain() {
+            DataChunk::Text(text.into())
+        } else {
+            DataChunk::Formatted(text.into(), format)
+        }
+    }
+
     pub fn edge(edge_id: impl Into<String>) -> DataChunk {
         DataChunk::Edge(edge_id.into())
     }
 
+    /// The text of a text chunk, formatted or not
+    pub fn string(&self) -> Option<&str> {
+        match self {
+            DataChunk::Text(s) | DataChunk::Formatted(s, _) => Some(s),
+            DataChunk::Edge(_) => None,
+            DataChunk::Unknown => None,
+        }
+    }
+
+    /// The format of a text chunk, plain for `Text` chunks
+    pub fn format(&self) -> Option<TextFormat> {
+        match self {
+            DataChunk::Text(_) => Some(TextFormat::default()),
+            DataChunk::Formatted(_, format) => Some(format.clone()),
+            DataChunk::Edge(_) => None,
+            DataChunk::Unknown => None,
+        }
+    }
+
     pub fn len(&self) -> usize {
         match self {
-            DataChunk::Text(s) => len_utf16_str(s),
+            DataChunk::Text(s) | DataChunk::Formatted(s, _) => len_utf16_str(s),
             DataChunk::Edge(_) => 1,
             DataChunk::Unknown => 1,
         }
@@ -57,7 +87,7 @@ impl DataChunk {
 
     pub fn is_empty(&self) -> bool {
         match self {
-            DataChunk::Text(s) => s.is_empty(),
+            DataChunk::Text(s) | DataChunk::Formatted(s, _) => s.is_empty(),
             DataChunk::Edge(_) => false,
             DataChunk::Unknown => false,
         }
@@ -76,7 +106,7 @@ impl DataChunk {
     /// ```
     pub fn split_at(&self, offset: usize) -> Option<(DataChunk, DataChunk)> {
         match self {
-            DataChunk::Text(text) => {
+            DataChunk::Text(text) | DataChunk::Formatted(text, _) => {
                 if offset == 0 || len_utf16_str(text) <= offset {
                     None
                 } else {
@@ -91,9 +121,10 @@ impl DataChunk {
                         }
                     };
 
+                    let format = self.format().unwrap_or_default();
                     Some((
-                        DataChunk::text(&text[0..offset]),
-                        DataChunk::text(&text[offset..]),
+                        DataChunk::formatted(&text[0..offset], format.clone()),
+                        DataChunk::formatted(&text[offset..], format),
                     ))
                 }
             }
@@ -193,6 +224,11 @@ pub trait Chunkable: AsRef<[DataChunk]> {
                             chunks_vec.push(DataChunk::Text(text[0..wanted].into()));
                             chunks_vec.push(DataChunk::Text(text[wanted..].into()));
                         }
+                        DataChunk::Formatted(text, format) => {
+                            let (left, right) = (&text[0..wanted], &text[wanted..]);
+                            chunks_vec.push(DataChunk::Formatted(left.into(), format.clone()));
+                            chunks_vec.push(DataChunk::Formatted(right.into(), format.clone()));
+                        }
                         DataChunk::Edge(_) => unreachable!("Edges can not be splitted"),
                         DataChunk::Unknown => unreachable!("Unknown embed can not be splitted"),
                     }
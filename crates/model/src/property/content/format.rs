@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use yrs::{types::Attrs, Any};
+
+const BOLD: &str = "bold";
+const ITALIC: &str = "italic";
+const UNDERLINE: &str = "underline";
+const CODE: &str = "code";
+const LINK: &str = "link";
+const COLOR: &str = "color";
+
+/// Inline formatting of a run of text, stored as yrs text attributes.
+/// Attributes this version does not know about are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextFormat {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub code: bool,
+    pub link: Option<Arc<str>>,
+    pub color: Option<Arc<str>>,
+}
+
+impl TextFormat {
+    pub fn is_plain(&self) -> bool {
+        *self == TextFormat::default()
+    }
+
+    /// Whether the text has `attr`, with the same value for links and colors
+    pub fn has(&self, attr: &FormatAttr) -> bool {
+        match attr {
+            FormatAttr::Bold => self.bold,
+            FormatAttr::Italic => self.italic,
+            FormatAttr::Underline => self.underline,
+            FormatAttr::Code => self.code,
+            FormatAttr::Link(url) => self.link.as_deref() == Some(url.as_str()),
+            FormatAttr::Color(color) => self.color.as_deref() == Some(color.as_str()),
+        }
+    }
+
+    pub fn with(mut self, attr: FormatAttr) -> Self {
+        match attr {
+            FormatAttr::Bold => self.bold = true,
+            FormatAttr::Italic => self.italic = true,
+            FormatAttr::Underline => self.underline = true,
+            FormatAttr::Code => self.code = true,
+            FormatAttr::Link(url) => self.link = Some(url.into()),
+            FormatAttr::Color(color) => self.color = Some(color.into()),
+        }
+        self
+    }
+
+    /// Every attribute of this format
+    pub fn attrs(&self) -> Vec<FormatAttr> {
+        let flags = [
+            (self.bold, FormatAttr::Bold),
+            (self.italic, FormatAttr::Italic),
+            (self.underline, FormatAttr::Underline),
+            (self.code, FormatAttr::Code),
+        ];
+        let mut attrs: Vec<FormatAttr> = flags
+            .into_iter()
+            .filter_map(|(set, attr)| set.then_some(attr))
+            .collect();
+        attrs.extend(self.link.as_deref().map(|url| FormatAttr::Link(url.into())));
+        attrs.extend(
+            self.color
+                .as_deref()
+                .map(|color| FormatAttr::Color(color.into())),
+        );
+        attrs
+    }
+
+    /// Attributes to insert text with this format
+    pub fn to_attrs(&self) -> Attrs {
+        self.attrs()
+            .iter()
+            .flat_map(|attr| attr.to_attrs(true))
+            .collect()
+    }
+}
+
+impl From<&Attrs> for TextFormat {
+    fn from(attrs: &Attrs) -> Self {
+        let flag = |name| matches!(attrs.get(name), Some(Any::Bool(true)));
+        let string = |name| match attrs.get(name) {
+            Some(Any::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+        TextFormat {
+            bold: flag(BOLD),
+            italic: flag(ITALIC),
+            underline: flag(UNDERLINE),
+            code: flag(CODE),
+            link: string(LINK),
+            color: string(COLOR),
+        }
+    }
+}
+
+/// A single formatting attribute, as toggled on a range of text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatAttr {
+    Bold,
+    Italic,
+    Underline,
+    Code,
+    Link(String),
+    Color(String),
+}
+
+impl FormatAttr {
+    /// Parse an attribute from its name, links and colors requiring a value
+    ///
+    /// ```
+    /// use edvo_model::property::content::FormatAttr;
+    ///
+    /// assert_eq!(FormatAttr::new("bold", None), Some(FormatAttr::Bold));
+    /// assert_eq!(FormatAttr::new("color", Some("#f00".into())), Some(FormatAttr::Color("#f00".into())));
+    /// assert_eq!(FormatAttr::new("link", None), None);
+    /// assert_eq!(FormatAttr::new("size", None), None);
+    /// ```
+    pub fn new(name: &str, value: Option<String>) -> Option<FormatAttr> {
+        match (name, value) {
+            (BOLD, _) => Some(FormatAttr::Bold),
+            (ITALIC, _) => Some(FormatAttr::Italic),
+            (UNDERLINE, _) => Some(FormatAttr::Underline),
+            (CODE, _) => Some(FormatAttr::Code),
+            (LINK, Some(url)) => Some(FormatAttr::Link(url)),
+            (COLOR, Some(color)) => Some(FormatAttr::Color(color)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FormatAttr::Bold => BOLD,
+            FormatAttr::Italic => ITALIC,
+            FormatAttr::Underline => UNDERLINE,
+            FormatAttr::Code => CODE,
+            FormatAttr::Link(_) => LINK,
+            FormatAttr::Color(_) => COLOR,
+        }
+    }
+
+    /// Attributes setting this attribute, or removing it if `enabled` is false
+    pub fn to_attrs(&self, enabled: bool) -> Attrs {
+        let value = match self {
+            _ if !enabled => Any::Null,
+            FormatAttr::Link(s) | FormatAttr::Color(s) => Any::String(s.as_str().into()),
+            _ => Any::Bool(true),
+        };
+        Attrs::from([(self.name().into(), value)])
+    }
+}
@@ -7,15 +7,19 @@ pub(crate) use text::Content;
 mod data_chunks;
 pub use data_chunks::*;
 
+mod format;
+pub use format::*;
+
 use std::{fmt::Debug, rc::Rc};
 
 use unicode_segmentation::UnicodeSegmentation;
+#[cfg(feature = "js")]
 use wasm_bindgen::prelude::wasm_bindgen;
 use yrs::types::text::{Diff, YChange};
 
 use crate::utils::helpers::len_utf16_gr;
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 #[derive(Debug, Clone)]
 pub struct ContentState(Rc<[DataChunk]>);
 
@@ -26,7 +30,7 @@ impl Default for ContentState {
     }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 impl ContentState {
     pub fn from_string(text: String) -> ContentState {
         ContentState(Rc::new([DataChunk::Text(text.into())]))
@@ -45,7 +49,7 @@ impl ContentState {
     }
 
     /// Lengh in bytes or characters, depending on the type of content
-    #[wasm_bindgen(getter)]
+    #[cfg_attr(feature = "js", wasm_bindgen(getter))]
     pub fn length(&self) -> usize {
         let mut len = 0;
         for chunk in self.0.iter() {
@@ -65,17 +69,17 @@ impl ContentState {
     pub fn to_lossy_string(&self) -> String {
         let mut string = "".to_string();
         for chunk in self.chunks() {
-            if let DataChunk::Text(s) = chunk {
+            if let Some(s) = chunk.string() {
                 string.push_str(s);
             }
         }
         string
     }
 
-    #[wasm_bindgen]
+    #[cfg_attr(feature = "js", wasm_bindgen)]
     pub fn nth_grapheme(&self, mut n: usize) -> Option<String> {
         for chunk in self.chunks() {
-            if let DataChunk::Text(s) = chunk {
+            if let Some(s) = chunk.string() {
                 let grs = grapheme_indices_utf16_offsets(s);
                 let len = grs
                     .iter()
@@ -240,7 +244,10 @@ impl From<Diff<YChange>> for DataChunk {
         };
 
         match any {
-            Any::String(text) => DataChunk::Text(text),
+            Any::String(text) => match value.attributes {
+                Some(attrs) => DataChunk::formatted(text, attrs.as_ref().into()),
+                None => DataChunk::Text(text),
+            },
             Any::Map(map) => {
                 if let Some(Any::String(s)) = map.get("eid") {
                     DataChunk::Edge(s.to_string())
@@ -1,37 +1,66 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, rc::Rc};
 
 use observable_rs::{Observable, Reader};
+#[cfg(feature = "js")]
 use wasm_bindgen::prelude::wasm_bindgen;
-use yrs::{self, ReadTxn, Text, TextRef, Transact, Update};
+use yrs::{self, ReadTxn, Text, TextRef, Transact, TransactionAcqError, UndoManager, Update};
 use yrs::{types::text::YChange, updates::decoder::Decode};
 
-use crate::text_range::{TextContentPosition, TextContentRange};
+use crate::{
+    text_range::{TextContentPosition, TextContentRange},
+    transaction::{Batchable, TrxHandle},
+    utils::clock::epoch_millis,
+};
 
-use super::{ContentState, DataChunk, DataChunks};
+use super::{ContentState, DataChunk, DataChunks, FormatAttr, TextFormat};
 
 // Important to not create a new yrs::TEXT object with .get_text()
 const TEXT_NAME: &str = "";
 
+/// Origin of the edits made through `Content`, which are the only ones `undo` reverts
+const LOCAL_ORIGIN: &str = "local";
+/// Origin of the updates coming from the database, or replacing the whole content
+const REMOTE_ORIGIN: &str = "remote";
+
+/// An update sent to the server, which `Content` does not assume the server has until
+/// it is acknowledged
+pub struct PendingUpdate {
+    pub update: Vec<u8>,
+    state: yrs::StateVector,
+}
+
 /**
  * We would store updateArray, but we don't need to because the Doc does that for us
  * All property will have or more content_string fields representing their saved state
  */
-#[wasm_bindgen(js_name = TextContent)]
+#[cfg_attr(feature = "js", wasm_bindgen(js_name = TextContent))]
 pub struct Content {
     doc: yrs::Doc, // interior mutability
     presumed_server_state: RefCell<yrs::StateVector>,
     state: Observable<ContentState>,
+    /// Tracks the local edits only, so undoing leaves the concurrent remote edits in place
+    undo_manager: RefCell<UndoManager>,
 }
 
 impl Default for Content {
     fn default() -> Self {
+        let doc = yrs::Doc::with_options(yrs::Options {
+            offset_kind: yrs::OffsetKind::Utf16,
+            ..Default::default()
+        });
+        // the default timestamp of the undo groups reads `SystemTime`, which panics on wasm
+        let options = yrs::undo::Options {
+            timestamp: Rc::new(epoch_millis),
+            ..Default::default()
+        };
+        let text = doc.get_or_insert_text(TEXT_NAME);
+        let mut undo_manager = UndoManager::with_options(&doc, &text, options);
+        undo_manager.include_origin(LOCAL_ORIGIN);
         Self {
-            doc: yrs::Doc::with_options(yrs::Options {
-                offset_kind: yrs::OffsetKind::Utf16,
-                ..Default::default()
-            }),
+            doc,
             presumed_server_state: Default::default(),
             state: Default::default(),
+            undo_manager: RefCell::new(undo_manager),
         }
     }
 }
@@ -48,15 +77,14 @@ impl From<String> for Content {
     fn from(legacy_content: String) -> Self {
         let content = Content::new();
         content.insert_chunk(0, legacy_content);
+        content.clear_undo_history();
         content
     }
 }
 
 impl From<&str> for Content {
     fn from(legacy_content: &str) -> Self {
-        let content = Content::new();
-        content.insert_chunk(0, legacy_content.to_owned());
-        content
+        legacy_content.to_owned().into()
     }
 }
 
@@ -89,7 +117,7 @@ impl Content {
     pub fn apply_updates_from_db(&self, updates: &[u8], update_lengths: &[usize]) {
         {
             let text = self.text();
-            let mut remote_trx = text.transact_mut();
+            let mut remote_trx = text.transact_mut_with(REMOTE_ORIGIN);
             let mut start = 0;
             for length in update_lengths {
                 let end = start + length;
@@ -107,7 +135,7 @@ impl Content {
     pub fn replace_content(&self, content: String) {
         {
             let text = self.text();
-            let mut remote_trx = text.transact_mut();
+            let mut remote_trx = text.transact_mut_with(REMOTE_ORIGIN);
             let len = text.len(&remote_trx);
             text.remove_range(&mut remote_trx, 0, len);
             text.insert(&mut remote_trx, 0, &content);
@@ -118,11 +146,14 @@ impl Content {
     pub fn insert_chunk(&self, index: u32, chunk: impl Into<DataChunk>) {
         {
             let text = self.text();
-            let mut txn = text.transact_mut();
+            let mut txn = text.transact_mut_with(LOCAL_ORIGIN);
 
             let chunk = chunk.into();
             match chunk {
                 DataChunk::Text(ref s) => text.insert(&mut txn, index, s),
+                DataChunk::Formatted(ref s, ref format) => {
+                    text.insert_with_attributes(&mut txn, index, s, format.to_attrs());
+                }
                 DataChunk::Edge(edge_id) => {
                     let map = yrs::any!({ "eid": edge_id });
                     text.insert_embed(&mut txn, index, map);
@@ -140,7 +171,7 @@ impl Content {
 
         {
             let text = self.text();
-            let mut txn = text.transact_mut();
+            let mut txn = text.transact_mut_with(LOCAL_ORIGIN);
             text.remove_range(&mut txn, index, len);
         }
 
@@ -160,6 +191,52 @@ impl Content {
         self.remove_range(index, length);
         self.insert_chunk(index, new_chunk);
     }
+    /// Set `attr` on the range, or remove it if `enabled` is false
+    pub fn format_range(&self, index: u32, len: u32, attr: &FormatAttr, enabled: bool) {
+        if len == 0 {
+            return;
+        }
+
+        {
+            let text = self.text();
+            let mut txn = text.transact_mut_with(LOCAL_ORIGIN);
+            text.format(&mut txn, index, len, attr.to_attrs(enabled));
+        }
+
+        self.notify_state()
+    }
+
+    /// Whether all the text in the range has `attr`, ignoring the embeds
+    pub fn has_format(&self, index: u32, len: u32, attr: &FormatAttr) -> bool {
+        let (start, end) = (index as usize, index.saturating_add(len) as usize);
+        let mut offset = 0;
+        let mut has_text = false;
+        for chunk in self.content() {
+            let chunk_end = offset + chunk.len();
+            if offset < end && start < chunk_end {
+                if let Some(format) = chunk.format() {
+                    if !format.has(attr) {
+                        return false;
+                    }
+                    has_text = true;
+                }
+            }
+            offset = chunk_end;
+        }
+        has_text
+    }
+
+    /// Remove `attr` from the range if all its text has it, set it otherwise.
+    /// Returns true if the range has `attr` now.
+    pub fn toggle_format(&self, index: u32, len: u32, attr: &FormatAttr) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let enabled = !self.has_format(index, len, attr);
+        self.format_range(index, len, attr, enabled);
+        enabled
+    }
+
     pub fn clear_content(&self) -> bool {
         let len = self.len();
         if len > 0 {
@@ -170,14 +247,61 @@ impl Content {
         }
     }
 
-    pub fn take_update_to_send(&self) -> Vec<u8> {
+    /// Encode the changes the server is not known to have. They are part of every update
+    /// prepared afterwards, until the update is acknowledged with `acknowledge_update`.
+    pub fn prepare_update(&self) -> PendingUpdate {
         let txn = self.doc.transact();
+        let presumed_server_state = self.presumed_server_state.borrow();
+        PendingUpdate {
+            update: txn.encode_diff_v1(&presumed_server_state),
+            state: txn.state_vector(),
+        }
+    }
 
-        let mut presumed_server_state = self.presumed_server_state.borrow_mut();
-        let update_to_send = txn.encode_diff_v1(&presumed_server_state);
-        *presumed_server_state = txn.state_vector();
+    /// The server has the changes of `pending`, they are not sent again
+    pub fn acknowledge_update(&self, pending: PendingUpdate) {
+        self.acknowledge_state(pending.state);
+    }
 
-        update_to_send
+    fn acknowledge_state(&self, state: yrs::StateVector) {
+        // updates may be acknowledged out of order
+        self.presumed_server_state.borrow_mut().merge(state);
+    }
+
+    /// Whether every change was acknowledged by the server
+    pub fn is_acknowledged(&self) -> bool {
+        let txn = self.doc.transact();
+        txn.state_vector() == *self.presumed_server_state.borrow()
+    }
+
+    /// Prepare an update sent by the transaction `txh`, acknowledged once it commits.
+    /// If the transaction fails, the changes stay unacknowledged so the next update sends
+    /// them again, and `on_abort` is called to save them again.
+    pub fn update_to_send<B: Batchable>(
+        self: &Rc<Self>,
+        txh: &TrxHandle<B>,
+        on_abort: impl FnOnce() + 'static,
+    ) -> Vec<u8> {
+        let PendingUpdate { update, state } = self.prepare_update();
+        let content = Rc::downgrade(self);
+        txh.add_post_commit_hook(Box::new(move || {
+            if let Some(content) = content.upgrade() {
+                content.acknowledge_state(state);
+            }
+            None
+        }));
+        txh.add_abort_hook(Box::new(move || {
+            on_abort();
+            None
+        }));
+        update
+    }
+
+    /// Prepare an update which is assumed to reach the server, as it can not be acknowledged
+    pub fn take_update_to_send(&self) -> Vec<u8> {
+        let PendingUpdate { update, state } = self.prepare_update();
+        self.acknowledge_state(state);
+        update
     }
 
     /// Get the present ContentState inclusive of any unsaved writes
@@ -203,13 +327,84 @@ impl Content {
         self.state.reader()
     }
 
+    pub fn can_undo(&self) -> bool {
+        self.undo_manager.borrow().can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.undo_manager.borrow().can_redo()
+    }
+
+    /// Stop merging the next local edit into the last one, so they are undone separately
+    pub fn break_undo_group(&self) {
+        self.undo_manager.borrow_mut().reset();
+    }
+
+    pub fn clear_undo_history(&self) {
+        let _ = self.undo_manager.borrow_mut().clear();
+    }
+
+    fn undo_with(
+        &self,
+        f: impl FnOnce(&mut UndoManager) -> Result<bool, TransactionAcqError>,
+    ) -> Option<TextContentRange> {
+        let before = self.content().into_iter().collect::<Vec<_>>();
+        match f(&mut self.undo_manager.borrow_mut()) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                log::warn!("Unable to undo or redo the text content: {e}");
+                return None;
+            }
+        }
+        self.notify_state();
+
+        let after = self.content().into_iter().collect::<Vec<_>>();
+        let offset = changed_end(&units(&before), &units(&after));
+        self.get_range(offset, offset)
+    }
+
     fn notify_state(&self) {
         let content = self.content();
         self.state.set(content.into())
     }
 }
 
-#[wasm_bindgen(js_class = TextContent)]
+/// Smallest part of the content an offset can point to
+#[derive(PartialEq)]
+enum Unit<'c> {
+    /// A code unit, along with its format so undoing a format change moves the caret too
+    Utf16(u16, Option<&'c TextFormat>),
+    Embed(&'c DataChunk),
+}
+
+fn units(chunks: &[DataChunk]) -> Vec<Unit<'_>> {
+    let mut units = Vec::new();
+    for chunk in chunks {
+        match chunk {
+            DataChunk::Text(s) => units.extend(s.encode_utf16().map(|u| Unit::Utf16(u, None))),
+            DataChunk::Formatted(s, format) => {
+                units.extend(s.encode_utf16().map(|u| Unit::Utf16(u, Some(format))))
+            }
+            chunk => units.push(Unit::Embed(chunk)),
+        }
+    }
+    units
+}
+
+/// Offset in `after` right after the part which differs from `before`
+fn changed_end(before: &[Unit], after: &[Unit]) -> u32 {
+    let prefix = before.iter().zip(after).take_while(|(b, a)| b == a).count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(b, a)| b == a)
+        .count();
+    (after.len() - suffix) as u32
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen(js_class = TextContent))]
 impl Content {
     #[inline]
     pub fn new() -> Self {
@@ -222,14 +417,32 @@ impl Content {
     pub fn insert_edge(&self, index: u32, edge_id: String) {
         self.insert_chunk(index, DataChunk::Edge(edge_id));
     }
+
+    /// Revert the last local edits, keeping the remote ones. Return a collapsed range where
+    /// the caret goes, or `None` if there was nothing to undo.
+    pub fn undo(&self) -> Option<TextContentRange> {
+        self.undo_with(|undo_manager| undo_manager.undo())
+    }
+
+    /// Make the last undone local edits again, see `undo`
+    pub fn redo(&self) -> Option<TextContentRange> {
+        self.undo_with(|undo_manager| undo_manager.redo())
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::{cell::Cell, rc::Rc};
+
     use super::Content;
-    use crate::property::content::{
-        Chunkable, ContentState,
-        DataChunk::{self, *},
+    use crate::{
+        db::memory::{Document, MemoryDb, MemoryTrxOp},
+        property::content::{
+            Chunkable, ContentState,
+            DataChunk::{self, *},
+            FormatAttr, TextFormat,
+        },
+        transaction::Precondition,
     };
 
     #[test]
@@ -326,4 +539,113 @@ mod test {
         let chunks: Vec<_> = content.chunks().cloned().collect();
         assert_eq!(chunks, [Text("hllo worl".into())]);
     }
+
+    #[test]
+    fn undo_reverts_local_edits_only() {
+        let sync = |from: &Content, to: &Content| {
+            let update = from.take_update_to_send();
+            to.apply_updates_from_db(&update, &[update.len()]);
+        };
+        let chunks = |text: &Content| -> Vec<DataChunk> { text.content().into_iter().collect() };
+
+        let local = Content::from("ab");
+        let remote = Content::new();
+        sync(&local, &remote);
+        assert!(!local.can_undo());
+
+        local.insert_chunk(2, "cd".to_string());
+        remote.insert_chunk(0, "xy".to_string());
+        sync(&remote, &local);
+        assert_eq!(chunks(&local), [Text("xyabcd".into())]);
+
+        let caret = local.undo().unwrap();
+        assert_eq!(chunks(&local), [Text("xyab".into())]);
+        assert_eq!(caret.get_offsets().unwrap(), (4, 4));
+        assert!(local.undo().is_none());
+
+        // the caret follows the remote edits
+        remote.insert_chunk(0, "z".to_string());
+        sync(&remote, &local);
+        assert_eq!(caret.get_offsets().unwrap(), (5, 5));
+
+        let caret = local.redo().unwrap();
+        assert_eq!(chunks(&local), [Text("zxyabcd".into())]);
+        assert_eq!(caret.get_offsets().unwrap(), (7, 7));
+    }
+
+    #[test]
+    fn formatting_survives_sync() {
+        let sync = |from: &Content, to: &Content| {
+            let update = from.take_update_to_send();
+            to.apply_updates_from_db(&update, &[update.len()]);
+        };
+        let chunks = |text: &Content| -> Vec<DataChunk> { text.content().into_iter().collect() };
+        let bold = TextFormat::default().with(FormatAttr::Bold);
+        let link = FormatAttr::Link("https://edvo.com".into());
+
+        let local = Content::from("hello world");
+        let remote = Content::new();
+        assert!(local.toggle_format(0, 5, &FormatAttr::Bold));
+        local.format_range(6, 5, &link, true);
+        local.insert_chunk(11, DataChunk::formatted("!", bold.clone()));
+        let formatted = [
+            DataChunk::formatted("hello", bold.clone()),
+            Text(" ".into()),
+            DataChunk::formatted("world", TextFormat::default().with(link.clone())),
+            DataChunk::formatted("!", bold),
+        ];
+        assert_eq!(chunks(&local), formatted);
+        assert!(local.has_format(0, 5, &FormatAttr::Bold));
+        assert!(!local.has_format(0, 6, &FormatAttr::Bold));
+
+        sync(&local, &remote);
+        assert_eq!(chunks(&remote), formatted);
+
+        // toggling it again removes it
+        assert!(!local.toggle_format(0, 5, &FormatAttr::Bold));
+        local.format_range(6, 5, &link, false);
+        sync(&local, &remote);
+        let content: ContentState = remote.content().into();
+        assert_eq!(content.to_lossy_string(), "hello world!");
+        assert!(!remote.has_format(0, 5, &FormatAttr::Bold));
+        assert!(!remote.has_format(6, 5, &link));
+        assert!(remote.has_format(11, 1, &FormatAttr::Bold));
+    }
+
+    #[tokio::test]
+    async fn failed_save_is_sent_again() {
+        let db = MemoryDb::new();
+        let content = Rc::new(Content::from("hello"));
+        let aborted = Rc::new(Cell::new(false));
+        let save = |update: Vec<u8>, precondition| MemoryTrxOp::Update {
+            path: "property/p".into(),
+            data: Document::from_iter([("updateArray".into(), update.into())]),
+            precondition,
+        };
+
+        // the property document is required to exist, so the commit fails
+        let transaction = db.trx("save");
+        let update = {
+            let aborted = aborted.clone();
+            content.update_to_send(&transaction, move || aborted.set(true))
+        };
+        let exists = Some(Precondition::Exists);
+        transaction.process_op(save(update, exists)).unwrap();
+        assert!(transaction.apply().await.is_err());
+        assert!(aborted.get());
+        assert!(!content.is_acknowledged());
+
+        content.insert_chunk(5, " world".to_string());
+        let transaction = db.trx("retry");
+        let update = content.update_to_send(&transaction, || panic!("the retry failed"));
+        transaction.process_op(save(update.clone(), None)).unwrap();
+        transaction.apply().await.unwrap();
+        assert!(content.is_acknowledged());
+
+        // the retry carries the edits of the failed save along with the new ones
+        let server = Content::new();
+        server.apply_updates_from_db(&update, &[update.len()]);
+        let chunks: Vec<_> = server.content().into_iter().collect();
+        assert_eq!(chunks, [Text("hello world".into())]);
+    }
 }
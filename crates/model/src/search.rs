@@ -1,6 +1,7 @@
 use std::{borrow::Cow, sync::Arc};
 
 use once_cell::sync::Lazy;
+#[cfg(feature = "js")]
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
 use crate::property::content::{Chunkable, ContentState, DataChunk, DataChunks};
@@ -31,6 +32,7 @@ static TOKEN_STRIP: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"\W").u
 //     return filterTerms(splitString(s)).map((t) => termToToken(t));
 //   }
 
+#[cfg(feature = "js")]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(typescript_type = "string[]")]
@@ -61,6 +63,7 @@ pub trait SearchTokens: Words {
     fn search_tokens(&self) -> Vec<String> {
         self.iter_search_token().collect()
     }
+    #[cfg(feature = "js")]
     fn search_tokens_js(&self) -> JsStringArray {
         let array: js_sys::Array = self
             .iter_search_token()
@@ -91,7 +94,7 @@ impl Words for &[DataChunk] {
     fn words(&self) -> impl Iterator<Item = &str> {
         self.iter()
             .filter_map(|c| match c {
-                DataChunk::Text(s) => Some(s.words()),
+                DataChunk::Text(s) | DataChunk::Formatted(s, _) => Some(s.words()),
                 DataChunk::Edge(_) => None,
                 DataChunk::Unknown => None,
             })
@@ -103,7 +106,7 @@ impl Words for DataChunks {
     fn words(&self) -> impl Iterator<Item = &str> {
         self.iter()
             .filter_map(|c| match c {
-                DataChunk::Text(s) => Some(s.words()),
+                DataChunk::Text(s) | DataChunk::Formatted(s, _) => Some(s.words()),
                 DataChunk::Edge(_) => None,
                 DataChunk::Unknown => None,
             })
@@ -114,7 +117,7 @@ impl Words for ContentState {
     fn words(&self) -> impl Iterator<Item = &str> {
         self.chunks()
             .filter_map(|c| match c {
-                DataChunk::Text(s) => Some(s.words()),
+                DataChunk::Text(s) | DataChunk::Formatted(s, _) => Some(s.words()),
                 DataChunk::Edge(_) => None,
                 DataChunk::Unknown => None,
             })
@@ -1,19 +1,31 @@
 use std::{
-    cell::{Cell, OnceCell},
+    cell::{Cell, OnceCell, RefCell},
     marker::PhantomData,
+    mem,
+    ops::Deref,
     sync::Arc,
 };
 
+use futures::future::LocalBoxFuture;
+#[cfg(feature = "js")]
 use observable_react::JsObservable;
 use observable_rs::{Observable, Reader};
+use serde::Serialize;
+#[cfg(feature = "js")]
 use wasm_bindgen::prelude::*;
 
+use crate::transaction::{TrxApplyErr, TrxErrCode};
+
 #[derive(Clone, Default)]
 pub enum Status {
     #[default]
     Init,
     Clean,
     Dirty,
+    /// A transaction failed to commit and is being retried
+    Retrying,
+    /// Transactions are queued, waiting to be replayed once the client is back online
+    Offline,
     Error,
 }
 
@@ -23,11 +35,14 @@ impl From<Status> for &str {
             Status::Init => "init",
             Status::Clean => "clean",
             Status::Dirty => "dirty",
+            Status::Retrying => "retrying",
+            Status::Offline => "offline",
             Status::Error => "error",
         }
     }
 }
 
+#[cfg(feature = "js")]
 impl From<Status> for JsValue {
     fn from(value: Status) -> Self {
         let val: &str = value.into();
@@ -35,10 +50,56 @@ impl From<Status> for JsValue {
     }
 }
 
+/// Commits the writes of a failed transaction again
+pub type RetryFn = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()>>;
+
+/// A transaction which failed, until it is retried or dismissed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrxFailure {
+    pub name: String,
+    pub code: TrxErrCode,
+    pub message: String,
+    /// Whether `SessionManager::retry_failed` commits its writes again
+    pub retryable: bool,
+}
+
+/// Failures kept until they are retried or dismissed, the oldest being forgotten first
+const MAX_FAILURES: usize = 50;
+
+/// Failed transactions, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct TrxFailures(pub Vec<TrxFailure>);
+
+impl Deref for TrxFailures {
+    type Target = [TrxFailure];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "js")]
+impl From<TrxFailures> for JsValue {
+    fn from(value: TrxFailures) -> Self {
+        serde_json::to_string(&value)
+            .ok()
+            .and_then(|json| js_sys::JSON::parse(&json).ok())
+            .unwrap_or(JsValue::NULL)
+    }
+}
+
 #[derive(Default)]
 struct Inner {
     pending_writes: Cell<usize>,
     pending_errors: Cell<usize>,
+    pending_retries: Cell<usize>,
+    pending_replays: Cell<usize>,
+    /// Names of the transactions being written
+    in_flight: RefCell<Vec<String>>,
+    failed: RefCell<Vec<(TrxFailure, Option<RetryFn>)>>,
+    failures: Observable<TrxFailures>,
     status: Observable<Status>,
 }
 
@@ -64,18 +125,101 @@ impl Inner {
         self.update_status();
     }
 
+    fn increment_retries(&self) {
+        let pr = self.pending_retries.get() + 1;
+        self.pending_retries.set(pr);
+        self.update_status();
+    }
+    fn decrement_retries(&self) {
+        let pr = self.pending_retries.get() - 1;
+        self.pending_retries.set(pr);
+        self.update_status();
+    }
+
+    fn set_replays(&self, replays: usize) {
+        self.pending_replays.set(replays);
+        self.update_status();
+    }
+
+    fn trx_started(&self, name: &str) {
+        self.in_flight.borrow_mut().push(name.to_string());
+        self.increment_writes();
+    }
+    fn trx_finished(&self, name: &str) {
+        let mut in_flight = self.in_flight.borrow_mut();
+        if let Some(i) = in_flight.iter().position(|n| n == name) {
+            in_flight.remove(i);
+            drop(in_flight);
+            self.decrement_writes();
+        }
+    }
+
+    fn trx_failed(&self, failure: TrxFailure, retry: Option<RetryFn>) {
+        let mut failed = self.failed.borrow_mut();
+        failed.push((failure, retry));
+        let excess = failed.len().saturating_sub(MAX_FAILURES);
+        failed.drain(..excess);
+        drop(failed);
+        self.update_failures();
+    }
+    fn dismiss(&self, name: &str) -> bool {
+        let mut failed = self.failed.borrow_mut();
+        let count = failed.len();
+        failed.retain(|(failure, _)| failure.name != name);
+        let dismissed = failed.len() < count;
+        drop(failed);
+        self.update_failures();
+        dismissed
+    }
+    /// Remove the retryable failures, returning their retries
+    fn take_retries(&self) -> Vec<RetryFn> {
+        let failed = mem::take(&mut *self.failed.borrow_mut());
+        let mut retries = Vec::new();
+        for (failure, retry) in failed {
+            match retry {
+                Some(retry) => retries.push(retry),
+                None => self.failed.borrow_mut().push((failure, None)),
+            }
+        }
+        self.update_failures();
+        retries
+    }
+
+    fn has_unsaved_work(&self) -> bool {
+        self.pending_writes.get() > 0
+            || self.pending_retries.get() > 0
+            || self.pending_replays.get() > 0
+            || self
+                .failed
+                .borrow()
+                .iter()
+                .any(|(_, retry)| retry.is_some())
+    }
+
+    fn update_failures(&self) {
+        let failed = self.failed.borrow();
+        let failures = failed.iter().map(|(failure, _)| failure.clone()).collect();
+        drop(failed);
+        self.failures.set(TrxFailures(failures));
+        self.update_status();
+    }
+
     fn update_status(&self) {
         let pw = self.pending_writes.get();
-        let pe = self.pending_errors.get();
+        let pe = self.pending_errors.get() + self.failed.borrow().len();
+        let pr = self.pending_retries.get();
+        let pq = self.pending_replays.get();
         self.status.set(
             if pe > 0 { Status::Error }
+                else if pr > 0 { Status::Retrying }
+                else if pq > 0 { Status::Offline }
                 else if pw > 0 { Status::Dirty }
                 else { Status::Clean }
         )
     }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 #[derive(Clone, Default)]
 pub struct SessionManager {
     inner: Arc<Inner>,
@@ -90,33 +234,145 @@ impl SessionManager {
     pub fn current_session<'s>(&self) -> CurrentSession<'s> {
         CurrentSession::new(self.clone())
     }
+    pub fn increment_writes(&self) {
+        self.inner.increment_writes()
+    }
+    pub fn decrement_writes(&self) {
+        self.inner.decrement_writes()
+    }
+    pub fn increment_errors(&self) {
+        self.inner.increment_errors()
+    }
+    pub fn decrement_errors(&self) {
+        self.inner.decrement_errors()
+    }
+    pub fn increment_retries(&self) {
+        self.inner.increment_retries()
+    }
+    pub fn decrement_retries(&self) {
+        self.inner.decrement_retries()
+    }
+    /// Number of transactions waiting in the offline queue
+    pub fn set_replays(&self, replays: usize) {
+        self.inner.set_replays(replays)
+    }
+
+    /// Track the transaction `name` as a pending write, until `trx_finished` is called
+    pub fn trx_started(&self, name: &str) {
+        self.inner.trx_started(name)
+    }
+    pub fn trx_finished(&self, name: &str) {
+        self.inner.trx_finished(name)
+    }
+    /// Names of the transactions being written, in the order they started
+    pub fn in_flight(&self) -> Vec<String> {
+        self.inner.in_flight.borrow().clone()
+    }
+
+    /// Record the failure of the transaction `name`, which `retry` commits again if given.
+    /// Cancelled transactions are not failures, they were meant not to be written.
+    pub fn trx_failed(&self, name: &str, err: &TrxApplyErr, retry: Option<RetryFn>) {
+        if err.code() == TrxErrCode::Cancelled {
+            return;
+        }
+        let failure = TrxFailure {
+            name: name.to_string(),
+            code: err.code(),
+            message: err.to_string(),
+            retryable: retry.is_some(),
+        };
+        self.inner.trx_failed(failure, retry)
+    }
+    /// Updated whenever a transaction fails, is retried or dismissed
+    pub fn failures(&self) -> Reader<TrxFailures> {
+        self.inner.failures.reader()
+    }
+    /// Forget the failures of the transaction `name`, return false if there were none
+    pub fn dismiss(&self, name: &str) -> bool {
+        self.inner.dismiss(name)
+    }
+    /// Retry the retryable failures, oldest first. They are removed from the failures, and
+    /// the retried transactions report their own outcome. The others stay until dismissed.
+    pub async fn retry_failed(&self) {
+        for retry in self.inner.take_retries() {
+            retry().await;
+        }
+    }
+
+    /// Whether leaving now would lose writes: transactions are being written or queued,
+    /// or failed and can be retried
+    pub fn has_unsaved_work(&self) -> bool {
+        self.inner.has_unsaved_work()
+    }
 }
 
+#[cfg(feature = "js")]
 #[wasm_bindgen]
 impl SessionManager {
     #[wasm_bindgen(js_name = increment_writes)]
     pub fn js_increment_writes(&self) {
-        self.inner.increment_writes()
+        self.increment_writes()
     }
     #[wasm_bindgen(js_name = decrement_writes)]
     pub fn js_decrement_writes(&self) {
-        self.inner.decrement_writes()
+        self.decrement_writes()
     }
     #[wasm_bindgen(js_name = increment_errors)]
     pub fn js_increment_errors(&self) {
-        self.inner.increment_errors()
+        self.increment_errors()
     }
     #[wasm_bindgen(js_name = decrement_errors)]
     pub fn js_decrement_errors(&self) {
-        self.inner.decrement_errors()
+        self.decrement_errors()
+    }
+    #[wasm_bindgen(js_name = increment_retries)]
+    pub fn js_increment_retries(&self) {
+        self.increment_retries()
+    }
+    #[wasm_bindgen(js_name = decrement_retries)]
+    pub fn js_decrement_retries(&self) {
+        self.decrement_retries()
+    }
+    #[wasm_bindgen(js_name = set_replays)]
+    pub fn js_set_replays(&self, replays: usize) {
+        self.set_replays(replays)
     }
     // TODO auto-generate this with a macro
     #[wasm_bindgen(js_name = status)]
     pub fn js_status(&self) -> JsObservable {
         self.status().into()
     }
+    /// `TrxFailure[]`, oldest first
+    #[wasm_bindgen(js_name = failures)]
+    pub fn js_failures(&self) -> JsObservable {
+        self.failures().into()
+    }
+    #[wasm_bindgen(js_name = dismiss)]
+    pub fn js_dismiss(&self, name: &str) -> bool {
+        self.dismiss(name)
+    }
+    #[wasm_bindgen(js_name = retry_failed)]
+    pub async fn js_retry_failed(&self) {
+        self.retry_failed().await
+    }
+    /// To be checked by a `beforeunload` listener
+    #[wasm_bindgen(js_name = has_unsaved_work)]
+    pub fn js_has_unsaved_work(&self) -> bool {
+        self.has_unsaved_work()
+    }
 }
 
+#[cfg(feature = "js")]
+#[wasm_bindgen(typescript_custom_section)]
+const TRX_FAILURE: &str = r#"
+interface TrxFailure {
+    name: string;
+    code: TrxErrorCode;
+    message: string;
+    retryable: boolean;
+}
+"#;
+
 pub struct CurrentSession<'s> {
     sm: SessionManager,
     phantom: PhantomData<&'s SessionManager>,
@@ -140,16 +396,19 @@ thread_local! {
     pub static SESSION_MANAGER: OnceCell<SessionManager> = OnceCell::new();
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 pub fn use_session_manager() -> SessionManager {
     SESSION_MANAGER.with(|cell| cell.get_or_init(SessionManager::new).clone())
 }
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{cell::Cell, rc::Rc, sync::Arc};
+
+    use futures::executor::block_on;
 
-    use super::{use_session_manager, SessionManager};
+    use super::{use_session_manager, RetryFn, SessionManager, Status, MAX_FAILURES};
+    use crate::transaction::{TrxApplyErr, TrxDbErr, TrxErrCode};
 
     #[test]
     fn global_session_manager_test() {
@@ -159,4 +418,60 @@ mod test {
         assert!(Arc::ptr_eq(&sm1.inner, &sm2.inner));
         assert!(!Arc::ptr_eq(&sm1.inner, &other.inner));
     }
+
+    #[test]
+    fn failed_transactions_test() {
+        let sm = SessionManager::new();
+        let retried = Rc::new(Cell::new(0));
+        let retry = || -> RetryFn {
+            let retried = retried.clone();
+            Box::new(move || Box::pin(async move { retried.set(retried.get() + 1) }))
+        };
+        let failures = sm.failures();
+        let names = || -> Vec<String> { failures.value().iter().map(|f| f.name.clone()).collect() };
+
+        sm.trx_started("a");
+        sm.trx_started("b");
+        sm.trx_started("c");
+        assert_eq!(sm.in_flight(), ["a", "b", "c"]);
+        for name in ["a", "b", "c"] {
+            sm.trx_finished(name);
+        }
+        assert!(!sm.has_unsaved_work());
+
+        let unavailable = || TrxApplyErr::Commit(TrxDbErr::new(TrxErrCode::Unavailable, "").into());
+        sm.trx_failed("a", &TrxApplyErr::TimedOut(Default::default()), None);
+        sm.trx_failed("b", &unavailable(), Some(retry()));
+        sm.trx_failed("c", &unavailable(), Some(retry()));
+        // not a failure
+        sm.trx_failed("d", &TrxApplyErr::Cancelled, None);
+        assert_eq!(names(), ["a", "b", "c"]);
+        assert_eq!(failures.value()[1].code, TrxErrCode::Unavailable);
+        assert!(matches!(*sm.status().value(), Status::Error));
+        assert!(sm.has_unsaved_work());
+
+        assert!(sm.dismiss("c"));
+        assert!(!sm.dismiss("c"));
+        block_on(sm.retry_failed());
+        assert_eq!(retried.get(), 1);
+        assert_eq!(names(), ["a"]);
+        // retrying can not save the writes of "a" anymore
+        assert!(!sm.has_unsaved_work());
+
+        sm.dismiss("a");
+        assert!(matches!(*sm.status().value(), Status::Clean));
+        assert!(!sm.has_unsaved_work());
+    }
+
+    #[test]
+    fn oldest_failures_are_forgotten() {
+        let sm = SessionManager::new();
+        let timed_out = TrxApplyErr::TimedOut(Default::default());
+        for i in 0..=MAX_FAILURES {
+            sm.trx_failed(&i.to_string(), &timed_out, None);
+        }
+        let failures = sm.failures();
+        assert_eq!(failures.value().len(), MAX_FAILURES);
+        assert_eq!(failures.value()[0].name, "1");
+    }
 }
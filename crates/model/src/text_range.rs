@@ -1,3 +1,4 @@
+#[cfg(feature = "js")]
 use wasm_bindgen::prelude::wasm_bindgen;
 use yrs::{StickyIndex, TextRef, Transact};
 
@@ -36,7 +37,7 @@ impl<T: yrs::IndexedSequence + Clone> ContentPosition<T> {
     }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 pub struct TextContentPosition(ContentPosition<yrs::TextRef>);
 
 impl TextContentPosition {
@@ -62,7 +63,7 @@ pub struct TextOffset(u32);
 /// It is directional, meaning that in cases where the user has selected from
 /// right to left, the start will represent the rightmost position in the text,
 /// and the end will represent the leftmost.
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 pub struct TextContentRange(ContentRange<yrs::TextRef>);
 
 pub struct ContentRange<T: yrs::IndexedSequence + Clone> {
@@ -100,7 +101,7 @@ impl TextContentRange {
     }
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 impl TextContentRange {
     // IDEA: consider renaming this to present_offset to communicate
     //       its ephemeral nature
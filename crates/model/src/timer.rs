@@ -1,6 +1,10 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, time::Duration};
 
+use futures::future::LocalBoxFuture;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::transaction::Clock;
 
 pub struct CallbackTimer {
     timer: Option<(i32, Closure<dyn FnMut()>)>,
@@ -24,6 +28,8 @@ extern "C" {
     fn set_timeout(closure: &Closure<dyn FnMut()>, timeout: i32) -> i32;
     #[wasm_bindgen(js_name = clearTimeout, static_method_of = window)]
     fn clear_timeout(id: i32);
+    #[wasm_bindgen(js_name = setTimeout, static_method_of = window)]
+    fn set_timeout_with_callback(callback: &js_sys::Function, timeout: i32) -> i32;
 
     #[wasm_bindgen(js_name = setInterval, static_method_of = window)]
     fn set_interval(closure: &Closure<dyn FnMut()>, interval: i32) -> i32;
@@ -103,3 +109,19 @@ impl Drop for IntervalInner {
         window::clear_interval(self.id);
     }
 }
+
+/// `Clock` sleeping on the browser event loop
+#[derive(Default, Clone, Copy)]
+pub struct JsClock;
+
+impl Clock for JsClock {
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'_, ()> {
+        let millis = duration.as_millis().min(i32::MAX as u128) as i32;
+        let promise = js_sys::Promise::new(&mut |resolve, _| {
+            window::set_timeout_with_callback(&resolve, millis);
+        });
+        Box::pin(async move {
+            let _ = JsFuture::from(promise).await;
+        })
+    }
+}
@@ -1,24 +1,46 @@
 mod batch;
+mod error;
+mod plan;
+mod queue;
+mod retry;
+mod savepoint;
+mod schedule;
 mod store;
+mod summary;
+mod trace;
 
 mod utils;
 
 pub use batch::*;
+pub use error::*;
+pub use plan::*;
+pub use queue::*;
+pub use retry::*;
+pub use savepoint::*;
+pub use schedule::*;
 pub use store::*;
+pub use summary::*;
+pub use trace::*;
 pub use utils::*;
 
 use futures::{
-    future::{join, try_join_all, LocalBoxFuture},
-    Future,
+    future::{
+        join, pending, select, try_join_all, AbortHandle, AbortRegistration, Abortable, Either,
+        LocalBoxFuture,
+    },
+    Future, FutureExt,
 };
+use serde::Serialize;
 
 use std::{
     cell::Cell,
-    fmt::{Debug, Display},
+    fmt::Debug,
     mem,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
     pin::Pin,
+    rc::Rc,
     sync::{Arc, Mutex, Weak},
+    time::Duration,
 };
 
 thread_local! {
@@ -33,7 +55,7 @@ pub fn use_trx_counter() -> u32 {
     })
 }
 
-pub type TrxErr = String;
+pub type TrxErr = BatchTrxOpErr;
 pub type OpResult = Result<(), TrxErr>;
 
 pub struct Unknown;
@@ -61,6 +83,10 @@ pub struct Open;
 pub struct Transaction<B: Batchable> {
     parent: B,
     handle: TrxHandle<B, Open>,
+    retry: Option<(RetryPolicy, Box<dyn Clock>)>,
+    timeout: Option<(Duration, Box<dyn Clock>)>,
+    /// Offline queue, alongside the idempotency key of the transaction
+    offline: Option<(OfflineQueue, String)>,
 }
 
 // Transaction - this is the thing you hold when you are the "owner" of the transaction
@@ -78,7 +104,8 @@ pub struct TrxHandle<B: Batchable, S = Open> {
 /// A reference to a transaction
 pub struct TrxRef<B: Batchable>(Weak<Inner<B>>);
 
-#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TrxState {
     #[default]
     Pending,
@@ -86,6 +113,8 @@ pub enum TrxState {
     Failed,
     Committing,
     Committed,
+    /// The commit failed, and the transaction waits in the offline queue to be replayed
+    Queued,
     //Applied,
 }
 
@@ -116,10 +145,62 @@ impl<B: Batchable> Clone for TrxRef<B> {
 
 impl<B: Batchable> Transaction<B> {
     pub fn new(parent: B, name: &str) -> Self {
+        let sink = parent.trace_sink();
+        Self::with_trace_sink(parent, name, sink)
+    }
+
+    /// Create a transaction reporting its audit events to `sink`, instead of the
+    /// default sink of `parent`
+    pub fn with_trace_sink(parent: B, name: &str, sink: Rc<dyn TraceSink>) -> Self {
         Self {
-            handle: TrxHandle::new(Inner::new(name)),
+            handle: TrxHandle::new(Inner::new(name, sink, Default::default())),
             parent,
+            retry: None,
+            timeout: None,
+            offline: None,
+        }
+    }
+
+    /// Transaction committing `ops` again, after they were queued as `queued`. It runs the
+    /// terminal hooks the queued transaction left with `queue`, if it was queued by this process.
+    fn replayed(parent: B, queued: &QueuedTrx, ops: Vec<B::Op>, queue: OfflineQueue) -> Self {
+        let mut transaction = Self::with_ops(parent, &queued.name, ops);
+        if let Some(hooks) = queue.take_hooks(&queued.key) {
+            transaction.handle.inner.set_terminal_hooks(hooks);
         }
+        transaction.offline = Some((queue, queued.key.clone()));
+        transaction
+    }
+
+    /// Transaction committing `ops` as they are
+    fn with_ops(parent: B, name: &str, ops: Vec<B::Op>) -> Self {
+        let transaction = Self::new(parent, name);
+        let inner = &transaction.handle.inner;
+        let mut inner_ops = inner.ops.try_lock().unwrap();
+        *inner_ops = ops;
+        inner.counter.set(inner_ops.len());
+        drop(inner_ops);
+        transaction
+    }
+
+    /// Retry failed commits according to `policy`, waiting on `clock` between attempts.
+    /// Without a retry policy, the transaction fails on the first commit error.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy, clock: impl Clock + 'static) {
+        self.retry = Some((policy, Box::new(clock)));
+    }
+
+    /// Fail with `TrxApplyErr::TimedOut` if the scheduled ops and pre-commit hooks are still
+    /// running `timeout` after `apply` was called, as measured by `clock`.
+    /// The commit itself is not interrupted, as it may leave some batches committed.
+    pub fn set_timeout(&mut self, timeout: Duration, clock: impl Clock + 'static) {
+        self.timeout = Some((timeout, Box::new(clock)));
+    }
+
+    /// Save the transaction into `queue` before committing it, so that it is replayed
+    /// if its commit fails with a transient error, or does not complete at all
+    pub fn set_offline_queue(&mut self, queue: OfflineQueue) {
+        let key = idempotency_key(self.name());
+        self.offline = Some((queue, key));
     }
 }
 impl<B: Batchable, S> TrxHandle<B, S> {
@@ -135,6 +216,9 @@ impl<B: Batchable, S> TrxHandle<B, S> {
             phantom: Default::default(),
         }
     }
+    pub fn id(&self) -> u32 {
+        self.inner.id
+    }
     pub fn name(&self) -> &str {
         &self.inner.name
     }
@@ -149,6 +233,19 @@ impl<B: Batchable, S> TrxHandle<B, S> {
         TrxRef(Arc::downgrade(&self.inner))
     }
 
+    /// Cancel the transaction. Once applied, it fails with `TrxApplyErr::Cancelled` if it was
+    /// not committing yet, otherwise the failed commit is not retried anymore.
+    /// Return false if the transaction was already committed or failed.
+    pub fn cancel(&self) -> bool {
+        match self.inner.state.get() {
+            TrxState::Pending | TrxState::Preparing | TrxState::Committing => {
+                self.inner.interrupt();
+                true
+            }
+            TrxState::Failed | TrxState::Committed | TrxState::Queued => false,
+        }
+    }
+
     /// convert a TrxHandle to checked TrxHandle<Open>
     pub fn checked(&self) -> Result<TrxHandle<B, Open>, TrxCheckErr> {
         self.inner.check_status()?;
@@ -172,31 +269,82 @@ impl<B: Batchable> Deref for TxhOp<B> {
     }
 }
 impl<B: Batchable> TrxHandle<B, Open> {
-    pub fn add_op<Fun, Fut>(&self, f: Fun)
+    /// Schedule an op, return `None` if the transaction does not accept ops anymore
+    pub fn add_op<Fun, Fut>(&self, f: Fun) -> Option<OpId>
     where
         B: 'static,
         Fut: Future<Output = Result<TxhOp<B>, BatchTrxOpErr>>,
         Fun: 'static,
         Fun: FnOnce(TxhOp<B>) -> Fut,
     {
-        let Ok(txh) = TxhOp::new(&self) else {
-            return;
-        };
+        self.add_op_after(&[], f).ok()
+    }
+    /// Schedule an op which starts once the ops `after` completed
+    pub fn add_op_after<Fun, Fut>(&self, after: &[OpId], f: Fun) -> Result<OpId, BatchTrxOpErr>
+    where
+        B: 'static,
+        Fut: Future<Output = Result<TxhOp<B>, BatchTrxOpErr>>,
+        Fun: 'static,
+        Fun: FnOnce(TxhOp<B>) -> Fut,
+    {
+        let txh = TxhOp::new(&self)?;
 
         let future = async move {
             let _: TxhOp<B> = f(txh).await?;
             Ok(())
         };
-        self.add_future_op(future);
+        self.add_future_op_after(after, future)
+    }
+    pub fn add_future_op<Fut>(&self, future: Fut) -> OpId
+    where
+        Fut: Future<Output = Result<(), BatchTrxOpErr>> + 'static,
+    {
+        self.add_boxed_op(Box::pin(future))
     }
-    pub fn add_future_op<Fut>(&self, future: Fut)
+    /// Schedule a future which starts once the ops `after` completed
+    pub fn add_future_op_after<Fut>(
+        &self,
+        after: &[OpId],
+        future: Fut,
+    ) -> Result<OpId, BatchTrxOpErr>
     where
         Fut: Future<Output = Result<(), BatchTrxOpErr>> + 'static,
     {
-        self.add_boxed_op(Box::pin(future));
+        let mut scheduled = self.inner.scheduled.try_lock().unwrap();
+        Ok(scheduled.add(after, Box::pin(future))?)
+    }
+    pub fn add_boxed_op(&self, future: TrxOppFuture<'static>) -> OpId {
+        self.inner.add_boxed_op(future)
+    }
+    /// Schedule an op which starts once every other scheduled op completed, before the
+    /// pre-commit hooks, unless an op was already deferred with the same `key` by this
+    /// transaction, even one which started already. Return false if `f` was dropped for that
+    /// reason.
+    pub fn defer_unique<Fun, Fut>(
+        &self,
+        key: impl Into<String>,
+        f: Fun,
+    ) -> Result<bool, TrxCheckErr>
+    where
+        B: 'static,
+        Fut: Future<Output = Result<TxhOp<B>, BatchTrxOpErr>>,
+        Fun: 'static,
+        Fun: FnOnce(TxhOp<B>) -> Fut,
+    {
+        let txh = TxhOp::new(self)?;
+
+        let future = async move {
+            let _: TxhOp<B> = f(txh).await?;
+            Ok(())
+        };
+        let mut scheduled = self.inner.scheduled.try_lock().unwrap();
+        Ok(scheduled.defer(key.into(), Box::pin(future)))
     }
-    pub fn add_boxed_op(&self, future: TrxOppFuture<'static>) {
-        self.inner.add_boxed_op(future);
+    /// Make the scheduled op `op` wait until `on` completed.
+    /// Fails if `op` already started, or if `on` is waiting on `op` itself.
+    pub fn add_dependency(&self, op: OpId, on: OpId) -> Result<(), BatchTrxOpErr> {
+        let mut scheduled = self.inner.scheduled.try_lock().unwrap();
+        Ok(scheduled.add_dependency(op, on)?)
     }
 
     pub fn add_on_commit_hook(&self, f: TerminalookFn) {
@@ -207,9 +355,41 @@ impl<B: Batchable> TrxHandle<B, Open> {
         self.inner.on_post_commit_hooks.try_lock().unwrap().push(f);
     }
 
+    /// Pre-commit hooks run once all scheduled ops completed, and are sync: async work,
+    /// like releasing a `Savepoint`, goes in an op they schedule, which runs before the commit.
     pub fn add_pre_commit_hook(&self, f: PreCommitHookFn<B>) {
         self.inner.on_pre_commit_hooks.try_lock().unwrap().push(f)
     }
+
+    /// Abort hooks run once the transaction moves to `TrxState::Failed`, which happens
+    /// when a scheduled op fails, a pre-commit hook fails, or the commit itself fails.
+    /// Use them to roll back any local state that was optimistically updated.
+    pub fn add_abort_hook(&self, f: TerminalookFn) {
+        self.inner.on_abort_hooks.try_lock().unwrap().push(f);
+    }
+
+    /// Also report the audit events of the transaction to `sink`
+    pub fn add_trace_sink(&self, sink: Rc<dyn TraceSink>) {
+        self.inner.observers.try_lock().unwrap().push(sink);
+    }
+
+    /// Report a non-fatal issue, returned in the `TrxSummary` of the transaction
+    pub fn add_warning(&self, warning: impl Into<String>) {
+        let warning = warning.into();
+        log::warn!("Transaction({}): {warning}", self.inner.name);
+        self.inner
+            .summary
+            .try_lock()
+            .unwrap()
+            .warnings
+            .push(warning);
+    }
+
+    /// Retry hooks run every time a failed commit is about to be retried,
+    /// with the number of the failed attempt, the backoff delay and the error.
+    pub fn add_retry_hook(&self, f: RetryHookFn) {
+        self.inner.on_retry_hooks.try_lock().unwrap().push(f);
+    }
     pub fn process_op(&self, op: B::TrxOp) -> Result<(), BatchTrxOpErr> {
         let txh = FullOpenTrxHandle(self.clone_casted());
         B::process_trx_op(txh, op)
@@ -237,13 +417,10 @@ impl<B: Batchable> FullOpenTrxHandle<B> {
         let op_debug = format!("{op:?}");
 
         self.inner.add_op(op)?;
-        B::trace_messages(
-            2,
-            &[
-                &format!("DBAUDIT: Transaction({}).batch_operation", self.inner.name),
-                &op_debug,
-            ],
-        );
+        self.inner.trace(TrxEvent::OpBatched {
+            op: op_debug,
+            count: self.inner.counter.get(),
+        });
         Ok(())
     }
 
@@ -255,8 +432,18 @@ impl<B: Batchable> FullOpenTrxHandle<B> {
 type PreCommitHookFn<B> = Box<dyn FnOnce(&TrxHandle<B>) -> OpResult>;
 type TerminalookFn = Box<dyn FnOnce() -> Option<LocalBoxFuture<'static, ()>>>;
 
+/// Post-commit and abort hooks of a transaction, which did not run yet
+struct TerminalHooks {
+    post_commit: Vec<TerminalookFn>,
+    abort: Vec<TerminalookFn>,
+}
+
+type RetryHookFn = Box<dyn Fn(u32, Duration, &TrxCommitErr)>;
+
 type TrxOppFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BatchTrxOpErr>> + 'a>>;
 struct Inner<B: Batchable> {
+    /// Unique to the transaction, and also suffixed to its name
+    id: u32,
     name: String,
     state: MutexCell<TrxState>,
     ops: Mutex<Vec<B::Op>>,
@@ -264,23 +451,42 @@ struct Inner<B: Batchable> {
     on_pre_commit_hooks: Mutex<Vec<PreCommitHookFn<B>>>,
     on_commit_hooks: Mutex<Vec<TerminalookFn>>,
     on_post_commit_hooks: Mutex<Vec<TerminalookFn>>,
-    scheduled: Mutex<Vec<TrxOppFuture<'static>>>,
-    extras: B::Extras,
+    on_abort_hooks: Mutex<Vec<TerminalookFn>>,
+    on_retry_hooks: Mutex<Vec<RetryHookFn>>,
+    scheduled: Mutex<Schedule>,
+    /// Shared with the savepoints of the transaction
+    extras: Rc<B::Extras>,
+    sink: Rc<dyn TraceSink>,
+    /// Sinks added with `TrxHandle::add_trace_sink`
+    observers: Mutex<Vec<Rc<dyn TraceSink>>>,
+    /// Interrupts the preparation of the transaction, see `TrxHandle::cancel`
+    cancel: AbortHandle,
+    cancel_registration: Mutex<Option<AbortRegistration>>,
+    /// Savepoints opened on the transaction, interrupted along with it
+    savepoints: Mutex<Vec<Weak<Inner<B>>>>,
+    /// Set once preparing the transaction ran past its timeout
+    timed_out: MutexCell<Option<Duration>>,
+    /// Filled as the transaction is applied, and returned by `apply`
+    summary: Mutex<TrxSummary>,
 }
 
 impl<B: Batchable> Transaction<B> {
-    pub async fn apply(self) -> Result<(), TrxApplyErr> {
+    pub async fn apply(self) -> Result<TrxSummary, TrxApplyErr> {
+        self.apply_with(|_| false).await
+    }
+
+    /// Apply the transaction, leaving the abort hooks to the transaction retrying it when
+    /// the commit fails with an error for which `retried` holds
+    async fn apply_with(
+        self,
+        retried: impl FnOnce(&TrxApplyErr) -> bool,
+    ) -> Result<TrxSummary, TrxApplyErr> {
         let inner = &self.handle.inner;
 
-        let count = inner.counter.get();
-        B::trace_messages(
-            2,
-            &[
-                &inner.name,
-                &format!("DBAUDIT: .apply() {} statements", count),
-            ],
-        );
-        B::trace_messages(4, &[&inner.name, &"DBAUDIT: apply (start)"]);
+        let started = inner.sink.now();
+        inner.trace(TrxEvent::ApplyStart {
+            ops: inner.counter.get(),
+        });
 
         // Given than in rust, apply drops transaction, it won't be possible to
         // apply twice, then, this condition could be unnecessary
@@ -289,43 +495,21 @@ impl<B: Batchable> Transaction<B> {
         //}
         inner.state.set(TrxState::Preparing);
 
-        loop {
-            if let Some(op_batch) = inner.take_ops() {
-                B::trace_messages(
-                    4,
-                    &[
-                        &inner.name,
-                        &format!("DBAUDIT: in ops ({})", op_batch.len()),
-                    ],
-                );
-                try_join_all(op_batch).await?;
-                continue;
-            }
-
-            if inner.dispatch_pre_commit_hooks()? {
-                continue;
-            }
-
-            break;
+        if let Err(err) = self.prepare_until_interrupted().await {
+            inner.abort(err.code()).await;
+            return Err(err);
         }
+        let prepared = inner.sink.now();
 
         inner.state.set(TrxState::Committing);
-        B::trace_messages(4, &[&inner.name, &"apply (committing"]);
 
-        let commit_future = self.commit();
+        let commit_future = self.commit_with_retry();
 
         let on_commit_hooks_futures = inner.dispatch_on_commit_hooks();
 
         let result = match commit_future.await {
             Ok(_) => {
-                B::trace_messages(4, &[&inner.name, &"apply (committed)"]);
-                B::trace_messages(
-                    2,
-                    &[
-                        &inner.name,
-                        &format!("DBAUDIT: .commit() {} statements", inner.counter.get()),
-                    ],
-                );
+                let committed = inner.sink.now();
                 inner.state.set(TrxState::Committed);
                 let post_commit_hooks_futures = inner.dispatch_post_commit_hooks();
 
@@ -336,100 +520,441 @@ impl<B: Batchable> Transaction<B> {
                 )
                 .await;
 
-                B::trace_messages(
-                    4,
-                    &[&inner.name, &".apply() (commit ops completed succesfully)"],
-                );
-                Ok(())
+                inner.trace(TrxEvent::Applied {
+                    ops: inner.counter.get(),
+                    duration: inner.sink.now().saturating_sub(started),
+                });
+                let mut summary = mem::take(inner.summary.try_lock().unwrap().deref_mut());
+                summary.name = inner.name.clone();
+                summary.ops_duration = prepared.saturating_sub(started);
+                summary.commit_duration = committed.saturating_sub(prepared);
+                Ok(summary)
+            }
+            Err(TrxApplyErr::Queued { key, source }) => {
+                log::warn!("Transaction({}) queued for replay: {source}", inner.name);
+                inner.state.set(TrxState::Queued);
+                inner.trace(TrxEvent::Queued { key: key.clone() });
+                // neither committed nor failed yet, the replay runs the terminal hooks
+                if let Some((queue, _)) = &self.offline {
+                    queue.keep_hooks(&key, inner.take_terminal_hooks());
+                }
+                Err(TrxApplyErr::Queued { key, source })
+            }
+            Err(err) if retried(&err) => {
+                log::error!("An error occurred committing this transaction: {err}");
+                inner.fail(err.code());
+                Err(err)
             }
             Err(err) => {
                 log::error!("An error occurred committing this transaction: {err}");
-                inner.state.set(TrxState::Failed);
+                inner.abort(err.code()).await;
                 // console.debug({
                 //     //entities: this.entities,
                 //     //pendingEntities: this.pendingEntities,
                 //     trx: this,
                 //     batch: this.batch,
                 //   });
-                B::trace_messages(
-                    4,
-                    &[&inner.name, &"apply (commit ops completed with error)"],
-                );
-                Err(err.into())
+                Err(err)
             }
         };
 
         result
     }
 
-    async fn commit(&self) -> Result<(), TrxCommitErr> {
+    /// Apply the transaction like `apply` does. When its commit fails with a transient error,
+    /// also return a new transaction committing the same ops again, so the commit can be
+    /// retried later on. The abort hooks do not run then: the retry runs the post-commit or
+    /// abort hooks of the transaction once it is applied, and none of them if it is dropped.
+    pub async fn apply_retryable(self) -> Result<TrxSummary, (TrxApplyErr, Option<Transaction<B>>)>
+    where
+        B: Clone,
+    {
+        let parent = self.parent.clone();
+        let inner = self.handle.inner.clone();
+        let retryable =
+            |err: &TrxApplyErr| matches!(err, TrxApplyErr::Commit(_)) && err.code().is_transient();
+        match self.apply_with(retryable).await {
+            Ok(summary) => Ok(summary),
+            Err(err) if retryable(&err) => {
+                let ops = mem::take(inner.ops.try_lock().unwrap().deref_mut());
+                let retry = Self::with_ops(parent, &inner.name, ops);
+                let hooks = inner.take_terminal_hooks();
+                retry.handle.inner.set_terminal_hooks(hooks);
+                Err((err, Some(retry)))
+            }
+            Err(err) => Err((err, None)),
+        }
+    }
+
+    /// Run the scheduled ops and pre-commit hooks like `apply` does, then return what `apply`
+    /// would commit instead of committing it. As nothing is written, the transaction is
+    /// aborted afterwards, running its abort hooks.
+    pub async fn dry_run(self) -> Result<TrxPlan, TrxApplyErr> {
+        let inner = &self.handle.inner;
+        inner.trace(TrxEvent::ApplyStart {
+            ops: inner.counter.get(),
+        });
+        inner.state.set(TrxState::Preparing);
+
+        let plan = match self.prepare_until_interrupted().await {
+            Ok(_) => self.plan(),
+            Err(err) => Err(err),
+        };
+        if let Ok(plan) = &plan {
+            inner.trace(TrxEvent::Planned {
+                ops: plan.ops.len(),
+                batches: plan.batches.len(),
+            });
+        }
+        let code = match &plan {
+            Ok(_) => TrxErrCode::Cancelled,
+            Err(err) => err.code(),
+        };
+        inner.abort(code).await;
+        plan
+    }
+
+    /// The ops as `commit_with_retry` would commit them
+    fn plan(&self) -> Result<TrxPlan, TrxApplyErr> {
+        let inner = &*self.handle.inner;
+        let Ok(ops) = inner.ops.try_lock() else {
+            return Err(TrxCommitErr::from(format!(
+                "Transaction({}) can not be planned",
+                inner.name
+            )))?;
+        };
+        let ops = coalesce_ops::<B>(ops.clone());
+        let batches = split_batches::<B>(&ops)?;
+        Ok(TrxPlan {
+            ops: ops.iter().map(B::explain_op).collect(),
+            batches,
+        })
+    }
+
+    /// Prepare the transaction, unless it is cancelled or times out first
+    async fn prepare_until_interrupted(&self) -> Result<(), TrxApplyErr> {
+        let inner = &self.handle.inner;
+        let Some(registration) = inner.cancel_registration.try_lock().unwrap().take() else {
+            return Err(TrxCheckErr::ClosedForAddingOps)?;
+        };
+        let prepare = Abortable::new(inner.prepare(), registration).boxed_local();
+        let deadline = match &self.timeout {
+            Some((timeout, clock)) => clock.sleep(*timeout),
+            None => pending().boxed_local(),
+        };
+
+        match select(prepare, deadline).await {
+            // an op may fail because the transaction was cancelled under its feet
+            Either::Left((Ok(_), _)) if inner.cancel.is_aborted() => Err(TrxApplyErr::Cancelled),
+            Either::Left((Ok(result), _)) => result,
+            Either::Left((Err(_aborted), _)) => Err(TrxApplyErr::Cancelled),
+            Either::Right(_) => {
+                let timeout = self.timeout.as_ref().map(|(timeout, _)| *timeout);
+                let timeout = timeout.unwrap_or_default();
+                inner.timed_out.set(Some(timeout));
+                inner.interrupt_savepoints();
+                Err(TrxApplyErr::TimedOut(timeout))
+            }
+        }
+    }
+
+    /// Commit, retrying according to the retry policy until it succeeds or gives up.
+    /// With an offline queue, the transaction is queued first, and stays queued if it
+    /// gave up on a transient error.
+    async fn commit_with_retry(&self) -> Result<(), TrxApplyErr> {
         let inner = &*self.handle.inner;
         let Ok(mut ops) = inner.ops.try_lock() else {
-            return Err(format!("Transaction({}) can not be commited", inner.name))?;
+            return Err(TrxCommitErr::from(format!(
+                "Transaction({}) can not be commited",
+                inner.name
+            )))?;
+        };
+        // a single write per document, which also keeps the child batches independent
+        let coalesced = coalesce_ops::<B>(mem::take(ops.deref_mut()));
+        *ops = coalesced;
+
+        // the applied marker is not an op of the transaction
+        let trx_ops = ops.len();
+        let queue = match &self.offline {
+            Some((queue, key)) if !ops.is_empty() => {
+                let queued = queue.enqueue::<B>(key, &inner.name, &ops).await;
+                if let (true, Some(marker)) = (queued, B::applied_marker(key)) {
+                    ops.push(marker);
+                }
+                queued.then_some((queue, key))
+            }
+            _ => None,
+        };
+
+        let result = self.commit_attempts(&ops).await;
+        if result.is_ok() {
+            inner.summary.try_lock().unwrap().ops = trx_ops;
+        }
+        let Some((queue, key)) = queue else {
+            return Ok(result?);
         };
+        match result {
+            Err(err) if err.code().is_transient() => {
+                queue.strand(key);
+                Err(TrxApplyErr::Queued {
+                    key: key.clone(),
+                    source: err,
+                })
+            }
+            result => {
+                queue.dequeue(&self.parent, key, result.is_ok()).await;
+                Ok(result?)
+            }
+        }
+    }
+
+    async fn commit_attempts(&self, ops: &[B::Op]) -> Result<(), TrxCommitErr> {
+        let inner = &*self.handle.inner;
+        // oversized ops are rejected before anything is sent
+        let batches = split_batches::<B>(ops)?;
+
+        let mut attempt = 1;
+        loop {
+            let err = match self.commit(ops, &batches).await {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+            let Some((policy, clock)) = &self.retry else {
+                return Err(err);
+            };
+            if inner.cancel.is_aborted() {
+                return Err(err);
+            }
+            let Some(delay) = policy.delay(attempt, &err) else {
+                return Err(err);
+            };
+
+            inner.trace(TrxEvent::Retrying { attempt, delay });
+            inner.dispatch_retry_hooks(attempt, delay, &err);
+            clock.sleep(delay).await;
+            attempt += 1;
+        }
+    }
 
+    async fn commit(&self, ops: &[B::Op], batches: &[Range<usize>]) -> Result<(), TrxCommitErr> {
         if ops.is_empty() {
             return Ok(());
         }
 
-        let mut batches: Vec<B::Child> = Vec::new();
+        let inner = &*self.handle.inner;
+        inner.trace(TrxEvent::CommitStart {
+            ops: ops.len(),
+            batches: batches.len(),
+        });
+        let started = inner.sink.now();
+        let result = self.commit_batches(ops, batches).await;
+        inner.trace(TrxEvent::CommitEnd {
+            ops: ops.len(),
+            batches: batches.len(),
+            duration: inner.sink.now().saturating_sub(started),
+            error: result.as_ref().err().map(TrxCommitErr::code),
+        });
+        if result.is_ok() {
+            inner.summary.try_lock().unwrap().batches = batches.len();
+        }
+        result
+    }
 
-        while !ops.is_empty() {
-            let n = ops.len().min(B::LIMIT);
+    /// Commit the `ranges` of `ops` as child batches, reverting the committed ones on error
+    async fn commit_batches(
+        &self,
+        ops: &[B::Op],
+        ranges: &[Range<usize>],
+    ) -> Result<(), TrxCommitErr> {
+        // committed child batches, alongside the paths of their documents
+        let mut batches: Vec<(B::Child, Vec<String>)> = Vec::new();
+
+        for range in ranges {
+            // index, within the transaction, of the first op of the child batch
+            let offset = range.start;
             let batch = self.parent.child()?;
-            for op in ops.drain(..n) {
-                B::insert(&batch, op);
+            let mut paths = Vec::with_capacity(range.len());
+            for op in &ops[range.clone()] {
+                paths.push(B::op_path(op));
+                B::insert(&batch, op.clone());
             }
-            match B::commit(&batch).await {
-                Ok(_) => batches.push(batch),
+
+            // Only batches followed by others may have to be reverted
+            let result = if range.end == ops.len() {
+                B::commit(&batch).await
+            } else {
+                match B::capture(&batch).await {
+                    Ok(_) => B::commit(&batch).await,
+                    Err(e) => Err(e),
+                }
+            };
+
+            match result {
+                Ok(_) => batches.push((batch, paths)),
                 Err(e) => {
-                    while let Some(_batch) = batches.pop() {
-                        // TODO: implement revert
-                        //batch.revert();
-                    }
-                    return Err(e);
+                    let e = e.locate(offset, &paths);
+                    return Err(self.revert(batches, e).await);
                 }
             }
         }
 
         Ok(())
     }
+
+    /// Revert the committed child batches, last one first, after `err` occurred
+    async fn revert(
+        &self,
+        mut batches: Vec<(B::Child, Vec<String>)>,
+        err: TrxCommitErr,
+    ) -> TrxCommitErr {
+        let inner = &*self.handle.inner;
+        if batches.is_empty() {
+            return err;
+        }
+
+        inner.trace(TrxEvent::Reverting {
+            batches: batches.len(),
+        });
+
+        let mut unrestored = Vec::new();
+        while let Some((batch, paths)) = batches.pop() {
+            match B::revert(&batch).await {
+                Ok(_) => {}
+                Err(TrxRevertErr::Unsupported) => unrestored.extend(paths),
+                Err(TrxRevertErr::Unrestored(paths)) => unrestored.extend(paths),
+            }
+        }
+
+        if unrestored.is_empty() {
+            return err;
+        }
+        log::error!(
+            "Transaction({}) left {} documents partially committed",
+            inner.name,
+            unrestored.len()
+        );
+        TrxCommitErr::Unreverted {
+            source: Box::new(err),
+            unrestored,
+        }
+    }
 }
 
 impl<B: Batchable> Inner<B> {
-    fn new(name: &str) -> Arc<Inner<B>> {
+    fn new(name: &str, sink: Rc<dyn TraceSink>, extras: Rc<B::Extras>) -> Arc<Inner<B>> {
         let trx_counter = use_trx_counter();
+        let (cancel, cancel_registration) = AbortHandle::new_pair();
 
         let inner = Arc::new(Inner {
+            id: trx_counter,
             name: format!("{name}-{trx_counter}"),
             state: Default::default(),
             counter: MutexCell::new(0),
             ops: Default::default(),
             on_commit_hooks: Default::default(),
             on_post_commit_hooks: Default::default(),
+            on_abort_hooks: Default::default(),
+            on_retry_hooks: Default::default(),
             on_pre_commit_hooks: Default::default(),
             scheduled: Default::default(),
-            extras: Default::default(),
+            extras,
+            sink,
+            observers: Default::default(),
+            cancel,
+            cancel_registration: Mutex::new(Some(cancel_registration)),
+            savepoints: Default::default(),
+            timed_out: MutexCell::new(None),
+            summary: Default::default(),
         });
-        B::trace_messages(2, &[&inner.name, &"CREATED"]);
+        inner.trace(TrxEvent::Created);
         inner
     }
 
+    fn trace(&self, event: TrxEvent) {
+        if self.sink.enabled(event.level()) {
+            self.sink.record(self.id, &self.name, &event);
+        }
+        // observers may add ops, tracing more events
+        let observers = self.observers.try_lock().unwrap().clone();
+        for observer in observers {
+            if observer.enabled(event.level()) {
+                observer.record(self.id, &self.name, &event);
+            }
+        }
+    }
+
+    /// Interrupt the preparation of the transaction and of its savepoints
+    fn interrupt(&self) {
+        self.cancel.abort();
+        self.interrupt_savepoints();
+    }
+
+    fn interrupt_savepoints(&self) {
+        let savepoints = self.savepoints.try_lock().unwrap().clone();
+        for savepoint in savepoints.iter().filter_map(Weak::upgrade) {
+            savepoint.interrupt();
+        }
+    }
+
     fn is_pending_or_preparing(&self) -> bool {
         let state = self.state.get();
         state == TrxState::Pending || state == TrxState::Preparing
     }
 
-    fn take_ops(&self) -> Option<Vec<TrxOppFuture>> {
-        let mut scheduled = self.scheduled.try_lock().unwrap();
-        if scheduled.is_empty() {
-            None
-        } else {
-            let ops = mem::take(scheduled.deref_mut());
-            Some(ops)
+    /// Run the scheduled ops, deferred ops and pre-commit hooks until all are exhausted
+    async fn prepare(self: &Arc<Inner<B>>) -> Result<(), TrxApplyErr> {
+        loop {
+            if let Some(op_batch) = self.take_ops()? {
+                self.trace(TrxEvent::OpsDispatched {
+                    count: op_batch.len(),
+                });
+                try_join_all(op_batch).await?;
+                continue;
+            }
+
+            if let Some(op_batch) = self.take_deferred_ops() {
+                self.trace(TrxEvent::OpsDispatched {
+                    count: op_batch.len(),
+                });
+                try_join_all(op_batch).await?;
+                continue;
+            }
+
+            if self.dispatch_pre_commit_hooks()? {
+                continue;
+            }
+
+            return Ok(());
         }
     }
 
+    /// Mark the transaction as failed and run its abort hooks.
+    /// Whatever was still scheduled is discarded, as it will never be committed.
+    async fn abort(&self, code: TrxErrCode) {
+        self.fail(code);
+        let abort_hooks_futures = self.dispatch_abort_hooks();
+        process_all(abort_hooks_futures).await;
+    }
+
+    /// Mark the transaction as failed, without running its abort hooks
+    fn fail(&self, code: TrxErrCode) {
+        self.state.set(TrxState::Failed);
+        self.scheduled.try_lock().unwrap().clear();
+        self.on_pre_commit_hooks.try_lock().unwrap().clear();
+        self.trace(TrxEvent::Aborted { code });
+    }
+
+    /// Take the scheduled ops whose dependencies completed
+    fn take_ops(&self) -> Result<Option<Vec<TrxOppFuture>>, BatchTrxOpErr> {
+        let mut scheduled = self.scheduled.try_lock().unwrap();
+        Ok(scheduled.take_ready()?)
+    }
+
+    /// Take the deferred ops, once no other op is scheduled
+    fn take_deferred_ops(&self) -> Option<Vec<TrxOppFuture>> {
+        self.scheduled.try_lock().unwrap().take_deferred()
+    }
+
     /// return true if a precommit hook was dispatched
     fn dispatch_pre_commit_hooks(self: &Arc<Inner<B>>) -> Result<bool, TrxApplyErr> {
         let mut guard = self.on_pre_commit_hooks.try_lock().unwrap();
@@ -438,13 +963,11 @@ impl<B: Batchable> Inner<B> {
         }
         let callbacks = mem::take(guard.deref_mut());
         drop(guard);
+        self.trace_hooks(HookKind::PreCommit, callbacks.len());
 
         let trx = TrxHandle::new(self.clone());
         for cb in callbacks {
-            match cb(&trx) {
-                Ok(_) => {}
-                Err(e) => return Err(TrxApplyErr::Other(e)),
-            }
+            cb(&trx)?;
         }
         Ok(true)
     }
@@ -453,6 +976,7 @@ impl<B: Batchable> Inner<B> {
         let mut guard = self.on_commit_hooks.try_lock().unwrap();
         let callbacks = mem::take(guard.deref_mut());
         drop(guard);
+        self.trace_hooks(HookKind::OnCommit, callbacks.len());
         callbacks.into_iter().filter_map(|f| f()).collect()
     }
 
@@ -460,13 +984,48 @@ impl<B: Batchable> Inner<B> {
         let mut guard = self.on_post_commit_hooks.try_lock().unwrap();
         let callbacks = mem::take(guard.deref_mut());
         drop(guard);
+        self.trace_hooks(HookKind::PostCommit, callbacks.len());
+        callbacks.into_iter().filter_map(|f| f()).collect()
+    }
+
+    fn dispatch_abort_hooks(&self) -> Vec<LocalBoxFuture<'static, ()>> {
+        let mut guard = self.on_abort_hooks.try_lock().unwrap();
+        let callbacks = mem::take(guard.deref_mut());
+        drop(guard);
+        self.trace_hooks(HookKind::Abort, callbacks.len());
         callbacks.into_iter().filter_map(|f| f()).collect()
     }
+
+    fn set_terminal_hooks(&self, hooks: TerminalHooks) {
+        *self.on_post_commit_hooks.try_lock().unwrap() = hooks.post_commit;
+        *self.on_abort_hooks.try_lock().unwrap() = hooks.abort;
+    }
+
+    fn take_terminal_hooks(&self) -> TerminalHooks {
+        TerminalHooks {
+            post_commit: mem::take(self.on_post_commit_hooks.try_lock().unwrap().deref_mut()),
+            abort: mem::take(self.on_abort_hooks.try_lock().unwrap().deref_mut()),
+        }
+    }
+
+    fn trace_hooks(&self, kind: HookKind, count: usize) {
+        if count > 0 {
+            self.summary.try_lock().unwrap().hooks.add(kind, count);
+            self.trace(TrxEvent::HooksDispatched { kind, count });
+        }
+    }
+
+    fn dispatch_retry_hooks(&self, attempt: u32, delay: Duration, err: &TrxCommitErr) {
+        let guard = self.on_retry_hooks.try_lock().unwrap();
+        for cb in guard.iter() {
+            cb(attempt, delay, err);
+        }
+    }
 }
 
 impl<B: Batchable> Inner<B> {
     fn check_status(&self) -> Result<(), TrxCheckErr> {
-        if !self.is_pending_or_preparing() {
+        if !self.is_pending_or_preparing() || self.cancel.is_aborted() {
             Err(TrxCheckErr::ClosedForAddingOps)
         } else {
             Ok(())
@@ -474,6 +1033,9 @@ impl<B: Batchable> Inner<B> {
     }
 
     fn add_op(&self, op: B::Op) -> Result<(), BatchTrxOpErr> {
+        if self.cancel.is_aborted() {
+            return Err(BatchTrxOpErr::ClosedForAddingOps);
+        }
         match self.ops.try_lock() {
             Ok(mut ops) => {
                 ops.push(op);
@@ -486,8 +1048,10 @@ impl<B: Batchable> Inner<B> {
 }
 
 impl<B: Batchable> Inner<B> {
-    fn add_boxed_op(&self, future: TrxOppFuture<'static>) {
-        self.scheduled.try_lock().unwrap().push(future);
+    fn add_boxed_op(&self, future: TrxOppFuture<'static>) -> OpId {
+        let mut scheduled = self.scheduled.try_lock().unwrap();
+        // without dependencies, scheduling can not fail
+        scheduled.add(&[], future).unwrap()
     }
 }
 
@@ -511,6 +1075,13 @@ impl<B: Batchable> TrxRef<B> {
         let txh = self.upgrade_checked()?;
         Ok(f(&txh))
     }
+    /// See `TrxHandle::cancel`, return false if the transaction was dropped
+    pub fn cancel(&self) -> bool {
+        match self.upgrade() {
+            Ok(txh) => txh.cancel(),
+            Err(_) => false,
+        }
+    }
     pub fn run_checked(&self, f: impl FnOnce(&TrxHandle<B, Open>)) {
         if let Ok(txr) = self.upgrade_checked() {
             f(&txr)
@@ -524,58 +1095,20 @@ impl<B: Batchable> TrxRef<B> {
         let txh = self.upgrade_checked()?;
         f(&txh)
     }
-}
-
-pub enum TrxApplyErr {
-    AccessDenied(String),
-    Other(String),
-    FailedCheck(TrxCheckErr),
-}
-
-#[derive(Debug)]
-pub enum TrxCheckErr {
-    ClosedForAddingOps,
-    Dropped,
-}
-impl Display for TrxCheckErr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TrxCheckErr::ClosedForAddingOps => write!(
-                f,
-                "you can only add operations to a transaction that is pending or preparing"
-            ),
-            TrxCheckErr::Dropped => write!(f, "Transaction was dropped"),
-        }
-    }
-}
-impl From<TrxCheckErr> for String {
-    fn from(value: TrxCheckErr) -> String {
-        value.to_string()
-    }
-}
-
-impl From<TrxCommitErr> for TrxApplyErr {
-    fn from(value: TrxCommitErr) -> Self {
-        match value {
-            TrxCommitErr::Msg(msg) => TrxApplyErr::Other(msg),
-        }
-    }
-}
-impl From<BatchTrxOpErr> for TrxApplyErr {
-    fn from(value: BatchTrxOpErr) -> Self {
-        match value {
-            BatchTrxOpErr::AccessDenied(msg) => TrxApplyErr::AccessDenied(msg),
-            BatchTrxOpErr::Other(e) => TrxApplyErr::Other(e),
-            BatchTrxOpErr::ClosedForAddingOps => {
-                TrxApplyErr::Other(format!("Operation can not be added"))
-            }
-        }
-    }
-}
 
-impl From<TrxCheckErr> for BatchTrxOpErr {
-    fn from(value: TrxCheckErr) -> Self {
-        BatchTrxOpErr::Other(value.to_string())
+    /// See `TrxHandle::defer_unique`
+    pub fn defer_unique<Fun, Fut>(
+        &self,
+        key: impl Into<String>,
+        f: Fun,
+    ) -> Result<bool, TrxCheckErr>
+    where
+        B: 'static,
+        Fut: Future<Output = Result<TxhOp<B>, BatchTrxOpErr>>,
+        Fun: 'static,
+        Fun: FnOnce(TxhOp<B>) -> Fut,
+    {
+        self.upgrade_checked()?.defer_unique(key, f)
     }
 }
 
@@ -583,20 +1116,36 @@ impl From<TrxCheckErr> for BatchTrxOpErr {
 mod test {
     use std::{
         borrow::Borrow,
+        cell::{Cell, RefCell},
         collections::{HashMap, HashSet},
+        rc::Rc,
         sync::Arc,
+        time::Duration,
     };
 
     use futures::lock::Mutex;
 
-    use super::{BatchTrxOpErr, FullOpenTrxHandle, Transaction, TrxCommitErr, TrxHandle};
+    use super::{
+        BatchTrxOpErr, Claim, Clock, Coalesced, FullOpenTrxHandle, HookKind, MemoryQueue,
+        MemorySink, OfflineQueue, PlannedOp, Precondition, QueueStorage, QueuedTrx, RetryPolicy,
+        ScheduleErr, TraceSink, Transaction, TrxApplyErr, TrxCommitErr, TrxDbErr, TrxErrCode,
+        TrxEvent, TrxHandle, TrxHooksRun, TrxRevertErr, TrxState,
+    };
 
     #[derive(Default, Clone)]
-    struct DummyDB(Arc<Mutex<HashMap<String, i32>>>);
+    struct DummyDB {
+        records: Arc<Mutex<HashMap<String, i32>>>,
+        /// Makes every revert fail, for testing unrestorable documents
+        unrestorable: Rc<Cell<bool>>,
+        /// Number of upcoming commits failing as unavailable
+        unavailable: Rc<Cell<u32>>,
+        /// Records the audit events of the transactions
+        sink: Rc<MemorySink>,
+    }
 
     impl DummyDB {
         pub async fn read(&self, key: impl Borrow<str>) -> Option<i32> {
-            let db = self.0.lock().await;
+            let db = self.records.lock().await;
             db.get(key.borrow()).cloned()
         }
         pub fn trx(&self, name: &str) -> Transaction<DummyDB> {
@@ -607,9 +1156,10 @@ mod test {
     struct Batch {
         db: DummyDB,
         ops: Mutex<Vec<Op>>,
+        before: Mutex<HashMap<String, Option<i32>>>,
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     enum Op {
         Insert(DocumentRef, i32),
         Update(DocumentRef, i32),
@@ -622,10 +1172,61 @@ mod test {
         type TrxOp = Op;
         type Extras = ();
 
+        const LIMIT: usize = 2;
+        const BYTE_LIMIT: usize = 32;
+
         fn child(&self) -> Result<Batch, String> {
             Ok(Batch {
                 db: self.clone(),
                 ops: Mutex::default(),
+                before: Mutex::default(),
+            })
+        }
+        fn op_path(op: &Op) -> String {
+            match op {
+                Op::Insert(doc, _) | Op::Update(doc, _) | Op::Delete(doc) => doc.path_ref.clone(),
+            }
+        }
+        fn op_size(op: &Op) -> usize {
+            Self::op_path(op).len() + 4
+        }
+        fn coalesce(prev: &mut Op, next: Op) -> Coalesced<Op> {
+            match (&*prev, next) {
+                (Op::Insert(doc, _), Op::Update(_, val)) => *prev = Op::Insert(doc.clone(), val),
+                (Op::Insert(..), Op::Delete(_)) => return Coalesced::Cancelled,
+                (Op::Update(..), next @ (Op::Update(..) | Op::Delete(_))) => *prev = next,
+                (Op::Delete(doc), Op::Insert(_, val)) => *prev = Op::Update(doc.clone(), val),
+                (_, next) => return Coalesced::Separate(next),
+            }
+            Coalesced::Merged
+        }
+        fn capture(child: &Batch) -> futures::future::LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+            Box::pin(async {
+                let db = child.db.records.lock().await;
+                let mut before = child.before.try_lock().unwrap();
+                for op in child.ops.try_lock().unwrap().iter() {
+                    let key = Self::op_path(op);
+                    let val = db.get(&key).cloned();
+                    before.insert(key, val);
+                }
+                Ok(())
+            })
+        }
+        fn revert(child: &Batch) -> futures::future::LocalBoxFuture<'_, Result<(), TrxRevertErr>> {
+            Box::pin(async {
+                let before = child.before.try_lock().unwrap();
+                if child.db.unrestorable.get() {
+                    let keys = before.keys().cloned().collect();
+                    return Err(TrxRevertErr::Unrestored(keys));
+                }
+                let mut db = child.db.records.lock().await;
+                for (key, val) in before.iter() {
+                    match val {
+                        Some(val) => db.insert(key.clone(), *val),
+                        None => db.remove(key),
+                    };
+                }
+                Ok(())
             })
         }
         fn insert(child: &Batch, op: Self::Op) {
@@ -638,37 +1239,44 @@ mod test {
         }
         fn commit(child: &Batch) -> futures::future::LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
             let fut = async {
+                let unavailable = child.db.unavailable.get();
+                if unavailable > 0 {
+                    child.db.unavailable.set(unavailable - 1);
+                    let err = TrxDbErr::new(TrxErrCode::Unavailable, "DummyDB is unavailable");
+                    return Err(err.into());
+                }
+
                 // checks if all the record references are valid
-                let mut db = child.db.0.lock().await;
+                let mut db = child.db.records.lock().await;
                 let mut keys = HashSet::new();
 
                 let ops = child.ops.try_lock().unwrap();
-                for op in ops.iter() {
+                for (i, op) in ops.iter().enumerate() {
                     let key = match &op {
                         Op::Insert(doc, _) => {
                             let key = doc.path_ref.as_str();
                             if db.get(key).is_some() {
-                                return Err(TrxCommitErr::Msg(format!(
-                                    "INSERT: Record({key}) already exists"
-                                )));
+                                let msg = format!("INSERT: Record({key}) already exists");
+                                let err = TrxDbErr::new(TrxErrCode::AlreadyExists, msg);
+                                return Err(err.with_op_index(i).into());
                             }
                             key
                         }
                         Op::Update(doc, _) => {
                             let key = doc.path_ref.as_str();
                             if db.get(key).is_none() {
-                                return Err(TrxCommitErr::Msg(format!(
-                                    "UPDATE: Record({key}) does not exists"
-                                )));
+                                let msg = format!("UPDATE: Record({key}) does not exists");
+                                let err = TrxDbErr::new(TrxErrCode::NotFound, msg);
+                                return Err(err.with_op_index(i).into());
                             }
                             key
                         }
                         Op::Delete(doc) => {
                             let key = doc.path_ref.as_str();
                             if db.get(key).is_none() {
-                                return Err(TrxCommitErr::Msg(format!(
-                                    "DELETE: Record({key}) does not exists"
-                                )));
+                                let msg = format!("DELETE: Record({key}) does not exists");
+                                let err = TrxDbErr::new(TrxErrCode::NotFound, msg);
+                                return Err(err.with_op_index(i).into());
                             }
                             key
                         }
@@ -703,14 +1311,59 @@ mod test {
             Box::pin(fut)
         }
 
-        fn trace_messages(_level: u8, messages: &[&dyn std::fmt::Display]) {
-            for m in messages {
-                log::info!("{m}")
-            }
+        fn trace_sink(&self) -> Rc<dyn TraceSink> {
+            self.sink.clone()
         }
-    }
 
-    #[derive(Debug)]
+        fn encode_op(op: &Op) -> Option<String> {
+            Some(match op {
+                Op::Insert(doc, val) => format!("insert {val} {}", doc.path_ref),
+                Op::Update(doc, val) => format!("update {val} {}", doc.path_ref),
+                Op::Delete(doc) => format!("delete 0 {}", doc.path_ref),
+            })
+        }
+        fn decode_op(encoded: &str) -> Option<Op> {
+            let mut parts = encoded.splitn(3, ' ');
+            let (kind, val, path) = (parts.next()?, parts.next()?, parts.next()?);
+            let (doc, val) = (DocumentRef::new(path), val.parse().ok()?);
+            match kind {
+                "insert" => Some(Op::Insert(doc, val)),
+                "update" => Some(Op::Update(doc, val)),
+                "delete" => Some(Op::Delete(doc)),
+                _ => None,
+            }
+        }
+        /// Keyed by the random suffix of the key, to fit in `BYTE_LIMIT`
+        fn applied_marker(key: &str) -> Option<Op> {
+            let suffix = &key[key.len().saturating_sub(16)..];
+            Some(Op::Insert(DocumentRef::new(format!("applied:{suffix}")), 1))
+        }
+        fn is_applied(
+            &self,
+            key: String,
+        ) -> futures::future::LocalBoxFuture<'_, Result<bool, TrxCommitErr>> {
+            Box::pin(async move {
+                let Some(Op::Insert(marker, _)) = Self::applied_marker(&key) else {
+                    unreachable!()
+                };
+                Ok(self.read(marker.path_ref).await.is_some())
+            })
+        }
+        fn clear_applied(
+            &self,
+            key: String,
+        ) -> futures::future::LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+            Box::pin(async move {
+                let Some(Op::Insert(marker, _)) = Self::applied_marker(&key) else {
+                    unreachable!()
+                };
+                self.records.lock().await.remove(&marker.path_ref);
+                Ok(())
+            })
+        }
+    }
+
+    #[derive(Debug, Clone)]
     struct DocumentRef {
         path_ref: String,
     }
@@ -806,4 +1459,1043 @@ mod test {
         assert_eq!(db.read("counter2").await, Some(5));
         assert_eq!(db.read("counter3").await, Some(3));
     }
+
+    fn count_aborts(trx: &TrxHandle<DummyDB>) -> Rc<Cell<u32>> {
+        let aborts = Rc::new(Cell::new(0));
+        let counter = aborts.clone();
+        trx.add_abort_hook(Box::new(move || {
+            counter.set(counter.get() + 1);
+            None
+        }));
+        aborts
+    }
+
+    #[tokio::test]
+    async fn abort_hooks_on_commit_failure() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("commit-fails");
+        let aborts = count_aborts(&transaction);
+        let _ = update_counter(&transaction, "counter1", 1).await;
+
+        assert!(transaction.apply().await.is_err());
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn abort_hooks_on_op_failure() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("op-fails");
+        let aborts = count_aborts(&transaction);
+        let state = Rc::new(Cell::new(TrxState::Pending));
+        {
+            let state = state.clone();
+            let txr = transaction.get_ref();
+            transaction.add_abort_hook(Box::new(move || {
+                state.set(txr.upgrade().map(|txh| txh.state()).unwrap_or_default());
+                None
+            }));
+        }
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        transaction.add_future_op(async { Err(BatchTrxOpErr::Other("op failed".into())) });
+
+        assert!(transaction.apply().await.is_err());
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(state.get(), TrxState::Failed);
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn abort_hooks_on_pre_commit_failure() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("pre-commit-fails");
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        transaction.add_pre_commit_hook(Box::new(|_| Err("pre-commit failed".into())));
+
+        assert!(transaction.apply().await.is_err());
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn abort_hooks_skipped_on_success() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("succeeds");
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(aborts.get(), 0);
+        assert_eq!(db.read("counter1").await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn revert_committed_batches() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("setup");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        assert!(transaction.apply().await.is_ok());
+
+        // first batch commits, the second one fails because counter4 does not exist
+        let transaction = db.trx("multi-batch");
+        let _ = update_counter(&transaction, "counter1", 10).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        let _ = insert_counter(&transaction, "counter3", 3).await;
+        let _ = update_counter(&transaction, "counter4", 4).await;
+
+        let err = transaction.apply().await.unwrap_err();
+        assert_eq!(err.code(), TrxErrCode::NotFound);
+        assert_eq!(err.path(), Some("counter4"));
+        assert_eq!(err.op_index(), Some(3));
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert_eq!(db.read("counter2").await, None);
+        assert_eq!(db.read("counter3").await, None);
+    }
+
+    #[tokio::test]
+    async fn revert_reports_unrestored_documents() {
+        let db = DummyDB::default();
+        db.unrestorable.set(true);
+
+        let transaction = db.trx("multi-batch");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        let _ = delete_counter(&transaction, "counter3").await;
+
+        let Err(TrxApplyErr::Commit(TrxCommitErr::Unreverted { unrestored, .. })) =
+            transaction.apply().await
+        else {
+            panic!("expected the first batch to be unreverted");
+        };
+        let mut unrestored = unrestored;
+        unrestored.sort();
+        assert_eq!(unrestored, ["counter1", "counter2"]);
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert_eq!(db.read("counter2").await, Some(2));
+    }
+
+    /// Records the backoff delays instead of sleeping
+    #[derive(Default, Clone)]
+    struct TestClock(Rc<RefCell<Vec<Duration>>>);
+
+    impl Clock for TestClock {
+        fn sleep(&self, duration: Duration) -> futures::future::LocalBoxFuture<'_, ()> {
+            self.0.borrow_mut().push(duration);
+            Box::pin(async {})
+        }
+    }
+
+    impl TestClock {
+        fn sleeps(&self) -> Vec<Duration> {
+            RefCell::borrow(&self.0).clone()
+        }
+    }
+
+    fn retry_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_transient_commit_failures() {
+        let db = DummyDB::default();
+        db.unavailable.set(2);
+        let clock = TestClock::default();
+        let retries = Rc::new(Cell::new(0));
+
+        let mut transaction = db.trx("retry");
+        transaction.set_retry_policy(retry_policy(4), clock.clone());
+        transaction.add_retry_hook({
+            let retries = retries.clone();
+            Box::new(move |attempt, _, err| {
+                assert_eq!(err.code(), TrxErrCode::Unavailable);
+                retries.set(attempt);
+            })
+        });
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        let _ = insert_counter(&transaction, "counter3", 3).await;
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(retries.get(), 2);
+        assert_eq!(
+            clock.sleeps(),
+            [Duration::from_millis(100), Duration::from_millis(200)]
+        );
+        assert_eq!(db.read("counter3").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_max_attempts() {
+        let db = DummyDB::default();
+        db.unavailable.set(5);
+        let clock = TestClock::default();
+        let aborted = Rc::new(Cell::new(false));
+
+        let mut transaction = db.trx("retry");
+        transaction.set_retry_policy(retry_policy(3), clock.clone());
+        transaction.add_abort_hook({
+            let aborted = aborted.clone();
+            Box::new(move || {
+                aborted.set(true);
+                None
+            })
+        });
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+
+        let err = transaction.apply().await.unwrap_err();
+        assert_eq!(err.code(), TrxErrCode::Unavailable);
+        assert_eq!(clock.sleeps().len(), 2);
+        assert_eq!(db.unavailable.get(), 2);
+        assert!(aborted.get());
+    }
+
+    #[tokio::test]
+    async fn failed_commit_can_be_retried() {
+        let db = DummyDB::default();
+        db.unavailable.set(1);
+
+        let transaction = db.trx("retryable");
+        let aborts = count_aborts(&transaction);
+        let committed = Rc::new(Cell::new(false));
+        transaction.add_post_commit_hook({
+            let committed = committed.clone();
+            Box::new(move || {
+                committed.set(true);
+                None
+            })
+        });
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let (err, retry) = transaction.apply_retryable().await.unwrap_err();
+        assert_eq!(err.code(), TrxErrCode::Unavailable);
+        assert_eq!(db.read("counter1").await, None);
+        // the optimistic state is kept for the retry
+        assert_eq!(aborts.get(), 0);
+
+        let summary = retry.unwrap().apply().await.unwrap();
+        assert_eq!(summary.ops, 1);
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert!(committed.get());
+        assert_eq!(aborts.get(), 0);
+
+        // nothing to retry when the ops themselves failed
+        let transaction = db.trx("op-failure");
+        transaction.add_future_op(async { Err(BatchTrxOpErr::Other("op failed".into())) });
+        let (_, retry) = transaction.apply_retryable().await.unwrap_err();
+        assert!(retry.is_none());
+
+        // nor when the commit would fail again
+        let transaction = db.trx("duplicate");
+        let _ = insert_counter(&transaction, "counter1", 2).await;
+        let (err, retry) = transaction.apply_retryable().await.unwrap_err();
+        assert_eq!(err.code(), TrxErrCode::AlreadyExists);
+        assert!(retry.is_none());
+    }
+
+    #[tokio::test]
+    async fn retry_skips_permanent_errors() {
+        let db = DummyDB::default();
+        let clock = TestClock::default();
+
+        let transaction = db.trx("first");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        assert!(transaction.apply().await.is_ok());
+
+        let mut transaction = db.trx("duplicate");
+        transaction.set_retry_policy(retry_policy(4), clock.clone());
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+
+        let err = transaction.apply().await.unwrap_err();
+        assert_eq!(err.code(), TrxErrCode::AlreadyExists);
+        assert!(clock.sleeps().is_empty());
+    }
+
+    /// The events following the apply of a transaction, leaving out the ops being
+    /// dispatched so the assertions do not depend on how the ops are scheduled
+    fn commit_events(events: &[TrxEvent]) -> Vec<TrxEvent> {
+        let apply_start = events
+            .iter()
+            .position(|event| matches!(event, TrxEvent::ApplyStart { .. }))
+            .expect("the transaction was applied");
+        events[apply_start..]
+            .iter()
+            .filter(|event| !matches!(event, TrxEvent::OpsDispatched { .. }))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn trace_transaction_lifecycle() {
+        let db = DummyDB::default();
+        db.unavailable.set(1);
+        let clock = TestClock::default();
+
+        let mut transaction = db.trx("traced");
+        let name = transaction.name().to_string();
+        transaction.set_retry_policy(retry_policy(2), clock);
+        transaction.add_on_commit_hook(Box::new(|| None));
+        transaction.add_pre_commit_hook(Box::new(|_| Ok(())));
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        let _ = insert_counter(&transaction, "counter3", 3).await;
+        assert!(transaction.apply().await.is_ok());
+
+        let events = db.sink.events_of(&name);
+        assert_eq!(events.first(), Some(&TrxEvent::Created));
+        let batched = events.iter().filter_map(|event| match event {
+            TrxEvent::OpBatched { count, .. } => Some(*count),
+            _ => None,
+        });
+        assert!(batched.eq(1..=3));
+        assert_eq!(
+            commit_events(&events),
+            [
+                TrxEvent::ApplyStart { ops: 3 },
+                TrxEvent::HooksDispatched {
+                    kind: HookKind::PreCommit,
+                    count: 1
+                },
+                TrxEvent::HooksDispatched {
+                    kind: HookKind::OnCommit,
+                    count: 1
+                },
+                TrxEvent::CommitStart { ops: 3, batches: 2 },
+                TrxEvent::CommitEnd {
+                    ops: 3,
+                    batches: 2,
+                    duration: Duration::ZERO,
+                    error: Some(TrxErrCode::Unavailable)
+                },
+                TrxEvent::Retrying {
+                    attempt: 1,
+                    delay: Duration::from_millis(100)
+                },
+                TrxEvent::CommitStart { ops: 3, batches: 2 },
+                TrxEvent::CommitEnd {
+                    ops: 3,
+                    batches: 2,
+                    duration: Duration::ZERO,
+                    error: None
+                },
+                TrxEvent::Applied {
+                    ops: 3,
+                    duration: Duration::ZERO
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn trace_aborted_transaction() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("aborted");
+        let name = transaction.name().to_string();
+        transaction.add_abort_hook(Box::new(|| None));
+        let _ = update_counter(&transaction, "missing", 1).await;
+        assert!(transaction.apply().await.is_err());
+
+        let events = db.sink.events_of(&name);
+        assert_eq!(
+            commit_events(&events),
+            [
+                TrxEvent::ApplyStart { ops: 1 },
+                TrxEvent::CommitStart { ops: 1, batches: 1 },
+                TrxEvent::CommitEnd {
+                    ops: 1,
+                    batches: 1,
+                    duration: Duration::ZERO,
+                    error: Some(TrxErrCode::NotFound)
+                },
+                TrxEvent::Aborted {
+                    code: TrxErrCode::NotFound
+                },
+                TrxEvent::HooksDispatched {
+                    kind: HookKind::Abort,
+                    count: 1
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesce_ops_per_document() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("first");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        assert!(transaction.apply().await.is_ok());
+
+        let transaction = db.trx("coalesced");
+        let name = transaction.name().to_string();
+        // insert then update
+        let _ = insert_counter(&transaction, "counter3", 3).await;
+        let _ = update_counter(&transaction, "counter3", 4).await;
+        // insert then delete
+        let _ = insert_counter(&transaction, "counter4", 4).await;
+        let _ = delete_counter(&transaction, "counter4").await;
+        // update then delete
+        let _ = update_counter(&transaction, "counter1", 5).await;
+        let _ = delete_counter(&transaction, "counter1").await;
+        // delete then insert
+        let _ = delete_counter(&transaction, "counter2").await;
+        let _ = insert_counter(&transaction, "counter2", 6).await;
+        assert_eq!(transaction.batch_counter(), 8);
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(db.read("counter1").await, None);
+        assert_eq!(db.read("counter2").await, Some(6));
+        assert_eq!(db.read("counter3").await, Some(4));
+        assert_eq!(db.read("counter4").await, None);
+
+        let commit_start = TrxEvent::CommitStart { ops: 3, batches: 2 };
+        assert!(db.sink.events_of(&name).contains(&commit_start));
+    }
+
+    #[tokio::test]
+    async fn split_batches_by_size() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("sized");
+        let name = transaction.name().to_string();
+        // 17 bytes each, so they do not fit in a single batch
+        let _ = insert_counter(&transaction, "big-counter-1", 1).await;
+        let _ = insert_counter(&transaction, "big-counter-2", 2).await;
+        assert!(transaction.apply().await.is_ok());
+
+        let commit_start = TrxEvent::CommitStart { ops: 2, batches: 2 };
+        assert!(db.sink.events_of(&name).contains(&commit_start));
+        assert_eq!(db.read("big-counter-2").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn reject_oversize_op() {
+        let db = DummyDB::default();
+        let clock = TestClock::default();
+
+        let mut transaction = db.trx("oversize");
+        transaction.set_retry_policy(retry_policy(4), clock.clone());
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        let _ = insert_counter(&transaction, "a-counter-with-a-very-long-path", 3).await;
+
+        let Err(TrxApplyErr::Commit(err @ TrxCommitErr::Oversize { size: 35, .. })) =
+            transaction.apply().await
+        else {
+            panic!("expected an oversize error");
+        };
+        assert_eq!(err.code(), TrxErrCode::InvalidArgument);
+        assert_eq!(err.path(), Some("a-counter-with-a-very-long-path"));
+        assert_eq!(err.op_index(), Some(2));
+        assert!(clock.sleeps().is_empty());
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn savepoint_release_merges_into_parent() {
+        let db = DummyDB::default();
+        let committed = Rc::new(Cell::new(false));
+
+        let transaction = db.trx("parent");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+
+        let savepoint = transaction.savepoint();
+        let _ = insert_counter(&savepoint, "counter2", 2).await;
+        savepoint.add_op(|txh| async {
+            insert_counter(&txh, "counter3", 3).await?;
+            Ok(txh)
+        });
+        savepoint.add_on_commit_hook({
+            let committed = committed.clone();
+            Box::new(move || {
+                committed.set(true);
+                None
+            })
+        });
+        assert!(savepoint.release().await.is_ok());
+        assert_eq!(transaction.batch_counter(), 3);
+
+        assert!(transaction.apply().await.is_ok());
+        assert!(committed.get());
+        assert_eq!(db.read("counter2").await, Some(2));
+        assert_eq!(db.read("counter3").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn savepoint_failure_is_discarded() {
+        let db = DummyDB::default();
+        let aborted = Rc::new(Cell::new(0));
+
+        let transaction = db.trx("parent");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+
+        let savepoint = transaction.savepoint();
+        let _ = insert_counter(&savepoint, "counter2", 2).await;
+        savepoint.add_future_op(async { Err(BatchTrxOpErr::Other("op failed".into())) });
+        savepoint.add_abort_hook({
+            let aborted = aborted.clone();
+            Box::new(move || {
+                aborted.set(aborted.get() + 1);
+                None
+            })
+        });
+        assert!(savepoint.release().await.is_err());
+
+        let savepoint = transaction.savepoint();
+        let _ = insert_counter(&savepoint, "counter3", 3).await;
+        savepoint.add_abort_hook({
+            let aborted = aborted.clone();
+            Box::new(move || {
+                aborted.set(aborted.get() + 1);
+                None
+            })
+        });
+        savepoint.rollback().await;
+
+        assert_eq!(aborted.get(), 2);
+        assert_eq!(transaction.batch_counter(), 1);
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(aborted.get(), 2);
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert_eq!(db.read("counter2").await, None);
+        assert_eq!(db.read("counter3").await, None);
+    }
+
+    #[tokio::test]
+    async fn savepoint_in_pre_commit_hook() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("parent");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        for (id, fail) in [("counter2", false), ("counter3", true)] {
+            transaction.add_pre_commit_hook(Box::new(move |trx| {
+                let savepoint = trx.savepoint();
+                savepoint.add_op(move |txh| async move {
+                    insert_counter(&txh, id, 2).await?;
+                    if fail {
+                        return Err(BatchTrxOpErr::Other("condition not met".into()));
+                    }
+                    Ok(txh)
+                });
+                trx.add_future_op(async move {
+                    let _ = savepoint.release().await;
+                    Ok(())
+                });
+                Ok(())
+            }));
+        }
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(db.read("counter2").await, Some(2));
+        assert_eq!(db.read("counter3").await, None);
+    }
+
+    #[tokio::test]
+    async fn cancelling_parent_interrupts_savepoint_release() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("parent");
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let savepoint = transaction.savepoint();
+        let aborts = count_aborts(&savepoint);
+        let _ = insert_counter(&savepoint, "counter2", 2).await;
+        savepoint.add_future_op(futures::future::pending());
+
+        let txr = transaction.get_ref();
+        let (result, _) = futures::join!(savepoint.release(), async { txr.cancel() });
+        assert!(matches!(result, Err(TrxApplyErr::Cancelled)));
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(transaction.batch_counter(), 1);
+
+        // savepoints opened once the parent was cancelled are interrupted right away
+        let savepoint = transaction.savepoint();
+        let result = savepoint.release().await;
+        assert!(matches!(result, Err(TrxApplyErr::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn parent_timeout_interrupts_savepoint_release() {
+        let db = DummyDB::default();
+        let clock = TestClock::default();
+
+        let mut transaction = db.trx("parent");
+        transaction.set_timeout(Duration::from_secs(30), clock.clone());
+        transaction.add_future_op(futures::future::pending());
+        let savepoint = transaction.savepoint();
+        let aborts = count_aborts(&savepoint);
+        let _ = insert_counter(&savepoint, "counter1", 1).await;
+        savepoint.add_future_op(futures::future::pending());
+
+        let (applied, released) = futures::join!(transaction.apply(), savepoint.release());
+        assert!(matches!(applied, Err(TrxApplyErr::TimedOut(_))));
+        let Err(TrxApplyErr::TimedOut(timeout)) = released else {
+            panic!("expected the savepoint to time out with its parent");
+        };
+        assert_eq!(timeout, Duration::from_secs(30));
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn apply_returns_summary() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("summary");
+        for id in ["counter1", "counter2", "counter3"] {
+            let _ = insert_counter(&transaction, id, 1).await;
+        }
+        let sink = db.sink.clone();
+        transaction.add_future_op(async move {
+            sink.advance(Duration::from_millis(12));
+            Ok(())
+        });
+        transaction.add_pre_commit_hook(Box::new(|trx| {
+            trx.add_warning("Key title had an undefined value");
+            Ok(())
+        }));
+        transaction.add_post_commit_hook(Box::new(|| None));
+
+        let summary = transaction.apply().await.unwrap();
+        assert!(summary.name.starts_with("summary-"));
+        assert_eq!((summary.ops, summary.batches), (3, 2));
+        assert_eq!(summary.ops_duration, Duration::from_millis(12));
+        assert_eq!(summary.commit_duration, Duration::ZERO);
+        let hooks = TrxHooksRun {
+            pre_commit: 1,
+            on_commit: 0,
+            post_commit: 1,
+        };
+        assert_eq!(summary.hooks, hooks);
+        assert_eq!(summary.warnings, ["Key title had an undefined value"]);
+    }
+
+    #[tokio::test]
+    async fn timeout_hung_ops() {
+        let db = DummyDB::default();
+        let clock = TestClock::default();
+
+        let mut transaction = db.trx("hung");
+        transaction.set_timeout(Duration::from_secs(30), clock.clone());
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        transaction.add_future_op(futures::future::pending());
+
+        let txr = transaction.get_ref();
+        let result = transaction.apply().await;
+        let Err(err @ TrxApplyErr::TimedOut(_)) = result else {
+            panic!("expected the transaction to time out");
+        };
+        assert_eq!(err.code(), TrxErrCode::DeadlineExceeded);
+        assert_eq!(clock.sleeps(), [Duration::from_secs(30)]);
+        assert_eq!(aborts.get(), 1);
+        assert!(!txr.cancel());
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn cancel_preparing_transaction() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("cancelled");
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        transaction.add_future_op(futures::future::pending());
+        let txr = transaction.get_ref();
+        transaction.add_future_op(async move {
+            assert!(txr.cancel());
+            assert!(insert_counter(&*txr.upgrade_checked()?, "counter2", 2)
+                .await
+                .is_err());
+            Ok(())
+        });
+
+        let result = transaction.apply().await;
+        assert!(matches!(result, Err(TrxApplyErr::Cancelled)));
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(db.read("counter1").await, None);
+        let aborted = TrxEvent::Aborted {
+            code: TrxErrCode::Cancelled,
+        };
+        assert!(db.sink.events().iter().any(|(_, event)| *event == aborted));
+    }
+
+    #[tokio::test]
+    async fn cancel_pending_transaction() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("cancelled");
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        assert!(transaction.cancel());
+        assert!(insert_counter(&transaction, "counter2", 2).await.is_err());
+
+        assert!(matches!(
+            transaction.apply().await,
+            Err(TrxApplyErr::Cancelled)
+        ));
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(db.read("counter1").await, None);
+    }
+
+    #[tokio::test]
+    async fn ops_follow_dependencies() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("dependencies");
+        let update = transaction
+            .add_op(|txh| async {
+                update_counter(&txh, "counter1", 5).await?;
+                Ok(txh)
+            })
+            .unwrap();
+        let insert = transaction
+            .add_op(|txh| async {
+                insert_counter(&txh, "counter1", 1).await?;
+                Ok(txh)
+            })
+            .unwrap();
+        assert!(transaction.add_dependency(update, insert).is_ok());
+
+        let cycle = transaction.add_dependency(insert, update);
+        assert!(matches!(
+            cycle,
+            Err(BatchTrxOpErr::Schedule(ScheduleErr::Cycle(ids))) if ids == [insert, update, insert]
+        ));
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(db.read("counter1").await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn deferred_ops_run_once_before_pre_commit_hooks() {
+        let db = DummyDB::default();
+        let log: Rc<RefCell<Vec<String>>> = Default::default();
+
+        let transaction = db.trx("deferred");
+        for i in 1..=3 {
+            let (txr, log) = (transaction.get_ref(), log.clone());
+            transaction.add_future_op(async move {
+                log.borrow_mut().push(format!("op{i}"));
+                let deferred = txr.defer_unique("total", move |txh| async move {
+                    log.borrow_mut().push("deferred".into());
+                    insert_counter(&txh, "total", i).await?;
+                    Ok(txh)
+                })?;
+                assert_eq!(deferred, i == 1);
+                Ok(())
+            });
+        }
+        transaction.add_pre_commit_hook({
+            let log = log.clone();
+            Box::new(move |_| {
+                log.borrow_mut().push("pre-commit".into());
+                Ok(())
+            })
+        });
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(log.take(), ["op1", "op2", "op3", "deferred", "pre-commit"]);
+        assert_eq!(db.read("total").await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn deferred_key_stays_reserved_while_its_op_runs() {
+        let db = DummyDB::default();
+        let runs = Rc::new(Cell::new(0));
+
+        let transaction = db.trx("deferred-again");
+        let deferred = transaction.get_ref().defer_unique("total", {
+            let runs = runs.clone();
+            move |txh| async move {
+                runs.set(runs.get() + 1);
+                let again = txh.get_ref().defer_unique("total", |txh| async move {
+                    insert_counter(&txh, "again", 2).await?;
+                    Ok(txh)
+                })?;
+                assert!(!again);
+                insert_counter(&txh, "total", 1).await?;
+                Ok(txh)
+            }
+        });
+        assert!(deferred.unwrap());
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(runs.get(), 1);
+        assert_eq!(db.read("total").await, Some(1));
+        assert_eq!(db.read("again").await, None);
+    }
+
+    /// Simulates a process ending right after its transactions were committed
+    #[derive(Default, Clone)]
+    struct CrashingQueue {
+        queue: MemoryQueue,
+        /// Makes every removal fail, leaving the committed transactions queued
+        crashed: Rc<Cell<bool>>,
+    }
+
+    impl QueueStorage for CrashingQueue {
+        fn put(&self, trx: QueuedTrx) -> futures::future::LocalBoxFuture<'_, Result<(), String>> {
+            self.queue.put(trx)
+        }
+        fn remove(&self, key: String) -> futures::future::LocalBoxFuture<'_, Result<(), String>> {
+            if self.crashed.get() {
+                return Box::pin(async { Err("crashed".to_string()) });
+            }
+            self.queue.remove(key)
+        }
+        fn load(&self) -> futures::future::LocalBoxFuture<'_, Result<Vec<QueuedTrx>, String>> {
+            self.queue.load()
+        }
+        fn claim(
+            &self,
+            key: String,
+        ) -> futures::future::LocalBoxFuture<'_, Result<Option<Claim>, String>> {
+            self.queue.claim(key)
+        }
+    }
+
+    /// Replays the offline queue instead of sleeping between commit attempts
+    struct ReplayingClock {
+        db: DummyDB,
+        queue: OfflineQueue,
+        replayed: Rc<Cell<usize>>,
+    }
+
+    impl Clock for ReplayingClock {
+        fn sleep(&self, _duration: Duration) -> futures::future::LocalBoxFuture<'_, ()> {
+            Box::pin(async {
+                let outcomes = self.queue.replay(&self.db).await;
+                self.replayed.set(self.replayed.get() + outcomes.len());
+            })
+        }
+    }
+
+    async fn applied_markers(db: &DummyDB) -> usize {
+        let records = db.records.lock().await;
+        records
+            .keys()
+            .filter(|key| key.starts_with("applied:"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn offline_queue_replays_stranded_commits() {
+        let db = DummyDB::default();
+        let storage = MemoryQueue::default();
+        let queue = OfflineQueue::new(storage.clone());
+
+        let mut transaction = db.trx("offline");
+        transaction.set_offline_queue(queue.clone());
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        db.unavailable.set(1);
+
+        let Err(err @ TrxApplyErr::Queued { .. }) = transaction.apply().await else {
+            panic!("expected the transaction to be queued");
+        };
+        assert_eq!(err.code(), TrxErrCode::Unavailable);
+        assert_eq!(aborts.get(), 0);
+        let events = db.sink.events();
+        assert!(events
+            .iter()
+            .any(|(_, event)| matches!(event, TrxEvent::Queued { .. })));
+        assert_eq!(queue.pending_replays(), 1);
+        assert_eq!(storage.load().await.unwrap().len(), 1);
+        assert_eq!(db.read("counter1").await, None);
+
+        let outcomes = queue.replay(&db).await;
+        assert_eq!(outcomes.len(), 1);
+        assert!(outcomes[0].1.is_ok());
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert_eq!(queue.pending_replays(), 0);
+        assert!(storage.load().await.unwrap().is_empty());
+        assert_eq!(applied_markers(&db).await, 0);
+
+        // permanent failures are not replayed
+        let mut transaction = db.trx("rejected");
+        transaction.set_offline_queue(queue.clone());
+        let _ = insert_counter(&transaction, "counter1", 2).await;
+        let result = transaction.apply().await;
+        assert!(matches!(result, Err(TrxApplyErr::Commit(_))));
+        assert!(storage.load().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_transactions_run_their_hooks_once_replayed() {
+        let db = DummyDB::default();
+        let queue = OfflineQueue::new(MemoryQueue::default());
+        let queued = |name, counter| {
+            let mut transaction = db.trx(name);
+            transaction.set_offline_queue(queue.clone());
+            let committed = Rc::new(Cell::new(0));
+            transaction.add_post_commit_hook({
+                let committed = committed.clone();
+                Box::new(move || {
+                    committed.set(committed.get() + 1);
+                    None
+                })
+            });
+            let aborts = count_aborts(&transaction);
+            (transaction, counter, committed, aborts)
+        };
+
+        let (transaction, counter, committed, aborts) = queued("committed", "counter1");
+        let _ = insert_counter(&transaction, counter, 1).await;
+        db.unavailable.set(1);
+        assert!(matches!(
+            transaction.apply().await,
+            Err(TrxApplyErr::Queued { .. })
+        ));
+        assert_eq!((committed.get(), aborts.get()), (0, 0));
+        queue.replay(&db).await;
+        assert_eq!((committed.get(), aborts.get()), (1, 0));
+
+        // the replay fails for good, as the counter was inserted meanwhile
+        let (transaction, counter, committed, aborts) = queued("aborted", "counter2");
+        let _ = insert_counter(&transaction, counter, 2).await;
+        db.unavailable.set(1);
+        assert!(matches!(
+            transaction.apply().await,
+            Err(TrxApplyErr::Queued { .. })
+        ));
+        let transaction = db.trx("concurrent");
+        let _ = insert_counter(&transaction, counter, 3).await;
+        transaction.apply().await.unwrap();
+        queue.replay(&db).await;
+        assert_eq!((committed.get(), aborts.get()), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn offline_queue_skips_applied_transactions() {
+        let db = DummyDB::default();
+        let storage = CrashingQueue::default();
+        let queue = OfflineQueue::new(storage.clone());
+
+        storage.crashed.set(true);
+        let mut transaction = db.trx("crashed");
+        transaction.set_offline_queue(queue.clone());
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(storage.load().await.unwrap().len(), 1);
+        assert_eq!(applied_markers(&db).await, 1);
+
+        // replaying the insert would fail, as the counter exists already
+        storage.crashed.set(false);
+        assert!(queue.replay(&db).await.is_empty());
+        assert!(storage.load().await.unwrap().is_empty());
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert_eq!(applied_markers(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn offline_queue_does_not_replay_in_flight_commits() {
+        let db = DummyDB::default();
+        let queue = OfflineQueue::new(MemoryQueue::default());
+        let replayed = Rc::new(Cell::new(0));
+
+        let mut transaction = db.trx("in-flight");
+        transaction.set_offline_queue(queue.clone());
+        let clock = ReplayingClock {
+            db: db.clone(),
+            queue: queue.clone(),
+            replayed: replayed.clone(),
+        };
+        transaction.set_retry_policy(retry_policy(2), clock);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        db.unavailable.set(1);
+
+        let summary = transaction.apply().await.unwrap();
+        assert_eq!(replayed.get(), 0);
+        // the applied marker is not counted
+        assert_eq!(summary.ops, 1);
+        assert_eq!(db.read("counter1").await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn offline_queue_does_not_replay_commits_of_other_processes() {
+        let db = DummyDB::default();
+        let storage = MemoryQueue::default();
+        // another tab, sharing the storage
+        let other = OfflineQueue::new(storage.clone());
+        let replayed = Rc::new(Cell::new(0));
+
+        let mut transaction = db.trx("other-tab");
+        transaction.set_offline_queue(OfflineQueue::new(storage.clone()));
+        let clock = ReplayingClock {
+            db: db.clone(),
+            queue: other.clone(),
+            replayed: replayed.clone(),
+        };
+        transaction.set_retry_policy(retry_policy(2), clock);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        db.unavailable.set(1);
+
+        assert!(transaction.apply().await.is_ok());
+        assert_eq!(replayed.get(), 0);
+        assert_eq!(db.read("counter1").await, Some(1));
+        assert!(storage.load().await.unwrap().is_empty());
+
+        // the claim is released once the transaction is queued for a replay
+        let mut transaction = db.trx("stranded");
+        transaction.set_offline_queue(OfflineQueue::new(storage.clone()));
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        db.unavailable.set(1);
+        assert!(matches!(
+            transaction.apply().await,
+            Err(TrxApplyErr::Queued { .. })
+        ));
+        let outcomes = other.replay(&db).await;
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(db.read("counter2").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn dry_run_plans_without_committing() {
+        let db = DummyDB::default();
+
+        let transaction = db.trx("dry-run");
+        let aborts = count_aborts(&transaction);
+        let _ = insert_counter(&transaction, "counter1", 1).await;
+        let _ = insert_counter(&transaction, "counter2", 2).await;
+        let _ = update_counter(&transaction, "counter1", 10).await;
+        let _ = delete_counter(&transaction, "counter3").await;
+        transaction.add_pre_commit_hook(Box::new(|txh| {
+            txh.process_op(Op::Insert(DocumentRef::new("hooked"), 4))
+        }));
+
+        let plan = transaction.dry_run().await.unwrap();
+        let paths: Vec<&str> = plan.ops.iter().map(|op| op.path.as_str()).collect();
+        assert_eq!(paths, ["counter1", "counter2", "counter3", "hooked"]);
+        assert_eq!(plan.batches, [0..2, 2..4]);
+        assert_eq!(aborts.get(), 1);
+        assert_eq!(db.read("counter1").await, None);
+        assert_eq!(db.read("hooked").await, None);
+    }
+
+    #[test]
+    fn planned_preconditions_serialize_as_in_js() {
+        let precondition = |precondition| {
+            let op = PlannedOp::new("counter1", "update").with_precondition(Some(precondition));
+            serde_json::to_value(op).unwrap()["precondition"].clone()
+        };
+        assert_eq!(
+            precondition(Precondition::Exists),
+            serde_json::json!({ "exists": true })
+        );
+        assert_eq!(
+            precondition(Precondition::UpdatedAt(42)),
+            serde_json::json!({ "updatedAt": 42 })
+        );
+    }
 }
@@ -1,6 +1,56 @@
-use super::FullOpenTrxHandle;
+use super::{
+    BatchTrxOpErr, FullOpenTrxHandle, LogSink, PlannedOp, TraceSink, TrxCommitErr, TrxRevertErr,
+};
 use futures::future::LocalBoxFuture;
-use std::fmt::{Debug, Display};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    ops::Range,
+    rc::Rc,
+};
+
+/// Outcome of folding an op into a previous op on the same document
+pub enum Coalesced<Op> {
+    /// The previous op now accounts for both of them
+    Merged,
+    /// The ops cancel each other out, so neither is committed
+    Cancelled,
+    /// The ops can not be combined, and are both committed in order
+    Separate(Op),
+}
+
+/// Condition the stored document must meet for an op to be committed. It is checked against
+/// the document as it was before the child batch holding the op, atomically with its commit.
+/// It serializes as `TrxPrecondition` does in JS, `{ exists: true }` or `{ updatedAt: millis }`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Precondition {
+    /// The document must exist
+    Exists,
+    /// The document must exist, and its `updatedAt` field must be this time,
+    /// in milliseconds since the epoch
+    UpdatedAt(i64),
+}
+
+impl Serialize for Precondition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        match self {
+            Precondition::Exists => map.serialize_entry("exists", &true)?,
+            Precondition::UpdatedAt(millis) => map.serialize_entry("updatedAt", millis)?,
+        }
+        map.end()
+    }
+}
+
+impl Display for Precondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Precondition::Exists => write!(f, "must exist"),
+            Precondition::UpdatedAt(millis) => write!(f, "must be last updated at {millis}"),
+        }
+    }
+}
 
 /// A batch is an accumulator of db operations which will be committed at the end of a transaction.
 /// Somtimes we might have more than one batch, depending on size limitations of the Batch impl
@@ -8,7 +58,8 @@ pub trait Batchable: Sized {
     /// Batch operation
     /// Could be an enum (insert, update, delete)
     /// or could be only callback
-    type Op: Debug;
+    /// Ops are cloned into the child batches, so a failed commit can be retried
+    type Op: Debug + Clone;
 
     type TrxOp: Debug;
 
@@ -21,49 +72,163 @@ pub trait Batchable: Sized {
 
     const LIMIT: usize = 400;
 
+    /// Maximum estimated size, in bytes, of the ops of a child batch
+    const BYTE_LIMIT: usize = usize::MAX;
+
     fn child(&self) -> Result<Self::Child, String>;
     fn insert(child: &Self::Child, op: Self::Op);
     fn commit(child: &Self::Child) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>>;
 
-    fn trace_messages(level: u8, messages: &[&dyn std::fmt::Display]);
+    /// Path of the document targeted by the operation
+    fn op_path(op: &Self::Op) -> String;
 
-    fn process_trx_op(trx: FullOpenTrxHandle<Self>, op: Self::TrxOp) -> Result<(), BatchTrxOpErr>;
-}
+    /// Estimated size, in bytes, of the operation once sent to the database
+    fn op_size(_op: &Self::Op) -> usize {
+        0
+    }
 
-#[derive(Debug)]
-pub enum BatchTrxOpErr {
-    AccessDenied(String),
-    Other(String),
-    ClosedForAddingOps,
-}
+    /// Precondition the op is committed under, if any
+    fn op_precondition(_op: &Self::Op) -> Option<&Precondition> {
+        None
+    }
 
-impl From<String> for BatchTrxOpErr {
-    fn from(value: String) -> Self {
-        BatchTrxOpErr::Other(value)
+    /// Describe the op for `Transaction::dry_run`. Backends should override this,
+    /// the default payload being the `Debug` output of the op.
+    fn explain_op(op: &Self::Op) -> PlannedOp {
+        PlannedOp::new(Self::op_path(op), "op")
+            .with_payload(format!("{op:?}").into())
+            .with_precondition(Self::op_precondition(op).cloned())
     }
-}
 
-pub enum TrxCommitErr {
-    Msg(String),
-}
+    /// Fold `next` into `prev`, an earlier op of the same transaction on the same document,
+    /// so that a single write per document is committed. Ops are kept separate by default.
+    /// Ops with a precondition are never coalesced, so their precondition is not lost.
+    fn coalesce(_prev: &mut Self::Op, next: Self::Op) -> Coalesced<Self::Op> {
+        Coalesced::Separate(next)
+    }
 
-impl From<String> for TrxCommitErr {
-    fn from(msg: String) -> Self {
-        TrxCommitErr::Msg(msg)
+    /// Called before committing a child batch which may have to be reverted later on,
+    /// because more child batches of the same transaction are still to be committed.
+    /// Implementations supporting `revert` should capture the before-images of the
+    /// documents touched by the batch here.
+    fn capture(_child: &Self::Child) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+        Box::pin(async { Ok(()) })
     }
+
+    /// Undo an already committed child batch, restoring the state captured by `capture`.
+    /// This is used when a later child batch of the same transaction fails to commit.
+    fn revert(_child: &Self::Child) -> LocalBoxFuture<'_, Result<(), TrxRevertErr>> {
+        Box::pin(async { Err(TrxRevertErr::Unsupported) })
+    }
+
+    /// Serialize an op for the offline queue, `None` if it can not be replayed later on
+    fn encode_op(_op: &Self::Op) -> Option<String> {
+        None
+    }
+
+    fn decode_op(_encoded: &str) -> Option<Self::Op> {
+        None
+    }
+
+    /// Extras of a savepoint opened on a transaction with the `parent` extras.
+    /// The savepoint gets its own empty extras by default.
+    fn savepoint_extras(_parent: &Self::Extras) -> Self::Extras {
+        Self::Extras::default()
+    }
+
+    /// Merge the extras of a released savepoint into the extras of its parent transaction
+    fn release_extras(_savepoint: &Self::Extras, _parent: &Self::Extras) {}
+
+    /// Op recording that the transaction with the idempotency `key` was committed.
+    /// It is committed along with the last child batch of the transaction, so that
+    /// `is_applied` tells whether a queued transaction must be replayed.
+    fn applied_marker(_key: &str) -> Option<Self::Op> {
+        None
+    }
+
+    /// Whether the `applied_marker` of `key` was committed
+    fn is_applied(&self, _key: String) -> LocalBoxFuture<'_, Result<bool, TrxCommitErr>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    /// Delete the `applied_marker` of `key`, once its transaction left the offline queue
+    fn clear_applied(&self, _key: String) -> LocalBoxFuture<'_, Result<(), TrxCommitErr>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Where the transactions of this backend report their audit events
+    fn trace_sink(&self) -> Rc<dyn TraceSink> {
+        Rc::new(LogSink)
+    }
+
+    fn process_trx_op(trx: FullOpenTrxHandle<Self>, op: Self::TrxOp) -> Result<(), BatchTrxOpErr>;
 }
-impl Display for TrxCommitErr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TrxCommitErr::Msg(msg) => Display::fmt(msg, f),
+
+/// Normalize `ops` per document path with `Batchable::coalesce`, keeping the order in which
+/// documents were first written
+pub fn coalesce_ops<B: Batchable>(ops: Vec<B::Op>) -> Vec<B::Op> {
+    let mut coalesced: Vec<Option<B::Op>> = Vec::with_capacity(ops.len());
+    // index, in `coalesced`, of the last op on each path
+    let mut last_by_path: HashMap<String, usize> = HashMap::new();
+
+    for op in ops {
+        let path = B::op_path(&op);
+        let prev = match last_by_path.get(&path) {
+            Some(&i) => coalesced[i].as_mut().map(|prev| (i, prev)),
+            None => None,
+        };
+        let prev = prev.filter(|(_, prev)| {
+            B::op_precondition(prev).is_none() && B::op_precondition(&op).is_none()
+        });
+        let Some((i, prev)) = prev else {
+            last_by_path.insert(path, coalesced.len());
+            coalesced.push(Some(op));
+            continue;
+        };
+
+        match B::coalesce(prev, op) {
+            Coalesced::Merged => {}
+            Coalesced::Cancelled => {
+                coalesced[i] = None;
+                last_by_path.remove(&path);
+            }
+            Coalesced::Separate(op) => {
+                last_by_path.insert(path, coalesced.len());
+                coalesced.push(Some(op));
+            }
         }
     }
+
+    coalesced.into_iter().flatten().collect()
 }
 
-impl From<TrxCommitErr> for BatchTrxOpErr {
-    fn from(value: TrxCommitErr) -> Self {
-        match value {
-            TrxCommitErr::Msg(msg) => BatchTrxOpErr::Other(msg),
+/// Split `ops` into the ranges of the child batches, each of them holding at most `B::LIMIT`
+/// ops and `B::BYTE_LIMIT` bytes. Fails if a single op is over `B::BYTE_LIMIT`.
+pub fn split_batches<B: Batchable>(ops: &[B::Op]) -> Result<Vec<Range<usize>>, TrxCommitErr> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (i, op) in ops.iter().enumerate() {
+        let size = B::op_size(op);
+        if size > B::BYTE_LIMIT {
+            return Err(TrxCommitErr::Oversize {
+                path: B::op_path(op),
+                op_index: i,
+                size,
+                limit: B::BYTE_LIMIT,
+            });
         }
+        if i - start == B::LIMIT || bytes + size > B::BYTE_LIMIT {
+            batches.push(start..i);
+            start = i;
+            bytes = 0;
+        }
+        bytes += size;
+    }
+    if start < ops.len() {
+        batches.push(start..ops.len());
     }
+
+    Ok(batches)
 }
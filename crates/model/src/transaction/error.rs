@@ -0,0 +1,607 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use super::{Precondition, ScheduleErr};
+
+/// Stable codes to tell transaction failures apart.
+/// Failures reported by the database use the Firestore error codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrxErrCode {
+    Cancelled,
+    Unknown,
+    InvalidArgument,
+    DeadlineExceeded,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ResourceExhausted,
+    FailedPrecondition,
+    Aborted,
+    Internal,
+    Unavailable,
+    Unauthenticated,
+    /// The transaction does not accept operations anymore, or it was dropped
+    Closed,
+    /// The transaction failed after some of its batches were committed,
+    /// and these could not be reverted
+    Unreverted,
+}
+
+impl TrxErrCode {
+    pub fn as_str(&self) -> &'static str {
+        use TrxErrCode::*;
+        match self {
+            Cancelled => "cancelled",
+            Unknown => "unknown",
+            InvalidArgument => "invalid-argument",
+            DeadlineExceeded => "deadline-exceeded",
+            NotFound => "not-found",
+            AlreadyExists => "already-exists",
+            PermissionDenied => "permission-denied",
+            ResourceExhausted => "resource-exhausted",
+            FailedPrecondition => "failed-precondition",
+            Aborted => "aborted",
+            Internal => "internal",
+            Unavailable => "unavailable",
+            Unauthenticated => "unauthenticated",
+            Closed => "closed",
+            Unreverted => "unreverted",
+        }
+    }
+
+    /// Parse a Firestore error code, like `permission-denied` or `firestore/permission-denied`
+    pub fn parse(code: &str) -> TrxErrCode {
+        use TrxErrCode::*;
+        let code = code.strip_prefix("firestore/").unwrap_or(code);
+        match code {
+            "cancelled" => Cancelled,
+            "invalid-argument" => InvalidArgument,
+            "deadline-exceeded" => DeadlineExceeded,
+            "not-found" => NotFound,
+            "already-exists" => AlreadyExists,
+            "permission-denied" => PermissionDenied,
+            "resource-exhausted" => ResourceExhausted,
+            "failed-precondition" => FailedPrecondition,
+            "aborted" => Aborted,
+            "internal" => Internal,
+            "unavailable" => Unavailable,
+            "unauthenticated" => Unauthenticated,
+            "closed" => Closed,
+            "unreverted" => Unreverted,
+            _ => Unknown,
+        }
+    }
+
+    /// Whether the same write may succeed if it is attempted again
+    pub fn is_transient(&self) -> bool {
+        use TrxErrCode::*;
+        matches!(
+            self,
+            Unavailable | Aborted | DeadlineExceeded | ResourceExhausted
+        )
+    }
+}
+
+impl Display for TrxErrCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Failure reported by the database
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrxDbErr {
+    pub code: TrxErrCode,
+    pub msg: String,
+    /// Path of the offending document, when known
+    pub path: Option<String>,
+    /// Index of the offending operation within the transaction, when known
+    pub op_index: Option<usize>,
+}
+
+impl TrxDbErr {
+    pub fn new(code: TrxErrCode, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            path: None,
+            op_index: None,
+        }
+    }
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+    pub fn with_op_index(mut self, op_index: usize) -> Self {
+        self.op_index = Some(op_index);
+        self
+    }
+}
+
+impl Display for TrxDbErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.msg)?;
+        if let Some(path) = &self.path {
+            write!(f, " (document: {path})")?;
+        }
+        if let Some(op_index) = self.op_index {
+            write!(f, " (op #{op_index})")?;
+        }
+        Ok(())
+    }
+}
+impl Error for TrxDbErr {}
+
+#[derive(Debug)]
+pub enum BatchTrxOpErr {
+    AccessDenied(String),
+    Other(String),
+    ClosedForAddingOps,
+    Db(TrxDbErr),
+    /// An op was scheduled with invalid dependencies
+    Schedule(ScheduleErr),
+}
+
+impl BatchTrxOpErr {
+    pub fn code(&self) -> TrxErrCode {
+        match self {
+            BatchTrxOpErr::AccessDenied(_) => TrxErrCode::PermissionDenied,
+            BatchTrxOpErr::Other(_) => TrxErrCode::Unknown,
+            BatchTrxOpErr::ClosedForAddingOps => TrxErrCode::Closed,
+            BatchTrxOpErr::Db(e) => e.code,
+            BatchTrxOpErr::Schedule(ScheduleErr::UnknownOp(_)) => TrxErrCode::InvalidArgument,
+            BatchTrxOpErr::Schedule(_) => TrxErrCode::FailedPrecondition,
+        }
+    }
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            BatchTrxOpErr::Db(e) => e.path.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for BatchTrxOpErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchTrxOpErr::AccessDenied(msg) | BatchTrxOpErr::Other(msg) => Display::fmt(msg, f),
+            BatchTrxOpErr::ClosedForAddingOps => write!(f, "Operation can not be added"),
+            BatchTrxOpErr::Db(e) => Display::fmt(e, f),
+            BatchTrxOpErr::Schedule(e) => Display::fmt(e, f),
+        }
+    }
+}
+impl Error for BatchTrxOpErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BatchTrxOpErr::Db(e) => Some(e),
+            BatchTrxOpErr::Schedule(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for BatchTrxOpErr {
+    fn from(value: String) -> Self {
+        BatchTrxOpErr::Other(value)
+    }
+}
+impl From<&str> for BatchTrxOpErr {
+    fn from(value: &str) -> Self {
+        BatchTrxOpErr::Other(value.to_string())
+    }
+}
+impl From<TrxDbErr> for BatchTrxOpErr {
+    fn from(value: TrxDbErr) -> Self {
+        BatchTrxOpErr::Db(value)
+    }
+}
+impl From<ScheduleErr> for BatchTrxOpErr {
+    fn from(value: ScheduleErr) -> Self {
+        BatchTrxOpErr::Schedule(value)
+    }
+}
+
+/// The precondition of an op did not hold, so the child batch holding it was not committed
+#[derive(Debug)]
+pub struct TrxConflict {
+    pub path: String,
+    /// Index of the op within the transaction, when known
+    pub op_index: Option<usize>,
+    pub precondition: Precondition,
+    /// The stored document, `None` if it does not exist.
+    /// Its type is the document type of the backend, see `current`.
+    pub current: Option<Box<dyn Any>>,
+}
+
+impl TrxConflict {
+    pub fn new(path: impl Into<String>, precondition: Precondition) -> Self {
+        Self {
+            path: path.into(),
+            op_index: None,
+            precondition,
+            current: None,
+        }
+    }
+    pub fn with_current(mut self, current: impl Any) -> Self {
+        self.current = Some(Box::new(current));
+        self
+    }
+    pub fn with_op_index(mut self, op_index: usize) -> Self {
+        self.op_index = Some(op_index);
+        self
+    }
+
+    /// The stored document, if it exists and is a `T`
+    pub fn current<T: Any>(&self) -> Option<&T> {
+        self.current.as_ref()?.downcast_ref()
+    }
+}
+
+impl Display for TrxConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.current {
+            Some(_) => "was changed",
+            None => "does not exist",
+        };
+        let Self {
+            path, precondition, ..
+        } = self;
+        write!(f, "Document({path}) {precondition}, but it {state}")?;
+        if let Some(op_index) = self.op_index {
+            write!(f, " (op #{op_index})")?;
+        }
+        Ok(())
+    }
+}
+impl Error for TrxConflict {}
+
+#[derive(Debug)]
+pub enum TrxCommitErr {
+    Msg(String),
+    Db(TrxDbErr),
+    /// The commit failed after some child batches were already committed,
+    /// and these could not be reverted completely
+    Unreverted {
+        source: Box<TrxCommitErr>,
+        /// Paths of the documents which were left in their committed state
+        unrestored: Vec<String>,
+    },
+    /// An op is larger than `Batchable::BYTE_LIMIT` on its own, so nothing was committed
+    Oversize {
+        path: String,
+        op_index: usize,
+        size: usize,
+        limit: usize,
+    },
+    /// The precondition of an op did not hold
+    Conflict(TrxConflict),
+}
+
+impl TrxCommitErr {
+    pub fn code(&self) -> TrxErrCode {
+        match self {
+            TrxCommitErr::Msg(_) => TrxErrCode::Unknown,
+            TrxCommitErr::Db(e) => e.code,
+            TrxCommitErr::Unreverted { .. } => TrxErrCode::Unreverted,
+            TrxCommitErr::Oversize { .. } => TrxErrCode::InvalidArgument,
+            TrxCommitErr::Conflict(_) => TrxErrCode::FailedPrecondition,
+        }
+    }
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            TrxCommitErr::Msg(_) => None,
+            TrxCommitErr::Db(e) => e.path.as_deref(),
+            TrxCommitErr::Unreverted { source, .. } => source.path(),
+            TrxCommitErr::Oversize { path, .. } => Some(path),
+            TrxCommitErr::Conflict(e) => Some(&e.path),
+        }
+    }
+    pub fn op_index(&self) -> Option<usize> {
+        match self {
+            TrxCommitErr::Msg(_) => None,
+            TrxCommitErr::Db(e) => e.op_index,
+            TrxCommitErr::Unreverted { source, .. } => source.op_index(),
+            TrxCommitErr::Oversize { op_index, .. } => Some(*op_index),
+            TrxCommitErr::Conflict(e) => e.op_index,
+        }
+    }
+
+    /// The conflict which failed the commit, if any
+    pub fn conflict(&self) -> Option<&TrxConflict> {
+        match self {
+            TrxCommitErr::Conflict(e) => Some(e),
+            TrxCommitErr::Unreverted { source, .. } => source.conflict(),
+            _ => None,
+        }
+    }
+
+    /// Turn the op index reported for a child batch into an index within the transaction,
+    /// and fill in the path of the offending document if the batch did not report it
+    pub(super) fn locate(self, offset: usize, paths: &[String]) -> Self {
+        match self {
+            TrxCommitErr::Db(mut e) => {
+                if let Some(op_index) = e.op_index {
+                    if e.path.is_none() {
+                        e.path = paths.get(op_index).cloned();
+                    }
+                    e.op_index = Some(offset + op_index);
+                }
+                TrxCommitErr::Db(e)
+            }
+            TrxCommitErr::Conflict(mut e) => {
+                e.op_index = e.op_index.map(|op_index| offset + op_index);
+                TrxCommitErr::Conflict(e)
+            }
+            err => err,
+        }
+    }
+}
+
+impl From<String> for TrxCommitErr {
+    fn from(msg: String) -> Self {
+        TrxCommitErr::Msg(msg)
+    }
+}
+impl From<TrxDbErr> for TrxCommitErr {
+    fn from(value: TrxDbErr) -> Self {
+        TrxCommitErr::Db(value)
+    }
+}
+impl From<TrxConflict> for TrxCommitErr {
+    fn from(value: TrxConflict) -> Self {
+        TrxCommitErr::Conflict(value)
+    }
+}
+impl Display for TrxCommitErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrxCommitErr::Msg(msg) => Display::fmt(msg, f),
+            TrxCommitErr::Db(e) => Display::fmt(e, f),
+            TrxCommitErr::Unreverted { source, unrestored } => write!(
+                f,
+                "{source} (unable to revert documents: {})",
+                unrestored.join(", ")
+            ),
+            TrxCommitErr::Oversize {
+                path,
+                op_index,
+                size,
+                limit,
+            } => write!(
+                f,
+                "Op #{op_index} on Document({path}) is about {size} bytes, over the {limit} bytes limit of a batch"
+            ),
+            TrxCommitErr::Conflict(e) => Display::fmt(e, f),
+        }
+    }
+}
+impl Error for TrxCommitErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TrxCommitErr::Msg(_) => None,
+            TrxCommitErr::Db(e) => Some(e),
+            TrxCommitErr::Unreverted { source, .. } => Some(source.as_ref()),
+            TrxCommitErr::Oversize { .. } => None,
+            TrxCommitErr::Conflict(e) => Some(e),
+        }
+    }
+}
+
+impl From<TrxCommitErr> for BatchTrxOpErr {
+    fn from(value: TrxCommitErr) -> Self {
+        match value {
+            TrxCommitErr::Msg(msg) => BatchTrxOpErr::Other(msg),
+            TrxCommitErr::Db(e) => BatchTrxOpErr::Db(e),
+            err @ TrxCommitErr::Unreverted { .. } => BatchTrxOpErr::Other(err.to_string()),
+            err @ (TrxCommitErr::Oversize { .. } | TrxCommitErr::Conflict(_)) => {
+                let db_err = TrxDbErr::new(err.code(), err.to_string());
+                let db_err = match err.path() {
+                    Some(path) => db_err.with_path(path),
+                    None => db_err,
+                };
+                let db_err = match err.op_index() {
+                    Some(op_index) => db_err.with_op_index(op_index),
+                    None => db_err,
+                };
+                BatchTrxOpErr::Db(db_err)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TrxRevertErr {
+    /// The `Batchable` implementation does not know how to revert a child batch
+    Unsupported,
+    /// Paths of the documents that could not be restored
+    Unrestored(Vec<String>),
+}
+
+#[derive(Debug)]
+pub enum TrxCheckErr {
+    ClosedForAddingOps,
+    Dropped,
+}
+impl Display for TrxCheckErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrxCheckErr::ClosedForAddingOps => write!(
+                f,
+                "you can only add operations to a transaction that is pending or preparing"
+            ),
+            TrxCheckErr::Dropped => write!(f, "Transaction was dropped"),
+        }
+    }
+}
+impl Error for TrxCheckErr {}
+
+impl From<TrxCheckErr> for String {
+    fn from(value: TrxCheckErr) -> String {
+        value.to_string()
+    }
+}
+
+impl From<TrxCheckErr> for BatchTrxOpErr {
+    fn from(value: TrxCheckErr) -> Self {
+        match value {
+            TrxCheckErr::ClosedForAddingOps => BatchTrxOpErr::ClosedForAddingOps,
+            TrxCheckErr::Dropped => {
+                BatchTrxOpErr::Db(TrxDbErr::new(TrxErrCode::Closed, value.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TrxApplyErr {
+    /// A scheduled op or a pre-commit hook failed
+    Op(BatchTrxOpErr),
+    /// Committing the batched operations failed
+    Commit(TrxCommitErr),
+    FailedCheck(TrxCheckErr),
+    /// The transaction was cancelled before it started committing
+    Cancelled,
+    /// Scheduled ops and pre-commit hooks were still running after the timeout
+    TimedOut(Duration),
+    /// The commit failed with a transient error, and the transaction waits in the
+    /// offline queue to be replayed
+    Queued {
+        key: String,
+        source: TrxCommitErr,
+    },
+}
+
+impl TrxApplyErr {
+    pub fn code(&self) -> TrxErrCode {
+        match self {
+            TrxApplyErr::Op(e) => e.code(),
+            TrxApplyErr::Commit(e) => e.code(),
+            TrxApplyErr::FailedCheck(_) => TrxErrCode::Closed,
+            TrxApplyErr::Cancelled => TrxErrCode::Cancelled,
+            TrxApplyErr::TimedOut(_) => TrxErrCode::DeadlineExceeded,
+            TrxApplyErr::Queued { source, .. } => source.code(),
+        }
+    }
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            TrxApplyErr::Op(e) => e.path(),
+            TrxApplyErr::Commit(e) | TrxApplyErr::Queued { source: e, .. } => e.path(),
+            _ => None,
+        }
+    }
+    pub fn op_index(&self) -> Option<usize> {
+        match self {
+            TrxApplyErr::Commit(e) | TrxApplyErr::Queued { source: e, .. } => e.op_index(),
+            _ => None,
+        }
+    }
+    /// The conflict which failed the transaction, so the caller can offer to merge
+    /// with the stored document
+    pub fn conflict(&self) -> Option<&TrxConflict> {
+        match self {
+            TrxApplyErr::Commit(e) => e.conflict(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for TrxApplyErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrxApplyErr::Op(e) => Display::fmt(e, f),
+            TrxApplyErr::Commit(e) => Display::fmt(e, f),
+            TrxApplyErr::FailedCheck(e) => Display::fmt(e, f),
+            TrxApplyErr::Cancelled => write!(f, "Transaction was cancelled"),
+            TrxApplyErr::TimedOut(timeout) => {
+                write!(f, "Transaction was still preparing after {timeout:?}")
+            }
+            TrxApplyErr::Queued { key, source } => {
+                write!(f, "{source} (queued for replay as {key})")
+            }
+        }
+    }
+}
+impl Error for TrxApplyErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TrxApplyErr::Op(e) => e.source(),
+            TrxApplyErr::Commit(e) => e.source(),
+            TrxApplyErr::Queued { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<TrxCommitErr> for TrxApplyErr {
+    fn from(value: TrxCommitErr) -> Self {
+        TrxApplyErr::Commit(value)
+    }
+}
+impl From<BatchTrxOpErr> for TrxApplyErr {
+    fn from(value: BatchTrxOpErr) -> Self {
+        TrxApplyErr::Op(value)
+    }
+}
+impl From<TrxCheckErr> for TrxApplyErr {
+    fn from(value: TrxCheckErr) -> Self {
+        TrxApplyErr::FailedCheck(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::{BatchTrxOpErr, TrxApplyErr, TrxCheckErr, TrxCommitErr, TrxDbErr, TrxErrCode};
+
+    #[test]
+    fn parse_firestore_codes() {
+        assert_eq!(
+            TrxErrCode::parse("permission-denied"),
+            TrxErrCode::PermissionDenied
+        );
+        assert_eq!(
+            TrxErrCode::parse("firestore/unavailable"),
+            TrxErrCode::Unavailable
+        );
+        assert_eq!(TrxErrCode::parse("something-new"), TrxErrCode::Unknown);
+        assert!(TrxErrCode::Unavailable.is_transient());
+        assert!(!TrxErrCode::PermissionDenied.is_transient());
+    }
+
+    #[test]
+    fn apply_err_source_chain() {
+        let db_err = TrxDbErr::new(TrxErrCode::NotFound, "no document")
+            .with_path("vertex/a")
+            .with_op_index(3);
+        let err: TrxApplyErr = TrxCommitErr::Unreverted {
+            source: Box::new(db_err.clone().into()),
+            unrestored: vec!["vertex/b".into()],
+        }
+        .into();
+
+        assert_eq!(err.code(), TrxErrCode::Unreverted);
+        assert_eq!(err.path(), Some("vertex/a"));
+        assert_eq!(err.op_index(), Some(3));
+
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), db_err.to_string());
+        assert!(source.source().unwrap().is::<TrxDbErr>());
+    }
+
+    #[test]
+    fn failed_checks_are_closed_errors() {
+        for check in [TrxCheckErr::ClosedForAddingOps, TrxCheckErr::Dropped] {
+            let err = BatchTrxOpErr::from(check);
+            assert_eq!(err.code(), TrxErrCode::Closed);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::ops::Range;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use super::Precondition;
+
+/// An op as it would be committed, described by `Batchable::explain_op`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedOp {
+    pub path: String,
+    /// Kind of write, as named by the backend, like `set` or `delete`
+    pub kind: &'static str,
+    pub merge: bool,
+    /// Data written, `null` for a delete
+    pub payload: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub precondition: Option<Precondition>,
+}
+
+impl PlannedOp {
+    pub fn new(path: impl Into<String>, kind: &'static str) -> Self {
+        Self {
+            path: path.into(),
+            kind,
+            merge: false,
+            payload: Value::Null,
+            precondition: None,
+        }
+    }
+    pub fn with_merge(mut self, merge: bool) -> Self {
+        self.merge = merge;
+        self
+    }
+    pub fn with_payload(mut self, payload: Value) -> Self {
+        self.payload = payload;
+        self
+    }
+    pub fn with_precondition(mut self, precondition: Option<Precondition>) -> Self {
+        self.precondition = precondition;
+        self
+    }
+}
+
+/// What `Transaction::apply` would commit, as returned by `Transaction::dry_run`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrxPlan {
+    /// Ops once coalesced, in commit order
+    pub ops: Vec<PlannedOp>,
+    /// Ranges of `ops` committed together in a child batch, in commit order
+    pub batches: Vec<Range<usize>>,
+}
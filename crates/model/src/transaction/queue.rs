@@ -0,0 +1,405 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    rc::Rc,
+};
+
+use futures::future::{join_all, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+
+use super::{Batchable, TerminalHooks, Transaction, TrxApplyErr};
+use crate::utils::{clock::epoch_millis, random::random_u64};
+
+/// A transaction saved before its commit, so it can be replayed if the commit does not complete
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedTrx {
+    /// Idempotency key, which stays the same across replays
+    pub key: String,
+    pub name: String,
+    /// Ops encoded by `Batchable::encode_op`, in commit order
+    pub ops: Vec<String>,
+    /// Milliseconds since the epoch, transactions being replayed oldest first
+    pub queued_at: u64,
+}
+
+/// Exclusive claim on a queued transaction, released when dropped
+#[derive(Default)]
+pub struct Claim(Option<Box<dyn FnOnce()>>);
+
+impl Claim {
+    pub fn new(release: impl FnOnce() + 'static) -> Self {
+        Self(Some(Box::new(release)))
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(release) = self.0.take() {
+            release()
+        }
+    }
+}
+
+/// Durable storage of the offline queue
+pub trait QueueStorage {
+    /// Insert or replace the transaction with the same key
+    fn put(&self, trx: QueuedTrx) -> LocalBoxFuture<'_, Result<(), String>>;
+    fn remove(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>>;
+    /// Every queued transaction, in no particular order
+    fn load(&self) -> LocalBoxFuture<'_, Result<Vec<QueuedTrx>, String>>;
+
+    /// Claim the transaction `key` until the claim is dropped, so the processes sharing the
+    /// storage do not commit it at the same time. Resolves to `None` while another process
+    /// holds it. Storage which is not shared between processes has nothing to claim.
+    fn claim(&self, _key: String) -> LocalBoxFuture<'_, Result<Option<Claim>, String>> {
+        Box::pin(async { Ok(Some(Claim::default())) })
+    }
+}
+
+/// Queue storage which does not outlive the process, for tests. Its clones share the queued
+/// transactions and their claims, like the processes sharing a durable storage.
+#[derive(Default, Clone)]
+pub struct MemoryQueue {
+    queued: Rc<RefCell<BTreeMap<String, QueuedTrx>>>,
+    claimed: Rc<RefCell<HashSet<String>>>,
+}
+
+impl QueueStorage for MemoryQueue {
+    fn put(&self, trx: QueuedTrx) -> LocalBoxFuture<'_, Result<(), String>> {
+        self.queued.borrow_mut().insert(trx.key.clone(), trx);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>> {
+        self.queued.borrow_mut().remove(&key);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<Vec<QueuedTrx>, String>> {
+        let queued = self.queued.borrow().values().cloned().collect();
+        Box::pin(async { Ok(queued) })
+    }
+
+    fn claim(&self, key: String) -> LocalBoxFuture<'_, Result<Option<Claim>, String>> {
+        let claim = self.claimed.borrow_mut().insert(key.clone()).then(|| {
+            let claimed = self.claimed.clone();
+            Claim::new(move || {
+                claimed.borrow_mut().remove(&key);
+            })
+        });
+        Box::pin(async { Ok(claim) })
+    }
+}
+
+/// Queue storage keeping a JSON file per transaction in a directory
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FileQueue {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileQueue {
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        // keys are made of transaction names, which may not be valid file names
+        self.dir.join(format!("{}.json", hex::encode(key)))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl QueueStorage for FileQueue {
+    fn put(&self, trx: QueuedTrx) -> LocalBoxFuture<'_, Result<(), String>> {
+        let path = self.path(&trx.key);
+        let result = serde_json::to_vec(&trx)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                // written aside first, so a crash can not leave a truncated file behind
+                let tmp = path.with_extension("tmp");
+                std::fs::write(&tmp, json).map_err(|e| e.to_string())?;
+                std::fs::rename(&tmp, &path).map_err(|e| e.to_string())
+            });
+        Box::pin(async { result })
+    }
+
+    fn remove(&self, key: String) -> LocalBoxFuture<'_, Result<(), String>> {
+        let result = match std::fs::remove_file(self.path(&key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        };
+        Box::pin(async { result })
+    }
+
+    fn load(&self) -> LocalBoxFuture<'_, Result<Vec<QueuedTrx>, String>> {
+        let load = || -> Result<Vec<QueuedTrx>, String> {
+            let mut queued = Vec::new();
+            for entry in std::fs::read_dir(&self.dir).map_err(|e| e.to_string())? {
+                let path = entry.map_err(|e| e.to_string())?.path();
+                if path.extension().map_or(true, |ext| ext != "json") {
+                    continue;
+                }
+                let json = std::fs::read(&path).map_err(|e| e.to_string())?;
+                match serde_json::from_slice(&json) {
+                    Ok(trx) => queued.push(trx),
+                    Err(e) => log::error!("Skipping corrupt queued transaction {path:?}: {e}"),
+                }
+            }
+            Ok(queued)
+        };
+        let result = load();
+        Box::pin(async { result })
+    }
+}
+
+/// Transactions are written to the offline queue before they are committed, and removed once
+/// committed. Those whose commit could not complete, because the client went offline or the
+/// process ended, are committed again by `replay`.
+///
+/// Backends implementing `Batchable::applied_marker` commit a marker along with the last child
+/// batch of a transaction, so a transaction is not applied twice when the process ended right
+/// after it was committed. The marker is cleared by `Batchable::clear_applied` once the
+/// transaction is removed from the queue.
+///
+/// A transaction is claimed through `QueueStorage::claim` while it is committed, so the other
+/// processes sharing the storage, such as other tabs, do not replay it meanwhile.
+///
+/// A queued transaction is neither committed nor failed, so its post-commit and abort hooks
+/// are kept by the queue, and run once its replay commits or fails for good. They only run if
+/// the transaction is replayed by the process which queued it.
+#[derive(Clone)]
+pub struct OfflineQueue {
+    storage: Rc<dyn QueueStorage>,
+    /// Keys of the transactions which failed to commit, and are waiting for a replay
+    stranded: Rc<RefCell<HashSet<String>>>,
+    /// Keys of the transactions being committed, which must not be replayed meanwhile
+    in_flight: Rc<RefCell<HashSet<String>>>,
+    replaying: Rc<RefCell<HashSet<String>>>,
+    /// Claims on the transactions being committed or replayed by this process
+    claims: Rc<RefCell<HashMap<String, Claim>>>,
+    /// Terminal hooks of the transactions queued by this process
+    hooks: Rc<RefCell<HashMap<String, TerminalHooks>>>,
+}
+
+impl OfflineQueue {
+    pub fn new(storage: impl QueueStorage + 'static) -> Self {
+        Self {
+            storage: Rc::new(storage),
+            stranded: Default::default(),
+            in_flight: Default::default(),
+            replaying: Default::default(),
+            claims: Default::default(),
+            hooks: Default::default(),
+        }
+    }
+
+    /// Number of transactions waiting for a replay, as far as this queue knows
+    pub fn pending_replays(&self) -> usize {
+        self.stranded.borrow().len()
+    }
+
+    /// Save the transaction, return false if it could not be saved
+    pub(super) async fn enqueue<B: Batchable>(&self, key: &str, name: &str, ops: &[B::Op]) -> bool {
+        let Some(ops) = ops.iter().map(B::encode_op).collect::<Option<Vec<_>>>() else {
+            log::debug!("Transaction({name}) can not be queued, its commit is not durable");
+            return false;
+        };
+        // a replayed transaction was claimed already
+        if !self.claims.borrow().contains_key(key) {
+            match self.storage.claim(key.to_string()).await {
+                Ok(Some(claim)) => {
+                    self.claims.borrow_mut().insert(key.to_string(), claim);
+                }
+                Ok(None) => {
+                    log::warn!("Transaction({name}) is claimed by another process, not queuing it");
+                    return false;
+                }
+                Err(e) => {
+                    log::warn!("Transaction({name}) could not be claimed, not queuing it: {e}");
+                    return false;
+                }
+            }
+        }
+        let trx = QueuedTrx {
+            key: key.to_string(),
+            name: name.to_string(),
+            ops,
+            queued_at: epoch_millis(),
+        };
+        match self.storage.put(trx).await {
+            Ok(_) => {
+                self.in_flight.borrow_mut().insert(key.to_string());
+                true
+            }
+            Err(e) => {
+                self.claims.borrow_mut().remove(key);
+                log::warn!(
+                    "Transaction({name}) could not be queued, its commit is not durable: {e}"
+                );
+                false
+            }
+        }
+    }
+
+    /// Remove the transaction from the queue, then clear its applied marker if `applied`.
+    /// The marker is kept if the transaction could not be removed, so it is not replayed.
+    pub(super) async fn dequeue<B: Batchable>(&self, parent: &B, key: &str, applied: bool) {
+        self.stranded.borrow_mut().remove(key);
+        self.in_flight.borrow_mut().remove(key);
+        // released once the transaction is not queued anymore
+        let _claim = self.claims.borrow_mut().remove(key);
+        if let Err(e) = self.storage.remove(key.to_string()).await {
+            log::warn!("Transaction({key}) could not be removed from the offline queue: {e}");
+            return;
+        }
+        if !applied || B::applied_marker(key).is_none() {
+            return;
+        }
+        if let Err(e) = parent.clear_applied(key.to_string()).await {
+            log::warn!("Applied marker of Transaction({key}) could not be cleared: {e:?}");
+        }
+    }
+
+    pub(super) fn keep_hooks(&self, key: &str, hooks: TerminalHooks) {
+        self.hooks.borrow_mut().insert(key.to_string(), hooks);
+    }
+
+    pub(super) fn take_hooks(&self, key: &str) -> Option<TerminalHooks> {
+        self.hooks.borrow_mut().remove(key)
+    }
+
+    /// Run the hooks kept for the transaction `key`, which was committed already, or is dropped
+    async fn run_hooks(&self, key: &str, committed: bool) {
+        let Some(hooks) = self.take_hooks(key) else {
+            return;
+        };
+        let hooks = if committed {
+            hooks.post_commit
+        } else {
+            hooks.abort
+        };
+        join_all(hooks.into_iter().filter_map(|f| f())).await;
+    }
+
+    pub(super) fn strand(&self, key: &str) {
+        self.in_flight.borrow_mut().remove(key);
+        self.claims.borrow_mut().remove(key);
+        self.stranded.borrow_mut().insert(key.to_string());
+    }
+
+    /// Commit the queued transactions again, oldest first, and return the outcome of each
+    /// of them alongside its key. Transactions failing again with a transient error stay
+    /// queued, the others are removed. Transactions still being committed, by this process
+    /// or by another one sharing the storage, are skipped.
+    pub async fn replay<B: Batchable + Clone>(
+        &self,
+        parent: &B,
+    ) -> Vec<(String, Result<(), TrxApplyErr>)> {
+        let mut queued = match self.storage.load().await {
+            Ok(queued) => queued,
+            Err(e) => {
+                log::error!("Unable to load the offline queue: {e}");
+                return Vec::new();
+            }
+        };
+        queued.sort_by_key(|trx| trx.queued_at);
+
+        let mut outcomes = Vec::with_capacity(queued.len());
+        for trx in queued {
+            if self.in_flight.borrow().contains(&trx.key)
+                || !self.replaying.borrow_mut().insert(trx.key.clone())
+            {
+                continue;
+            }
+            let key = trx.key.clone();
+            let outcome = match self.storage.claim(key.clone()).await {
+                Ok(Some(claim)) => {
+                    self.claims.borrow_mut().insert(key.clone(), claim);
+                    self.replay_one(parent, trx).await
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    log::warn!("Transaction({key}) could not be claimed for a replay: {e}");
+                    None
+                }
+            };
+            self.claims.borrow_mut().remove(&key);
+            self.replaying.borrow_mut().remove(&key);
+            if let Some(outcome) = outcome {
+                outcomes.push((key, outcome));
+            }
+        }
+        outcomes
+    }
+
+    /// Replay `trx`, unless it was applied already
+    async fn replay_one<B: Batchable + Clone>(
+        &self,
+        parent: &B,
+        trx: QueuedTrx,
+    ) -> Option<Result<(), TrxApplyErr>> {
+        match parent.is_applied(trx.key.clone()).await {
+            Ok(false) => {}
+            Ok(true) => {
+                log::debug!("Transaction({}) was applied already", trx.key);
+                self.dequeue(parent, &trx.key, true).await;
+                self.run_hooks(&trx.key, true).await;
+                return None;
+            }
+            Err(e) => {
+                // it may be applied, or not, so it waits for the next replay
+                log::warn!("Transaction({}) can not be replayed yet: {e}", trx.key);
+                self.strand(&trx.key);
+                return None;
+            }
+        }
+
+        let Some(ops) = trx.ops.iter().map(|op| B::decode_op(op)).collect() else {
+            log::error!("Transaction({}) can not be decoded, dropping it", trx.key);
+            self.dequeue(parent, &trx.key, false).await;
+            self.run_hooks(&trx.key, false).await;
+            return None;
+        };
+        let transaction = Transaction::replayed(parent.clone(), &trx, ops, self.clone());
+        Some(transaction.apply().await.map(|_| ()))
+    }
+}
+
+/// Idempotency key of a transaction. Its random part tells apart the transactions of
+/// different processes or tabs, even when they start in the same millisecond.
+pub(super) fn idempotency_key(name: &str) -> String {
+    format!("{name}-{}-{:016x}", epoch_millis(), random_u64())
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use futures::executor::block_on;
+
+    use super::{FileQueue, QueueStorage, QueuedTrx};
+
+    #[test]
+    fn file_queue_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("trx-queue-{}", std::process::id()));
+        let queue = FileQueue::new(&dir).unwrap();
+        let trx = |key: &str, queued_at| QueuedTrx {
+            key: key.into(),
+            name: "tx/with/slashes".into(),
+            ops: vec!["op".into()],
+            queued_at,
+        };
+
+        block_on(async {
+            queue.put(trx("a", 1)).await.unwrap();
+            queue.put(trx("b", 2)).await.unwrap();
+            queue.put(trx("a", 3)).await.unwrap();
+            queue.remove("b".into()).await.unwrap();
+            queue.remove("missing".into()).await.unwrap();
+
+            assert_eq!(queue.load().await.unwrap(), [trx("a", 3)]);
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
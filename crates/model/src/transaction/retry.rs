@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+
+use super::TrxCommitErr;
+use crate::utils::random::random_unit;
+
+/// Source of time for the retry backoff, so it can be faked in tests
+pub trait Clock {
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'_, ()>;
+}
+
+/// Clock backed by `futures_timer`, for native targets
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default, Clone, Copy)]
+pub struct TimerClock;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Clock for TimerClock {
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'_, ()> {
+        Box::pin(futures_timer::Delay::new(duration))
+    }
+}
+
+/// How `Transaction::apply` retries a failed commit.
+///
+/// The n-th retry waits `initial_backoff * multiplier^(n-1)`, capped at `max_backoff`,
+/// from which up to a `jitter` fraction is randomly taken off.
+/// A commit which left documents unreverted is never retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of commit attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of the backoff, between 0 and 1, which is randomized
+    pub jitter: f64,
+    /// Which errors are worth retrying
+    pub retryable: fn(&TrxCommitErr) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retryable: |err| err.code().is_transient(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Commit only once
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Backoff before the retry following the `attempt`-th failed attempt, without jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exp);
+        Duration::from_secs_f64(secs.min(self.max_backoff.as_secs_f64()))
+    }
+
+    /// Delay before retrying after the `attempt`-th attempt failed with `err`,
+    /// or `None` if it should not be retried
+    pub fn delay(&self, attempt: u32, err: &TrxCommitErr) -> Option<Duration> {
+        if attempt >= self.max_attempts || matches!(err, TrxCommitErr::Unreverted { .. }) {
+            return None;
+        }
+        if !(self.retryable)(err) {
+            return None;
+        }
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_unit();
+        Some(self.backoff(attempt).mul_f64(1.0 - jitter))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+    use crate::transaction::{TrxCommitErr, TrxDbErr, TrxErrCode};
+
+    #[test]
+    fn exponential_backoff_with_jitter() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            ..Default::default()
+        };
+        let backoffs: Vec<u128> = (1..=6).map(|n| policy.backoff(n).as_millis()).collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000]);
+
+        let unavailable = TrxDbErr::new(TrxErrCode::Unavailable, "offline").into();
+        for attempt in 1..10 {
+            let delay = policy.delay(attempt, &unavailable).unwrap();
+            let backoff = policy.backoff(attempt);
+            assert!(delay <= backoff && delay >= backoff / 2);
+        }
+        assert_eq!(policy.delay(10, &unavailable), None);
+
+        let exists = TrxDbErr::new(TrxErrCode::AlreadyExists, "exists").into();
+        assert_eq!(policy.delay(1, &exists), None);
+
+        let unreverted = TrxCommitErr::Unreverted {
+            source: Box::new(unavailable),
+            unrestored: vec!["vertex/a".into()],
+        };
+        assert_eq!(policy.delay(1, &unreverted), None);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{mem, ops::Deref, rc::Rc, sync::Arc};
+
+use futures::future::Abortable;
+
+use super::{
+    BatchTrxOpErr, Batchable, Inner, Open, TrxApplyErr, TrxCheckErr, TrxErrCode, TrxEvent,
+    TrxHandle, TrxState,
+};
+
+/// A child transaction, whose ops, extras, hooks and scheduled futures are merged into its
+/// parent transaction by `release`, or discarded by `rollback`.
+/// Either way, only the parent transaction is committed.
+///
+/// It derefs to a `TrxHandle`, so it can be used wherever ops are added to a transaction.
+/// A savepoint dropped without being released is discarded, without running its abort hooks.
+///
+/// Pre-commit hooks are sync, so a savepoint opened in one is released from an op it
+/// schedules on the parent, e.g. `trx.add_future_op(async move { savepoint.release().await })`.
+/// That op runs before the parent commits, and the parent waits for it like any other op.
+pub struct Savepoint<B: Batchable> {
+    parent: TrxHandle<B, Open>,
+    handle: TrxHandle<B, Open>,
+}
+
+impl<B: Batchable> TrxHandle<B, Open> {
+    /// Open a savepoint on top of the ops added to this transaction so far
+    pub fn savepoint(&self) -> Savepoint<B> {
+        let parent = &self.inner;
+        let name = format!("{}/savepoint", parent.name);
+        let extras = B::savepoint_extras(&parent.extras);
+        let inner = Inner::new(&name, parent.sink.clone(), Rc::new(extras));
+        let mut savepoints = parent.savepoints.try_lock().unwrap();
+        savepoints.retain(|savepoint| savepoint.strong_count() > 0);
+        savepoints.push(Arc::downgrade(&inner));
+        drop(savepoints);
+        if parent.cancel.is_aborted() {
+            inner.interrupt();
+        }
+        Savepoint {
+            parent: self.clone_casted(),
+            handle: TrxHandle::new(inner),
+        }
+    }
+}
+
+impl<B: Batchable> Deref for Savepoint<B> {
+    type Target = TrxHandle<B, Open>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl<B: Batchable> Savepoint<B> {
+    /// Run the scheduled ops and pre-commit hooks of the savepoint, then merge its ops and
+    /// remaining hooks into the parent transaction.
+    /// On failure nothing is merged, the abort hooks of the savepoint run, and the parent
+    /// transaction is left as it was.
+    ///
+    /// It is interrupted when the savepoint or its parent is cancelled, or when the parent
+    /// runs past its timeout while being applied.
+    pub async fn release(self) -> Result<(), TrxApplyErr> {
+        let inner = &self.handle.inner;
+        let Some(registration) = inner.cancel_registration.try_lock().unwrap().take() else {
+            return Err(TrxCheckErr::ClosedForAddingOps)?;
+        };
+        inner.state.set(TrxState::Preparing);
+
+        let result = match Abortable::new(inner.prepare(), registration).await {
+            // an op may fail because the savepoint was interrupted under its feet
+            Ok(Ok(_)) if inner.cancel.is_aborted() => Err(self.interrupted()),
+            Ok(Ok(_)) => inner
+                .merge_into(&self.parent.inner)
+                .map_err(TrxApplyErr::from),
+            Ok(Err(err)) => Err(err),
+            Err(_aborted) => Err(self.interrupted()),
+        };
+        match &result {
+            Ok(_) => {
+                inner.state.set(TrxState::Committed);
+            }
+            Err(err) => inner.abort(err.code()).await,
+        }
+        result
+    }
+
+    fn interrupted(&self) -> TrxApplyErr {
+        match self.parent.inner.timed_out.get() {
+            Some(timeout) => TrxApplyErr::TimedOut(timeout),
+            None => TrxApplyErr::Cancelled,
+        }
+    }
+
+    /// Discard the savepoint and run its abort hooks
+    pub async fn rollback(self) {
+        self.handle.inner.abort(TrxErrCode::Cancelled).await;
+    }
+}
+
+impl<B: Batchable> Inner<B> {
+    /// Move the ops, extras, terminal hooks and warnings of a prepared savepoint into `parent`
+    fn merge_into(&self, parent: &Inner<B>) -> Result<(), BatchTrxOpErr> {
+        parent.check_status()?;
+        let Ok(mut parent_ops) = parent.ops.try_lock() else {
+            return Err(BatchTrxOpErr::ClosedForAddingOps);
+        };
+
+        let ops = mem::take(&mut *self.ops.try_lock().unwrap());
+        let merged = ops.len();
+        parent_ops.extend(ops);
+        parent.counter.set(parent_ops.len());
+        drop(parent_ops);
+        B::release_extras(&self.extras, &parent.extras);
+
+        let hooks = [
+            (&self.on_commit_hooks, &parent.on_commit_hooks),
+            (&self.on_post_commit_hooks, &parent.on_post_commit_hooks),
+            (&self.on_abort_hooks, &parent.on_abort_hooks),
+        ];
+        for (child, parent) in hooks {
+            let callbacks = mem::take(&mut *child.try_lock().unwrap());
+            parent.try_lock().unwrap().extend(callbacks);
+        }
+        let callbacks = mem::take(&mut *self.on_retry_hooks.try_lock().unwrap());
+        parent.on_retry_hooks.try_lock().unwrap().extend(callbacks);
+
+        // its pre-commit hooks ran as part of the parent transaction
+        let summary = mem::take(&mut *self.summary.try_lock().unwrap());
+        let mut parent_summary = parent.summary.try_lock().unwrap();
+        parent_summary.hooks.pre_commit += summary.hooks.pre_commit;
+        parent_summary.warnings.extend(summary.warnings);
+        drop(parent_summary);
+
+        self.trace(TrxEvent::Released {
+            into: parent.name.clone(),
+            ops: merged,
+        });
+        Ok(())
+    }
+}
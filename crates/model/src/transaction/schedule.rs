@@ -0,0 +1,288 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    fmt::{self, Display},
+};
+
+use super::TrxOppFuture;
+
+thread_local! {
+    static OP_COUNTER: Cell<u32> = Cell::new(0);
+}
+
+/// Identifies an op scheduled in a transaction, so that other ops can depend on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OpId(u32);
+
+impl OpId {
+    fn next() -> Self {
+        OP_COUNTER.with(|cell| {
+            let current = cell.get();
+            cell.set(current + 1);
+            OpId(current)
+        })
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        self.0
+    }
+
+    pub fn from_u32(id: u32) -> Self {
+        OpId(id)
+    }
+}
+
+impl Display for OpId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleErr {
+    /// The op was not scheduled in this transaction
+    UnknownOp(OpId),
+    /// The op already started, so it can not wait on another op anymore
+    Started(OpId),
+    /// Ops which would wait on each other, the first one being repeated last
+    Cycle(Vec<OpId>),
+}
+
+impl Display for ScheduleErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleErr::UnknownOp(id) => write!(f, "Op {id} is not scheduled in this transaction"),
+            ScheduleErr::Started(id) => write!(f, "Op {id} already started"),
+            ScheduleErr::Cycle(ids) => {
+                let ids: Vec<String> = ids.iter().map(OpId::to_string).collect();
+                write!(f, "Ops depend on each other: {}", ids.join(" -> "))
+            }
+        }
+    }
+}
+impl Error for ScheduleErr {}
+
+/// Scheduled ops of a transaction, alongside the ops each of them waits on.
+///
+/// Ops run in rounds: a round starts every op whose dependencies all ran in previous rounds,
+/// in the order they were scheduled, and the next round starts once they all completed.
+/// Deferred ops run in a round of their own, once no other op is left.
+#[derive(Default)]
+pub(super) struct Schedule {
+    /// Ops not started yet, ordered by id, that is in the order they were scheduled
+    pending: BTreeMap<OpId, (Vec<OpId>, TrxOppFuture<'static>)>,
+    /// Ops started in previous rounds
+    started: HashSet<OpId>,
+    /// Deferred ops not started yet, in the order they were deferred
+    deferred: Vec<(String, TrxOppFuture<'static>)>,
+    /// Keys of every op deferred so far, started or not
+    deferred_keys: HashSet<String>,
+}
+
+impl Schedule {
+    pub(super) fn add(
+        &mut self,
+        after: &[OpId],
+        future: TrxOppFuture<'static>,
+    ) -> Result<OpId, ScheduleErr> {
+        if let Some(unknown) = after.iter().find(|id| !self.is_known(id)) {
+            return Err(ScheduleErr::UnknownOp(*unknown));
+        }
+        let id = OpId::next();
+        self.pending.insert(id, (after.to_vec(), future));
+        Ok(id)
+    }
+
+    /// Make `op` wait on `on`, unless `on` already waits on `op`
+    pub(super) fn add_dependency(&mut self, op: OpId, on: OpId) -> Result<(), ScheduleErr> {
+        if !self.is_known(&on) {
+            return Err(ScheduleErr::UnknownOp(on));
+        }
+        if !self.pending.contains_key(&op) {
+            return Err(match self.started.contains(&op) {
+                true => ScheduleErr::Started(op),
+                false => ScheduleErr::UnknownOp(op),
+            });
+        }
+        if let Some(mut path) = self.path(on, op) {
+            path.insert(0, op);
+            return Err(ScheduleErr::Cycle(path));
+        }
+
+        let (deps, _) = self.pending.get_mut(&op).unwrap();
+        deps.push(on);
+        Ok(())
+    }
+
+    /// Take the ops of the next round, if any op is left
+    pub(super) fn take_ready(&mut self) -> Result<Option<Vec<TrxOppFuture<'static>>>, ScheduleErr> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+
+        let ready: Vec<OpId> = self
+            .pending
+            .iter()
+            .filter(|(_, (deps, _))| deps.iter().all(|dep| self.started.contains(dep)))
+            .map(|(id, _)| *id)
+            .collect();
+        if ready.is_empty() {
+            // dependencies are checked as they are added, so this is only a safety net
+            return Err(ScheduleErr::Cycle(self.pending.keys().copied().collect()));
+        }
+
+        let mut ops = Vec::with_capacity(ready.len());
+        for id in ready {
+            let (_, future) = self.pending.remove(&id).unwrap();
+            self.started.insert(id);
+            ops.push(future);
+        }
+        Ok(Some(ops))
+    }
+
+    /// Defer an op, unless an op was already deferred with the same key.
+    /// Return false if the op was dropped.
+    pub(super) fn defer(&mut self, key: String, future: TrxOppFuture<'static>) -> bool {
+        if !self.deferred_keys.insert(key.clone()) {
+            return false;
+        }
+        self.deferred.push((key, future));
+        true
+    }
+
+    /// Take the deferred ops, once every other op started
+    pub(super) fn take_deferred(&mut self) -> Option<Vec<TrxOppFuture<'static>>> {
+        if !self.pending.is_empty() || self.deferred.is_empty() {
+            return None;
+        }
+        Some(self.deferred.drain(..).map(|(_, future)| future).collect())
+    }
+
+    /// Drop the ops which did not start yet
+    pub(super) fn clear(&mut self) {
+        self.pending.clear();
+        self.deferred.clear();
+    }
+
+    fn is_known(&self, id: &OpId) -> bool {
+        self.pending.contains_key(id) || self.started.contains(id)
+    }
+
+    /// Dependency path from `from` to `to` among the pending ops, both included
+    fn path(&self, from: OpId, to: OpId) -> Option<Vec<OpId>> {
+        if from == to {
+            return Some(vec![to]);
+        }
+        let (deps, _) = self.pending.get(&from)?;
+        deps.iter().find_map(|dep| {
+            let mut path = self.path(*dep, to)?;
+            path.insert(0, from);
+            Some(path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{executor::block_on, future::join_all};
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{OpId, Schedule, ScheduleErr};
+
+    fn record(schedule: &mut Schedule, log: &Rc<RefCell<Vec<u8>>>, n: u8, after: &[OpId]) -> OpId {
+        let log = log.clone();
+        let future = Box::pin(async move {
+            log.borrow_mut().push(n);
+            Ok(())
+        });
+        schedule.add(after, future).unwrap()
+    }
+
+    #[test]
+    fn rounds_follow_dependencies() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut schedule = Schedule::default();
+
+        let property = record(&mut schedule, &log, 3, &[]);
+        let vertex = record(&mut schedule, &log, 1, &[]);
+        let edge = record(&mut schedule, &log, 4, &[vertex]);
+        record(&mut schedule, &log, 2, &[]);
+        schedule.add_dependency(property, vertex).unwrap();
+        record(&mut schedule, &log, 5, &[edge, property]);
+
+        let mut rounds = Vec::new();
+        while let Some(ops) = schedule.take_ready().unwrap() {
+            rounds.push(ops.len());
+            block_on(join_all(ops));
+        }
+        assert_eq!(rounds, [2, 2, 1]);
+        assert_eq!(*log.borrow(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn deferred_ops_run_last_once_per_key() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut schedule = Schedule::default();
+        let defer = |schedule: &mut Schedule, key: &str, n: u8| {
+            let log = log.clone();
+            let future = Box::pin(async move {
+                log.borrow_mut().push(n);
+                Ok(())
+            });
+            schedule.defer(key.into(), future)
+        };
+
+        assert!(defer(&mut schedule, "save", 3));
+        let first = record(&mut schedule, &log, 1, &[]);
+        assert!(!defer(&mut schedule, "save", 4));
+        assert!(defer(&mut schedule, "keywords", 5));
+        record(&mut schedule, &log, 2, &[first]);
+
+        let mut rounds = Vec::new();
+        loop {
+            let ops = match schedule.take_ready().unwrap() {
+                Some(ops) => ops,
+                None => match schedule.take_deferred() {
+                    Some(ops) => ops,
+                    None => break,
+                },
+            };
+            rounds.push(ops.len());
+            block_on(join_all(ops));
+        }
+        assert_eq!(rounds, [1, 1, 2]);
+        assert_eq!(*log.borrow(), [1, 2, 3, 5]);
+
+        // the key stays reserved once its op started
+        assert!(!defer(&mut schedule, "save", 6));
+    }
+
+    #[test]
+    fn reject_cycles() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut schedule = Schedule::default();
+
+        let a = record(&mut schedule, &log, 1, &[]);
+        let b = record(&mut schedule, &log, 2, &[a]);
+        let c = record(&mut schedule, &log, 3, &[b]);
+
+        assert_eq!(
+            schedule.add_dependency(a, c),
+            Err(ScheduleErr::Cycle(vec![a, c, b, a]))
+        );
+        assert_eq!(
+            schedule.add_dependency(a, a),
+            Err(ScheduleErr::Cycle(vec![a, a]))
+        );
+
+        let unknown = OpId::from_u32(u32::MAX);
+        assert_eq!(
+            schedule.add_dependency(c, unknown),
+            Err(ScheduleErr::UnknownOp(unknown))
+        );
+
+        let _ = schedule.take_ready().unwrap();
+        assert_eq!(schedule.add_dependency(a, c), Err(ScheduleErr::Started(a)));
+    }
+}
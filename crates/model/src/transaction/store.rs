@@ -1,15 +1,114 @@
-use super::{Batchable, Transaction, TrxHandle, TrxRef, Unknown};
-use std::{cell::RefCell, fmt::Debug, sync::Arc};
+use super::{
+    utils::{millis, optional_millis},
+    Batchable, TraceSink, Transaction, TrxErrCode, TrxEvent, TrxHandle, TrxRef, TrxState, Unknown,
+};
+use observable_rs::{Observable, Reader};
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt::Debug,
+    rc::Rc,
+    sync::{Arc, Weak},
+    time::Duration,
+};
 
+/// Number of finished transactions kept by `ActiveTrxStore` by default
+pub const TRX_HISTORY_LIMIT: usize = 50;
+
+/// Lifecycle of a transaction, as recorded by `ActiveTrxStore`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrxRecord {
+    /// Id of the transaction, see `TrxHandle::id`
+    pub id: u32,
+    pub name: String,
+    pub state: TrxState,
+    /// When the transaction was added to the store, as measured by its trace sink
+    #[serde(serialize_with = "millis")]
+    pub started_at: Duration,
+    /// Time spent committing, once committed
+    #[serde(serialize_with = "optional_millis")]
+    pub commit_duration: Option<Duration>,
+    pub ops: usize,
+    /// Why the transaction failed, `closed` if it was dropped without being applied
+    pub failure: Option<TrxErrCode>,
+    #[serde(skip)]
+    commit_started_at: Option<Duration>,
+}
+
+impl TrxRecord {
+    fn new(id: u32, name: &str, started_at: Duration) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            state: TrxState::Pending,
+            started_at,
+            commit_duration: None,
+            ops: 0,
+            failure: None,
+            commit_started_at: None,
+        }
+    }
+
+    /// Follow `event`, and return true once the transaction finished
+    fn update(&mut self, event: &TrxEvent, now: Duration) -> bool {
+        match event {
+            TrxEvent::OpBatched { count, .. } => self.ops = *count,
+            TrxEvent::ApplyStart { ops } => {
+                self.state = TrxState::Preparing;
+                self.ops = *ops;
+            }
+            TrxEvent::CommitStart { .. } => {
+                self.state = TrxState::Committing;
+                self.commit_started_at.get_or_insert(now);
+            }
+            TrxEvent::Applied { ops, .. } => {
+                self.state = TrxState::Committed;
+                self.ops = *ops;
+                self.commit_duration = self.commit_started_at.map(|t| now.saturating_sub(t));
+                return true;
+            }
+            TrxEvent::Aborted { code } => {
+                self.state = TrxState::Failed;
+                self.failure = Some(*code);
+                return true;
+            }
+            TrxEvent::Queued { .. } => {
+                self.state = TrxState::Queued;
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Transactions not finished yet, and the most recently finished ones, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TrxActivity {
+    pub active: Vec<TrxRecord>,
+    pub history: Vec<TrxRecord>,
+}
+
+/// Registry of the transactions of the application, following their lifecycle through their
+/// trace events. Finished transactions move to a bounded history, as do transactions dropped
+/// without being applied, which are recorded as failed with `TrxErrCode::Closed`.
 pub struct ActiveTrxStore<B: Batchable>(Arc<Inner<B>>);
 struct Inner<B: Batchable> {
-    set: RefCell<Vec<TrxRef<B>>>,
+    set: RefCell<Vec<(TrxRef<B>, TrxRecord)>>,
+    history: RefCell<VecDeque<TrxRecord>>,
+    history_limit: usize,
+    activity: Observable<TrxActivity>,
 }
 
 impl<B: Batchable> Default for Inner<B> {
     fn default() -> Self {
         Self {
             set: Default::default(),
+            history: Default::default(),
+            history_limit: TRX_HISTORY_LIMIT,
+            activity: Default::default(),
         }
     }
 }
@@ -37,22 +136,58 @@ impl<B: Batchable> Default for ActiveTrxStore<B> {
     }
 }
 
-impl<B: Batchable> ActiveTrxStore<B> {
+/// Forwards the trace events of a transaction to its store
+struct StoreSink<B: Batchable> {
+    store: Weak<Inner<B>>,
+    /// Trace sink of the transaction, which measures the time
+    clock: Rc<dyn TraceSink>,
+}
+
+impl<B: Batchable> TraceSink for StoreSink<B> {
+    fn record(&self, id: u32, _trx: &str, event: &TrxEvent) {
+        if let Some(inner) = self.store.upgrade() {
+            ActiveTrxStore(inner).on_event(id, event, self.clock.now());
+        }
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.now()
+    }
+}
+
+impl<B: Batchable + 'static> ActiveTrxStore<B> {
     pub fn add(&self, transaction: &Transaction<B>) {
-        self.0.set.borrow_mut().push(transaction.get_ref());
+        let clock = transaction.handle.inner.sink.clone();
+        let record = TrxRecord::new(transaction.id(), transaction.name(), clock.now());
+        self.0
+            .set
+            .borrow_mut()
+            .push((transaction.get_ref(), record));
+        transaction.add_trace_sink(Rc::new(StoreSink {
+            store: Arc::downgrade(&self.0),
+            clock,
+        }));
+        self.notify();
     }
+}
 
+impl<B: Batchable> ActiveTrxStore<B> {
+    /// Keep at most `limit` finished transactions
+    pub fn with_history_limit(limit: usize) -> Self {
+        Self(Arc::new(Inner {
+            history_limit: limit,
+            ..Default::default()
+        }))
+    }
+
+    /// Stop following the transaction named `id`, which moves to the history as last seen,
+    /// along with the transactions which were dropped
     pub fn remove_by_id(&self, id: &str) {
-        let mut set = self.0.set.borrow_mut();
-        set.retain(|weak| match weak.upgrade() {
-            Ok(trx) => trx.name() == id,
-            Err(_) => false,
-        })
+        self.retain(|trx, record| trx.upgrade().is_ok() && record.name != id);
     }
 
     pub fn clean_inactive(&self) {
-        let mut set = self.0.set.borrow_mut();
-        set.retain(|weak| weak.upgrade().is_ok())
+        self.retain(|trx, _| trx.upgrade().is_ok());
     }
 
     pub fn count(&self) -> u32 {
@@ -60,16 +195,196 @@ impl<B: Batchable> ActiveTrxStore<B> {
         set.len() as u32
     }
 
-    pub fn map<T>(&self, mut f: impl FnMut(&TrxHandle<B, Unknown>) -> T) -> Vec<T> {
-        let mut set = self.0.set.borrow_mut();
-        let mut list = Vec::new();
-        set.retain(|weak: &TrxRef<B>| match weak.upgrade() {
-            Ok(trx) => {
-                list.push(f(&trx));
-                true
+    /// Call `f` with each transaction still alive, moving the dropped ones to the history
+    pub fn map<T>(&self, f: impl FnMut(&TrxHandle<B, Unknown>) -> T) -> Vec<T> {
+        self.clean_inactive();
+        // `f` may add ops, tracing events back into the store
+        let handles: Vec<_> = self
+            .0
+            .set
+            .borrow()
+            .iter()
+            .filter_map(|(trx, _)| trx.upgrade().ok())
+            .collect();
+        handles.iter().map(f).collect()
+    }
+
+    pub fn snapshot(&self) -> TrxActivity {
+        TrxActivity {
+            active: self.0.set.borrow().iter().map(|(_, r)| r.clone()).collect(),
+            history: self.0.history.borrow().iter().cloned().collect(),
+        }
+    }
+
+    /// Updated whenever a transaction is added, changes state or finishes
+    pub fn activity(&self) -> Reader<TrxActivity> {
+        self.0.activity.reader()
+    }
+
+    /// Move the transactions rejected by `keep` to the history.
+    /// Those dropped before they finished are recorded as failed with `TrxErrCode::Closed`.
+    fn retain(&self, mut keep: impl FnMut(&TrxRef<B>, &TrxRecord) -> bool) {
+        // `keep` runs without the set borrowed, as it may trace events back into the store
+        let entries = self.0.set.borrow().clone();
+        let rejected: Vec<u32> = entries
+            .iter()
+            .filter(|(trx, record)| !keep(trx, record))
+            .map(|(_, record)| record.id)
+            .collect();
+        if rejected.is_empty() {
+            return;
+        }
+
+        let mut removed = Vec::new();
+        self.0.set.borrow_mut().retain(|(trx, record)| {
+            let kept = !rejected.contains(&record.id);
+            if !kept {
+                removed.push((trx.upgrade().is_err(), record.clone()));
             }
-            Err(_) => false,
+            kept
         });
-        list
+        for (dropped, mut record) in removed {
+            if dropped {
+                record.failure = Some(TrxErrCode::Closed);
+            }
+            self.archive(record);
+        }
+        self.notify();
+    }
+
+    /// Follow `event` of the transaction `id`, notifying the activity only if the transaction
+    /// changed state, as ops are batched too often for each of them to be notified
+    fn on_event(&self, id: u32, event: &TrxEvent, now: Duration) {
+        let mut set = self.0.set.borrow_mut();
+        let Some(i) = set.iter().position(|(_, record)| record.id == id) else {
+            return;
+        };
+        let state = set[i].1.state;
+        if set[i].1.update(event, now) {
+            let (_, record) = set.remove(i);
+            drop(set);
+            self.archive(record);
+        } else if set[i].1.state == state {
+            return;
+        } else {
+            drop(set);
+        }
+        self.notify();
+    }
+
+    fn archive(&self, record: TrxRecord) {
+        let mut history = self.0.history.borrow_mut();
+        history.push_back(record);
+        while history.len() > self.0.history_limit {
+            history.pop_front();
+        }
+    }
+
+    fn notify(&self) {
+        self.0.activity.set(self.snapshot());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{rc::Rc, time::Duration};
+
+    use super::{ActiveTrxStore, TrxRecord, TrxState};
+    use crate::{
+        db::memory::{Document, MemoryDb, MemoryTrxOp},
+        transaction::{MemorySink, Precondition, Transaction, TrxErrCode},
+    };
+
+    fn states(records: &[TrxRecord]) -> Vec<(TrxState, Option<TrxErrCode>)> {
+        records.iter().map(|r| (r.state, r.failure)).collect()
+    }
+
+    #[tokio::test]
+    async fn store_follows_transaction_lifecycle() {
+        let db = MemoryDb::new();
+        let sink = Rc::new(MemorySink::new());
+        let store = ActiveTrxStore::with_history_limit(2);
+        let trx = |name| {
+            let transaction = Transaction::with_trace_sink(db.clone(), name, sink.clone());
+            store.add(&transaction);
+            transaction
+        };
+        let write = |path: &str| MemoryTrxOp::Update {
+            path: path.into(),
+            data: Document::new(),
+            precondition: Some(Precondition::Exists),
+        };
+
+        let committed = trx("committed");
+        committed
+            .process_op(MemoryTrxOp::Insert {
+                path: "vertex/a".into(),
+                data: Document::new(),
+            })
+            .unwrap();
+        sink.advance(Duration::from_millis(5));
+        let failed = trx("failed");
+        failed.process_op(write("vertex/missing")).unwrap();
+        let leaked = trx("leaked");
+        let removed = trx("removed");
+
+        let activity = store.snapshot();
+        assert_eq!(activity.active.len(), 4);
+        assert_eq!(activity.active[0].id, committed.id());
+        assert_eq!(activity.active[0].ops, 1);
+        assert_eq!(activity.active[1].started_at, Duration::from_millis(5));
+
+        // only the removed transaction leaves the store, as it was last seen
+        store.remove_by_id(removed.name());
+        assert_eq!(store.count(), 3);
+        let history = store.snapshot().history;
+        assert_eq!(states(&history), [(TrxState::Pending, None)]);
+
+        committed.apply().await.unwrap();
+        let history = store.snapshot().history;
+        assert_eq!(history[1].state, TrxState::Committed);
+        assert_eq!(history[1].commit_duration, Some(Duration::ZERO));
+
+        assert!(failed.apply().await.is_err());
+        drop(leaked);
+        store.clean_inactive();
+
+        let activity = store.snapshot();
+        assert!(activity.active.is_empty());
+        assert_eq!(
+            states(&activity.history),
+            [
+                (TrxState::Failed, Some(TrxErrCode::FailedPrecondition)),
+                (TrxState::Pending, Some(TrxErrCode::Closed)),
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn map_callbacks_can_add_ops() {
+        let db = MemoryDb::new();
+        let store = ActiveTrxStore::default();
+        let transaction = db.trx("mapped");
+        store.add(&transaction);
+        let leaked = db.trx("leaked");
+        store.add(&leaked);
+        drop(leaked);
+
+        let names = store.map(|trx| {
+            let txh = trx.checked().unwrap();
+            txh.process_op(MemoryTrxOp::Insert {
+                path: "vertex/a".into(),
+                data: Document::new(),
+            })
+            .unwrap();
+            trx.name().to_string()
+        });
+        assert_eq!(names, [transaction.name()]);
+
+        let activity = store.snapshot();
+        assert_eq!(activity.active[0].ops, 1);
+        assert_eq!(
+            states(&activity.history),
+            [(TrxState::Pending, Some(TrxErrCode::Closed))]
+        );
     }
 }
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::{utils::millis, HookKind};
+
+/// Number of hooks run by a transaction, per kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrxHooksRun {
+    pub pre_commit: usize,
+    pub on_commit: usize,
+    pub post_commit: usize,
+}
+
+impl TrxHooksRun {
+    pub(super) fn add(&mut self, kind: HookKind, count: usize) {
+        match kind {
+            HookKind::PreCommit => self.pre_commit += count,
+            HookKind::OnCommit => self.on_commit += count,
+            HookKind::PostCommit => self.post_commit += count,
+            // a transaction which ran its abort hooks returns an error instead
+            HookKind::Abort => {}
+        }
+    }
+}
+
+/// What `Transaction::apply` did, for telemetry
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrxSummary {
+    pub name: String,
+    /// Ops committed, once coalesced
+    pub ops: usize,
+    /// Child batches the ops were committed in
+    pub batches: usize,
+    /// Time spent running the scheduled ops and pre-commit hooks
+    #[serde(serialize_with = "millis")]
+    pub ops_duration: Duration,
+    /// Time spent committing, retries included
+    #[serde(serialize_with = "millis")]
+    pub commit_duration: Duration,
+    pub hooks: TrxHooksRun,
+    /// Non-fatal issues, reported with `TrxHandle::add_warning`
+    pub warnings: Vec<String>,
+}
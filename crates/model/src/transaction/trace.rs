@@ -0,0 +1,241 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use super::TrxErrCode;
+use crate::utils::clock;
+
+/// Kind of hooks dispatched by a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    PreCommit,
+    OnCommit,
+    PostCommit,
+    Abort,
+}
+
+/// Structured audit event in the lifecycle of a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrxEvent {
+    Created,
+    /// An op was added, `count` being the number of ops in the transaction so far
+    OpBatched {
+        op: String,
+        count: usize,
+    },
+    ApplyStart {
+        ops: usize,
+    },
+    /// A round of scheduled ops is being awaited
+    OpsDispatched {
+        count: usize,
+    },
+    HooksDispatched {
+        kind: HookKind,
+        count: usize,
+    },
+    CommitStart {
+        ops: usize,
+        batches: usize,
+    },
+    /// A commit attempt completed, `error` being set if it failed
+    CommitEnd {
+        ops: usize,
+        batches: usize,
+        duration: Duration,
+        error: Option<TrxErrCode>,
+    },
+    Retrying {
+        attempt: u32,
+        delay: Duration,
+    },
+    Reverting {
+        batches: usize,
+    },
+    Applied {
+        ops: usize,
+        duration: Duration,
+    },
+    Aborted {
+        code: TrxErrCode,
+    },
+    /// The commit failed, and the transaction waits in the offline queue to be replayed
+    Queued {
+        key: String,
+    },
+    /// A dry run planned the ops, instead of committing them
+    Planned {
+        ops: usize,
+        batches: usize,
+    },
+    /// A savepoint merged its ops into the transaction `into`
+    Released {
+        into: String,
+        ops: usize,
+    },
+}
+
+impl TrxEvent {
+    /// Verbosity level of the event, the lower the more important
+    pub fn level(&self) -> u8 {
+        match self {
+            TrxEvent::Created
+            | TrxEvent::OpBatched { .. }
+            | TrxEvent::ApplyStart { .. }
+            | TrxEvent::CommitEnd { .. }
+            | TrxEvent::Retrying { .. }
+            | TrxEvent::Reverting { .. }
+            | TrxEvent::Queued { .. }
+            | TrxEvent::Planned { .. } => 2,
+            TrxEvent::OpsDispatched { .. }
+            | TrxEvent::HooksDispatched { .. }
+            | TrxEvent::CommitStart { .. }
+            | TrxEvent::Applied { .. }
+            | TrxEvent::Aborted { .. }
+            | TrxEvent::Released { .. } => 4,
+        }
+    }
+}
+
+impl Display for TrxEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrxEvent::Created => write!(f, "CREATED"),
+            TrxEvent::OpBatched { op, count } => {
+                write!(f, "DBAUDIT: batch_operation #{count} {op}")
+            }
+            TrxEvent::ApplyStart { ops } => write!(f, "DBAUDIT: .apply() {ops} statements"),
+            TrxEvent::OpsDispatched { count } => write!(f, "DBAUDIT: in ops ({count})"),
+            TrxEvent::HooksDispatched { kind, count } => {
+                write!(f, "apply (dispatching {count} {kind:?} hooks)")
+            }
+            TrxEvent::CommitStart { ops, batches } => {
+                write!(
+                    f,
+                    "apply (committing {ops} statements in {batches} batches)"
+                )
+            }
+            TrxEvent::CommitEnd {
+                ops,
+                duration,
+                error: None,
+                ..
+            } => write!(f, "DBAUDIT: .commit() {ops} statements in {duration:?}"),
+            TrxEvent::CommitEnd {
+                ops,
+                duration,
+                error: Some(code),
+                ..
+            } => write!(
+                f,
+                "DBAUDIT: .commit() {ops} statements failed({code}) in {duration:?}"
+            ),
+            TrxEvent::Retrying { attempt, delay } => {
+                write!(
+                    f,
+                    "DBAUDIT: commit attempt {attempt} failed, retrying in {delay:?}"
+                )
+            }
+            TrxEvent::Reverting { batches } => {
+                write!(f, "DBAUDIT: reverting {batches} committed batches")
+            }
+            TrxEvent::Applied { ops, duration } => {
+                write!(f, ".apply() ({ops} statements committed in {duration:?})")
+            }
+            TrxEvent::Aborted { code } => write!(f, "apply (aborted: {code})"),
+            TrxEvent::Queued { key } => write!(f, "DBAUDIT: commit queued for replay ({key})"),
+            TrxEvent::Planned { ops, batches } => {
+                write!(
+                    f,
+                    "DBAUDIT: dry run ({ops} statements in {batches} batches)"
+                )
+            }
+            TrxEvent::Released { into, ops } => {
+                write!(f, "savepoint (released {ops} statements into {into})")
+            }
+        }
+    }
+}
+
+/// Destination of the audit events of transactions
+pub trait TraceSink {
+    /// Whether events of `level` are recorded at all
+    fn enabled(&self, _level: u8) -> bool {
+        true
+    }
+
+    /// Record `event` of the transaction `id`, named `trx`
+    fn record(&self, id: u32, trx: &str, event: &TrxEvent);
+
+    /// Monotonic time, used to measure durations
+    fn now(&self) -> Duration;
+}
+
+/// Sink writing every event to `log::debug`
+#[derive(Default, Clone, Copy)]
+pub struct LogSink;
+
+impl TraceSink for LogSink {
+    fn enabled(&self, _level: u8) -> bool {
+        log::log_enabled!(log::Level::Debug)
+    }
+
+    fn record(&self, _id: u32, trx: &str, event: &TrxEvent) {
+        log::debug!("level({}): {trx}: {event}", event.level());
+    }
+
+    fn now(&self) -> Duration {
+        clock::now()
+    }
+}
+
+/// Sink keeping the events in memory, so tests can assert on them.
+/// Its time only moves forward with `advance`.
+#[derive(Default)]
+pub struct MemorySink {
+    events: RefCell<Vec<(String, TrxEvent)>>,
+    now: Cell<Duration>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Events recorded so far, alongside the name of their transaction
+    pub fn events(&self) -> Vec<(String, TrxEvent)> {
+        self.events.borrow().clone()
+    }
+
+    /// Events recorded for the transaction `trx`
+    pub fn events_of(&self, trx: &str) -> Vec<TrxEvent> {
+        let events = self.events.borrow();
+        events
+            .iter()
+            .filter(|(name, _)| name == trx)
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl TraceSink for MemorySink {
+    fn record(&self, _id: u32, trx: &str, event: &TrxEvent) {
+        self.events
+            .borrow_mut()
+            .push((trx.to_string(), event.clone()));
+    }
+
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
@@ -1,11 +1,27 @@
-use std::{ops::Deref, task::Poll};
+use std::{ops::Deref, task::Poll, time::Duration};
 
 use futures::{future::MaybeDone, lock::Mutex, Future, FutureExt};
+use serde::Serializer;
 
 pub fn intersection<T: PartialEq<T>>(a: &[T], b: &[T]) -> bool {
     a.iter().any(|x| b.contains(x))
 }
 
+/// Serialize a duration as fractional milliseconds, as JS measures time
+pub(super) fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+pub(super) fn optional_millis<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => millis(duration, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::transaction::{intersection, TrxState};
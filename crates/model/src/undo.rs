@@ -1,99 +1,383 @@
-use undo_2::{Action, Commands};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::{self, Display},
+    time::Duration,
+};
+
+#[cfg(feature = "js")]
+use observable_react::JsObservable;
+use observable_rs::{Observable, Reader};
+use serde::Serialize;
+#[cfg(feature = "js")]
 use wasm_bindgen::prelude::*;
 
-enum Command {
-    JsFn {
-        dofn: js_sys::Function,
-        undofn: js_sys::Function,
-    },
-    Batch(Vec<Command>),
+use crate::utils::clock::now;
+
+/// Number of undo entries kept by `UndoManager` by default
+pub const UNDO_MAX_DEPTH: usize = 100;
+
+/// Commands sharing a merge key are undone as one when they are recorded within this delay
+/// of each other, by default
+pub const UNDO_MERGE_WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UndoErr {
+    /// `commit` was called without a matching `begin`
+    NotBegun,
+    /// A command failed to apply or unapply
+    Command(String),
 }
 
-impl Command {
-    fn apply(&self) {
+impl Display for UndoErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Command::JsFn { dofn, .. } => {
-                dofn.call0(&JsValue::UNDEFINED).unwrap();
-            }
-            Command::Batch(pending) => {
-                for cmd in pending {
-                    cmd.apply()
-                }
-            }
+            UndoErr::NotBegun => write!(f, "Cannot commit without begin"),
+            UndoErr::Command(msg) => write!(f, "Undo command failed: {msg}"),
         }
     }
-    fn unapply(&self) {
-        match self {
-            Command::JsFn { undofn, .. } => {
-                undofn.call0(&JsValue::UNDEFINED).unwrap();
-            }
-            Command::Batch(pending) => {
-                for cmd in pending.iter().rev() {
-                    cmd.unapply()
+}
+
+impl Error for UndoErr {}
+
+impl From<String> for UndoErr {
+    fn from(value: String) -> Self {
+        UndoErr::Command(value)
+    }
+}
+
+#[cfg(feature = "js")]
+impl From<UndoErr> for JsValue {
+    fn from(value: UndoErr) -> Self {
+        js_sys::Error::new(&value.to_string()).into()
+    }
+}
+
+/// A change which can be undone and redone
+pub trait Command {
+    /// Make the change, or make it again once it was undone
+    fn apply(&mut self) -> Result<(), UndoErr>;
+    /// Revert the change
+    fn unapply(&mut self) -> Result<(), UndoErr>;
+    /// Consecutive commands with the same merge key are undone as one, as long as each of
+    /// them is recorded within the merge window of the previous one. Typing a word in a text
+    /// field is then undone at once rather than one key at a time.
+    fn merge_key(&self) -> Option<&str> {
+        None
+    }
+    /// Describes the change in undo/redo menus
+    fn label(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// A DO/UNDO pair of JS functions
+#[cfg(feature = "js")]
+struct JsCommand {
+    dofn: js_sys::Function,
+    undofn: js_sys::Function,
+    label: Option<String>,
+}
+
+#[cfg(feature = "js")]
+impl Command for JsCommand {
+    fn apply(&mut self) -> Result<(), UndoErr> {
+        self.dofn
+            .call0(&JsValue::UNDEFINED)
+            .map_err(|e| UndoErr::Command(format!("{e:?}")))?;
+        Ok(())
+    }
+    fn unapply(&mut self) -> Result<(), UndoErr> {
+        self.undofn
+            .call0(&JsValue::UNDEFINED)
+            .map_err(|e| UndoErr::Command(format!("{e:?}")))?;
+        Ok(())
+    }
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// What can be undone and redone, for undo/redo menus and buttons
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UndoState {
+    pub can_undo: bool,
+    pub can_redo: bool,
+    /// Label of the entry `undo` would revert
+    pub undo_label: Option<String>,
+    /// Label of the entry `redo` would make again
+    pub redo_label: Option<String>,
+    /// Number of entries which can be undone
+    pub undo_depth: usize,
+    /// Number of entries which can be redone
+    pub redo_depth: usize,
+}
+
+#[cfg(feature = "js")]
+impl From<UndoState> for JsValue {
+    fn from(value: UndoState) -> Self {
+        serde_json::to_string(&value)
+            .ok()
+            .and_then(|json| js_sys::JSON::parse(&json).ok())
+            .unwrap_or(JsValue::NULL)
+    }
+}
+
+#[cfg(feature = "js")]
+#[wasm_bindgen(typescript_custom_section)]
+const UNDO_STATE: &str = r#"
+interface UndoState {
+    canUndo: boolean;
+    canRedo: boolean;
+    undoLabel: string | null;
+    redoLabel: string | null;
+    undoDepth: number;
+    redoDepth: number;
+}
+"#;
+
+/// Commands recorded since a `begin`, alongside the label of the group
+type Group = (Option<String>, Vec<Box<dyn Command>>);
+
+/// Commands undone at once, in reverse order
+struct Entry {
+    commands: Vec<Box<dyn Command>>,
+    merge_key: Option<String>,
+    label: Option<String>,
+    recorded_at: Duration,
+}
+
+impl Entry {
+    fn apply(&mut self) -> Result<(), UndoErr> {
+        let order: Vec<usize> = (0..self.commands.len()).collect();
+        self.run(&order, |cmd| cmd.apply(), |cmd| cmd.unapply())
+    }
+    fn unapply(&mut self) -> Result<(), UndoErr> {
+        let order: Vec<usize> = (0..self.commands.len()).rev().collect();
+        self.run(&order, |cmd| cmd.unapply(), |cmd| cmd.apply())
+    }
+
+    /// Run `forward` on the commands in `order`. If one of them fails, run `backward` on
+    /// those which succeeded, in reverse order, so the entry is left as it was.
+    fn run(
+        &mut self,
+        order: &[usize],
+        forward: impl Fn(&mut dyn Command) -> Result<(), UndoErr>,
+        backward: impl Fn(&mut dyn Command) -> Result<(), UndoErr>,
+    ) -> Result<(), UndoErr> {
+        for (done, &i) in order.iter().enumerate() {
+            let Err(err) = forward(self.commands[i].as_mut()) else {
+                continue;
+            };
+            for &j in order[..done].iter().rev() {
+                if let Err(e) = backward(self.commands[j].as_mut()) {
+                    log::error!("Unable to roll back a partially applied undo entry: {e}");
                 }
             }
+            return Err(err);
         }
+        Ok(())
     }
 }
 
-#[wasm_bindgen]
+/// Linear undo history: recording a command drops the entries which were undone.
+/// An entry failing to undo or redo is rolled back, and stays where it was.
+#[cfg_attr(feature = "js", wasm_bindgen)]
 pub struct UndoManager {
-    commands: Commands<Command>,
-    pending: Option<Vec<Command>>,
+    undo: VecDeque<Entry>,
+    redo: Vec<Entry>,
+    /// One group per nesting level of `begin`
+    pending: Vec<Group>,
+    /// Whether the next command may merge into the last entry
+    mergeable: bool,
+    max_depth: usize,
+    merge_window: Duration,
+    state: Observable<UndoState>,
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 impl UndoManager {
     pub fn new() -> Self {
         UndoManager {
-            commands: Commands::new(),
-            pending: None,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            pending: Vec::new(),
+            mergeable: false,
+            max_depth: UNDO_MAX_DEPTH,
+            merge_window: UNDO_MERGE_WINDOW,
+            state: Default::default(),
         }
     }
-    pub fn undo(&mut self) {
+    pub fn undo(&mut self) -> Result<(), UndoErr> {
         log::info!("UNDO");
-        for action in self.commands.undo() {
-            interpret_action(action);
+        self.mergeable = false;
+        let Some(mut entry) = self.undo.pop_back() else {
+            return Ok(());
+        };
+        let result = entry.unapply();
+        match result {
+            Ok(_) => self.redo.push(entry),
+            Err(_) => self.undo.push_back(entry),
         }
+        self.notify();
+        result
     }
-    pub fn redo(&mut self) {
+    pub fn redo(&mut self) -> Result<(), UndoErr> {
         log::info!("REDO");
-        for action in self.commands.redo() {
-            interpret_action(action);
+        self.mergeable = false;
+        let Some(mut entry) = self.redo.pop() else {
+            return Ok(());
+        };
+        let result = entry.apply();
+        match result {
+            Ok(_) => self.undo.push_back(entry),
+            Err(_) => self.redo.push(entry),
         }
+        self.notify();
+        result
+    }
+    /// Group the commands recorded until the matching `commit` into a single entry.
+    /// Groups may be nested, the outermost one making the entry.
+    pub fn begin(&mut self) {
+        self.pending.push((None, Vec::new()));
     }
-    /// Add a DO/UNDO pair, and immediately call the DO handler
-    pub fn add_action(&mut self, dofn: js_sys::Function, undofn: js_sys::Function) {
-        dofn.call0(&JsValue::UNDEFINED).unwrap();
 
-        let cmd = Command::JsFn { dofn, undofn };
+    /// Like `begin`, the entry being labeled `label` if it is the outermost group
+    pub fn begin_labeled(&mut self, label: String) {
+        self.pending.push((Some(label), Vec::new()));
+    }
 
-        match self.pending {
-            Some(ref mut pending) => {
-                pending.push(cmd);
-            }
+    pub fn commit(&mut self) -> Result<(), UndoErr> {
+        let (label, group) = self.pending.pop().ok_or(UndoErr::NotBegun)?;
+        match self.pending.last_mut() {
+            Some((_, parent)) => parent.extend(group),
+            None if group.is_empty() => {}
             None => {
-                self.commands.push(cmd);
+                // unlabeled groups are described by their first labeled command
+                let first_label = || group.iter().find_map(|cmd| cmd.label());
+                let label = label.or_else(|| first_label().map(str::to_owned));
+                self.push_entry(group, None, label);
             }
         }
+        Ok(())
+    }
+}
+
+impl UndoManager {
+    /// Keep at most `max_depth` entries, dropping the oldest ones
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
     }
 
-    pub fn begin(&mut self) {
-        self.pending = Some(Vec::new());
+    /// Merge consecutive commands sharing a merge key when they are recorded less than
+    /// `merge_window` apart, see `Command::merge_key`
+    pub fn with_merge_window(mut self, merge_window: Duration) -> Self {
+        self.merge_window = merge_window;
+        self
+    }
+
+    /// Apply `cmd`, and record it if it succeeded
+    pub fn add(&mut self, mut cmd: impl Command + 'static) -> Result<(), UndoErr> {
+        cmd.apply()?;
+        self.record(cmd);
+        Ok(())
+    }
+
+    /// Record `cmd`, whose change was made already
+    pub fn record(&mut self, cmd: impl Command + 'static) {
+        self.push(Box::new(cmd));
+    }
+
+    /// Updated whenever an entry is recorded, undone or redone
+    pub fn state(&self) -> Reader<UndoState> {
+        self.state.reader()
+    }
+
+    /// Group the commands recorded by `f` into a single entry
+    pub fn transact<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Result<T, UndoErr> {
+        self.begin();
+        let value = f(self);
+        self.commit()?;
+        Ok(value)
     }
 
-    pub fn commit(&mut self) {
-        let pending = self.pending.take().expect("Cannot commit without begin");
-        let batch = Command::Batch(pending);
-        self.commands.push(batch);
+    fn push(&mut self, cmd: Box<dyn Command>) {
+        if let Some((_, group)) = self.pending.last_mut() {
+            group.push(cmd);
+            return;
+        }
+
+        let now = now();
+        let merge_key = cmd.merge_key().map(str::to_owned);
+        if let (true, Some(key), Some(last)) = (self.mergeable, &merge_key, self.undo.back_mut()) {
+            let within_window = now.saturating_sub(last.recorded_at) < self.merge_window;
+            if within_window && last.merge_key.as_ref() == Some(key) {
+                last.commands.push(cmd);
+                last.recorded_at = now;
+                return;
+            }
+        }
+        let label = cmd.label().map(str::to_owned);
+        self.push_entry(vec![cmd], merge_key, label);
+    }
+
+    fn push_entry(
+        &mut self,
+        commands: Vec<Box<dyn Command>>,
+        merge_key: Option<String>,
+        label: Option<String>,
+    ) {
+        self.redo.clear();
+        self.mergeable = merge_key.is_some();
+        self.undo.push_back(Entry {
+            commands,
+            merge_key,
+            label,
+            recorded_at: now(),
+        });
+        while self.undo.len() > self.max_depth {
+            self.undo.pop_front();
+        }
+        self.notify();
+    }
+
+    fn notify(&self) {
+        let (undo, redo) = (self.undo.back(), self.redo.last());
+        self.state.set(UndoState {
+            can_undo: undo.is_some(),
+            can_redo: redo.is_some(),
+            undo_label: undo.and_then(|entry| entry.label.clone()),
+            redo_label: redo.and_then(|entry| entry.label.clone()),
+            undo_depth: self.undo.len(),
+            redo_depth: self.redo.len(),
+        });
+    }
+}
+
+#[cfg(feature = "js")]
+#[wasm_bindgen]
+impl UndoManager {
+    /// Add a DO/UNDO pair, and immediately call the DO handler
+    pub fn add_action(
+        &mut self,
+        dofn: js_sys::Function,
+        undofn: js_sys::Function,
+        label: Option<String>,
+    ) -> Result<(), UndoErr> {
+        self.add(JsCommand {
+            dofn,
+            undofn,
+            label,
+        })
     }
 
-    // TODO - consider Using Rc<RefCell<Inner> to make this work
-    // pub fn transact(&mut self, jsFn: js_sys::Function) {
-    //     self.begin();
-    //     jsFn.call0(&JsValue::UNDEFINED).unwrap(); // What's getting called inside this closure?
-    //     self.commit();
-    // }
+    /// `UndoState`
+    #[wasm_bindgen(js_name = state)]
+    pub fn js_state(&self) -> JsObservable {
+        self.state().into()
+    }
 }
 
 impl Default for UndoManager {
@@ -102,9 +386,205 @@ impl Default for UndoManager {
     }
 }
 
-fn interpret_action(action: Action<&Command>) {
-    match action {
-        Action::Do(cmd) => cmd.apply(),
-        Action::Undo(cmd) => cmd.unapply(),
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+        time::Duration,
+    };
+
+    use super::{Command, UndoErr, UndoManager, UndoState};
+
+    /// Appends `ch` to the text
+    struct Type {
+        text: Rc<RefCell<String>>,
+        ch: char,
+    }
+
+    impl Command for Type {
+        fn apply(&mut self) -> Result<(), UndoErr> {
+            self.text.borrow_mut().push(self.ch);
+            Ok(())
+        }
+        fn unapply(&mut self) -> Result<(), UndoErr> {
+            let mut text = self.text.borrow_mut();
+            if !text.ends_with(self.ch) {
+                return Err(format!("expected {} at the end", self.ch).into());
+            }
+            text.pop();
+            Ok(())
+        }
+        fn merge_key(&self) -> Option<&str> {
+            Some("text")
+        }
+        fn label(&self) -> Option<&str> {
+            Some("Typing")
+        }
+    }
+
+    fn typist(text: &Rc<RefCell<String>>) -> impl Fn(&mut UndoManager, &str) + '_ {
+        |um, chars| {
+            for ch in chars.chars() {
+                let text = text.clone();
+                um.add(Type { text, ch }).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn nested_groups_undo_as_one() {
+        let text = Rc::new(RefCell::new(String::new()));
+        let type_in = typist(&text);
+        let mut um = UndoManager::new().with_merge_window(Duration::ZERO);
+
+        type_in(&mut um, "a");
+        um.begin();
+        type_in(&mut um, "b");
+        um.transact(|um| type_in(um, "cd")).unwrap();
+        um.commit().unwrap();
+        assert_eq!(um.commit(), Err(UndoErr::NotBegun));
+
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "a");
+        um.redo().unwrap();
+        assert_eq!(*text.borrow(), "abcd");
+        um.undo().unwrap();
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "");
+    }
+
+    #[test]
+    fn consecutive_commands_merge_within_window() {
+        let text = Rc::new(RefCell::new(String::new()));
+        let type_in = typist(&text);
+        let mut um = UndoManager::new().with_merge_window(Duration::from_secs(60));
+
+        type_in(&mut um, "ab");
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "");
+        um.redo().unwrap();
+
+        // an undo closes the merged entry
+        um.undo().unwrap();
+        type_in(&mut um, "c");
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "");
+    }
+
+    #[test]
+    fn history_is_bounded_and_surfaces_errors() {
+        let text = Rc::new(RefCell::new(String::new()));
+        let type_in = typist(&text);
+        let mut um = UndoManager::new()
+            .with_merge_window(Duration::ZERO)
+            .with_max_depth(2);
+
+        type_in(&mut um, "abc");
+        um.undo().unwrap();
+        um.undo().unwrap();
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "a");
+
+        type_in(&mut um, "b");
+        text.borrow_mut().push('x');
+        assert!(matches!(um.undo(), Err(UndoErr::Command(_))));
+        // the failed entry stays to be undone, so there is nothing to redo
+        um.redo().unwrap();
+        assert_eq!(*text.borrow(), "abx");
+        text.borrow_mut().pop();
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "a");
+    }
+
+    /// Fails to apply and unapply while `failing` is set
+    struct Flaky {
+        failing: Rc<Cell<bool>>,
+    }
+
+    impl Command for Flaky {
+        fn apply(&mut self) -> Result<(), UndoErr> {
+            match self.failing.get() {
+                true => Err("flaky apply".to_string().into()),
+                false => Ok(()),
+            }
+        }
+        fn unapply(&mut self) -> Result<(), UndoErr> {
+            match self.failing.get() {
+                true => Err("flaky unapply".to_string().into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn failed_group_is_rolled_back() {
+        let text = Rc::new(RefCell::new(String::new()));
+        let type_in = typist(&text);
+        let failing = Rc::new(Cell::new(false));
+        let mut um = UndoManager::new();
+        let state = um.state();
+
+        um.begin();
+        type_in(&mut um, "a");
+        um.add(Flaky {
+            failing: failing.clone(),
+        })
+        .unwrap();
+        type_in(&mut um, "b");
+        um.commit().unwrap();
+
+        failing.set(true);
+        assert!(matches!(um.undo(), Err(UndoErr::Command(_))));
+        assert_eq!(*text.borrow(), "ab");
+        assert!(state.value().can_undo && !state.value().can_redo);
+
+        failing.set(false);
+        um.undo().unwrap();
+        assert_eq!(*text.borrow(), "");
+
+        failing.set(true);
+        assert!(matches!(um.redo(), Err(UndoErr::Command(_))));
+        assert_eq!(*text.borrow(), "");
+        assert!(!state.value().can_undo && state.value().can_redo);
+
+        failing.set(false);
+        um.redo().unwrap();
+        assert_eq!(*text.borrow(), "ab");
+    }
+
+    #[test]
+    fn state_follows_history() {
+        let text = Rc::new(RefCell::new(String::new()));
+        let type_in = typist(&text);
+        let mut um = UndoManager::new().with_merge_window(Duration::ZERO);
+        let state = um.state();
+
+        type_in(&mut um, "a");
+        um.begin_labeled("Paste".into());
+        type_in(&mut um, "bc");
+        um.commit().unwrap();
+        assert_eq!(
+            *state.value(),
+            UndoState {
+                can_undo: true,
+                can_redo: false,
+                undo_label: Some("Paste".into()),
+                redo_label: None,
+                undo_depth: 2,
+                redo_depth: 0,
+            }
+        );
+
+        um.undo().unwrap();
+        um.undo().unwrap();
+        let undone = state.value().clone();
+        assert!(!undone.can_undo && undone.can_redo);
+        assert_eq!(undone.redo_label.as_deref(), Some("Typing"));
+        assert_eq!(undone.redo_depth, 2);
+
+        um.redo().unwrap();
+        assert_eq!(state.value().undo_label.as_deref(), Some("Typing"));
+        assert_eq!(state.value().redo_label.as_deref(), Some("Paste"));
     }
 }
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+/// Time elapsed since an arbitrary point, to measure durations
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> Duration {
+    thread_local! {
+        static START: std::time::Instant = std::time::Instant::now();
+    }
+    START.with(|start| start.elapsed())
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "js"))]
+pub fn now() -> Duration {
+    Duration::from_millis(epoch_millis())
+}
+
+/// `std::time` panics on wasm32 without JS to ask the time from, so time stands still
+#[cfg(all(target_arch = "wasm32", not(feature = "js")))]
+pub fn now() -> Duration {
+    Duration::ZERO
+}
+
+/// Milliseconds since the epoch
+#[cfg(not(target_arch = "wasm32"))]
+pub fn epoch_millis() -> u64 {
+    let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    since_epoch.map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "js"))]
+pub fn epoch_millis() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+#[cfg(all(target_arch = "wasm32", not(feature = "js")))]
+pub fn epoch_millis() -> u64 {
+    0
+}
@@ -1,4 +1,5 @@
 use unicode_segmentation::UnicodeSegmentation;
+#[cfg(feature = "js")]
 use wasm_bindgen::prelude::*;
 
 /// Calculates the total number of UTF-16 units in a string.
@@ -26,13 +27,13 @@ use wasm_bindgen::prelude::*;
 /// assert_eq!(len_utf16_str("👨‍🎨 💜 🦀"), 11);
 /// ```
 ///
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 pub fn len_utf16_str(string: &str) -> usize {
     let graphemes = UnicodeSegmentation::graphemes(string, true);
     graphemes.fold(0, |acc, g| acc + len_utf16_gr(g))
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "js", wasm_bindgen)]
 pub fn len_utf16_gr(grapheme: &str) -> usize {
     grapheme.chars().fold(0, |acc, c| acc + c.len_utf16())
 }
@@ -1 +1,3 @@
+pub mod clock;
 pub mod helpers;
+pub mod random;
@@ -0,0 +1,66 @@
+/// A random number, to spread out retries and to tell transactions apart across processes
+#[cfg(not(target_arch = "wasm32"))]
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => u64::from_le_bytes(bytes),
+        Err(err) => {
+            log::warn!("No randomness from the OS: {err}");
+            fallback()
+        }
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "js"))]
+pub fn random_u64() -> u64 {
+    use wasm_bindgen::prelude::wasm_bindgen;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = crypto, js_name = getRandomValues)]
+        fn get_random_values(buf: &mut [u8]);
+    }
+
+    let mut bytes = [0; 8];
+    get_random_values(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// There is no source of randomness on wasm32 without JS, so the numbers only differ
+/// within the process
+#[cfg(all(target_arch = "wasm32", not(feature = "js")))]
+pub fn random_u64() -> u64 {
+    fallback()
+}
+
+/// A random number in `[0, 1)`
+pub fn random_unit() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Numbers which differ from one call to the next, but are only as random as `RandomState`
+#[cfg(not(all(target_arch = "wasm32", feature = "js")))]
+fn fallback() -> u64 {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{random_u64, random_unit};
+
+    #[test]
+    fn random_numbers_differ() {
+        assert_ne!(random_u64(), random_u64());
+        let unit = random_unit();
+        assert!((0.0..1.0).contains(&unit));
+    }
+}
@@ -0,0 +1,118 @@
+//! Runs against the local Firestore emulator, e.g.
+//! `FIRESTORE_EMULATOR_HOST=localhost:8080 GOOGLE_PROJECT_ID=demo-edvo cargo test --test firestore-emulator`
+//! and is skipped when `FIRESTORE_EMULATOR_HOST` is not set.
+#![cfg(not(target_arch = "wasm32"))]
+
+use edvo_model::{
+    db::{
+        firestore::{FirestoreDbRef, FirestoreTrxOp},
+        memory::Document,
+    },
+    transaction::{Precondition, TrxErrCode},
+};
+use serde_json::{json, Value};
+
+fn doc(value: Value) -> Document {
+    value.as_object().cloned().unwrap()
+}
+
+async fn connect() -> Option<FirestoreDbRef> {
+    if std::env::var("FIRESTORE_EMULATOR_HOST").is_err() {
+        eprintln!("FIRESTORE_EMULATOR_HOST is not set, skipping");
+        return None;
+    }
+    Some(FirestoreDbRef::from_env().await.unwrap())
+}
+
+#[tokio::test]
+async fn insert_update_delete() {
+    let Some(db) = connect().await else {
+        return;
+    };
+    let path = format!("vertex/emulator-{}/property/p1", std::process::id());
+
+    let transaction = db.trx("insert");
+    transaction
+        .process_op(FirestoreTrxOp::Insert {
+            path: path.clone(),
+            data: doc(json!({ "payload": "hello", "meta": { "a": 1 } })),
+        })
+        .unwrap();
+    assert!(transaction.apply().await.is_ok());
+
+    let transaction = db.trx("update");
+    transaction
+        .process_op(FirestoreTrxOp::Update {
+            path: path.clone(),
+            data: doc(json!({ "meta": { "b": 2 } })),
+            precondition: Some(Precondition::Exists),
+        })
+        .unwrap();
+    assert!(transaction.apply().await.is_ok());
+    assert_eq!(
+        db.get(&path).await.unwrap(),
+        Some(doc(
+            json!({ "payload": "hello", "meta": { "a": 1, "b": 2 } })
+        ))
+    );
+
+    let transaction = db.trx("delete");
+    transaction
+        .process_op(FirestoreTrxOp::Delete { path: path.clone() })
+        .unwrap();
+    assert!(transaction.apply().await.is_ok());
+    assert_eq!(db.get(&path).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn update_preconditions() {
+    let Some(db) = connect().await else {
+        return;
+    };
+    let path = format!("vertex/emulator-{}/property/p2", std::process::id());
+    let update = |updated_at: i64, precondition| FirestoreTrxOp::Update {
+        path: path.clone(),
+        data: doc(json!({ "updatedAt": updated_at })),
+        precondition: Some(precondition),
+    };
+
+    let transaction = db.trx("missing");
+    transaction
+        .process_op(update(1, Precondition::Exists))
+        .unwrap();
+    let err = transaction.apply().await.unwrap_err();
+    assert_eq!(err.code(), TrxErrCode::FailedPrecondition);
+    assert_eq!(db.get(&path).await.unwrap(), None);
+
+    let transaction = db.trx("insert");
+    transaction
+        .process_op(FirestoreTrxOp::Insert {
+            path: path.clone(),
+            data: doc(json!({ "updatedAt": 1 })),
+        })
+        .unwrap();
+    assert!(transaction.apply().await.is_ok());
+
+    let transaction = db.trx("update");
+    transaction
+        .process_op(update(2, Precondition::UpdatedAt(1)))
+        .unwrap();
+    assert!(transaction.apply().await.is_ok());
+
+    let transaction = db.trx("stale");
+    transaction
+        .process_op(update(3, Precondition::UpdatedAt(1)))
+        .unwrap();
+    let err = transaction.apply().await.unwrap_err();
+    assert_eq!(err.code(), TrxErrCode::FailedPrecondition);
+    assert_eq!(
+        db.get(&path).await.unwrap(),
+        Some(doc(json!({ "updatedAt": 2 })))
+    );
+
+    let transaction = db.trx("delete");
+    transaction
+        .process_op(FirestoreTrxOp::Delete { path: path.clone() })
+        .unwrap();
+    assert!(transaction.apply().await.is_ok());
+}
@@ -5,7 +5,9 @@ use item::*;
 
 use edvo_model::{
     property::{
-        content::{text::Content, text_range_offset::ContentRangeOffsets, ContentState},
+        content::{
+            text::Content, text_range_offset::ContentRangeOffsets, ContentState, FormatAttr,
+        },
         Property,
     },
     text_range::{TextContentPosition, TextContentRange},
@@ -256,6 +258,25 @@ impl TextField {
         true
     }
 
+    /// Remove the `attr` format from the range if all its text has it, set it otherwise.
+    /// `value` is the url of a link, or the color. Returns true if the range has it now.
+    pub fn toggle_format(
+        &self,
+        index: u32,
+        length: u32,
+        attr: &str,
+        value: Option<String>,
+    ) -> bool {
+        let Some(attr) = FormatAttr::new(attr, value) else {
+            log::warn!("VM_TextField: unsupported format {attr}");
+            return false;
+        };
+        let content = self.content.get();
+        let enabled = content.toggle_format(index, length, &attr);
+        content.maybe_debounce_save();
+        enabled
+    }
+
     #[wasm_bindgen(getter)]
     pub fn offsets(&self) -> Option<ContentRangeOffsets> {
         self.offsets
@@ -269,6 +290,29 @@ impl TextField {
         self.set_offsets(self.content.get().get_range(start, end));
     }
 
+    /// Revert the last local edits, moving the caret where they were.
+    /// Returns true if the content was updated
+    pub fn undo(&self) -> bool {
+        let content = self.content.get();
+        let Some(caret) = content.undo() else {
+            return false;
+        };
+        content.maybe_debounce_save();
+        self.set_offsets(Some(caret));
+        true
+    }
+
+    /// Make the last undone local edits again, see `undo`
+    pub fn redo(&self) -> bool {
+        let content = self.content.get();
+        let Some(caret) = content.redo() else {
+            return false;
+        };
+        content.maybe_debounce_save();
+        self.set_offsets(Some(caret));
+        true
+    }
+
     pub fn clear_offsets(&self) {
         self.set_offsets(None);
     }
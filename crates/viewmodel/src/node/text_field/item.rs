@@ -1,6 +1,6 @@
 use std::{rc::Rc, sync::Arc};
 
-use edvo_model::property::content::{Chunkable, DataChunk};
+use edvo_model::property::content::{Chunkable, DataChunk, TextFormat};
 use wasm_bindgen::prelude::wasm_bindgen;
 
 use crate::ViewModelNode;
@@ -19,6 +19,7 @@ struct Inner {
 #[derive(Debug, PartialEq)]
 pub enum TextFieldItemKind {
     Text(Arc<str>),
+    Formatted(Arc<str>, TextFormat),
     Edge(String),
     Unknown,
 }
@@ -31,6 +32,7 @@ impl TextFieldItem {
     pub fn from_datachunk(chunk: impl Into<DataChunk>) -> Self {
         let kind = match chunk.into() {
             DataChunk::Text(text) => TextFieldItemKind::Text(text),
+            DataChunk::Formatted(text, format) => TextFieldItemKind::Formatted(text, format),
             DataChunk::Edge(edge_id) => TextFieldItemKind::Edge(edge_id),
             DataChunk::Unknown => TextFieldItemKind::Unknown,
         };
@@ -41,6 +43,10 @@ impl TextFieldItem {
         Self::new(TextFieldItemKind::Text(text.into()))
     }
 
+    pub fn formatted(text: impl Into<Arc<str>>, format: TextFormat) -> Self {
+        Self::new(TextFieldItemKind::Formatted(text.into(), format))
+    }
+
     pub fn edge(edge_id: impl Into<String>) -> Self {
         Self::new(TextFieldItemKind::Edge(edge_id.into()))
     }
@@ -65,7 +71,7 @@ impl Default for TextFieldItem {
 impl TextFieldItemKind {
     pub fn len(&self) -> usize {
         match self {
-            TextFieldItemKind::Text(text) => text.len(),
+            TextFieldItemKind::Text(text) | TextFieldItemKind::Formatted(text, _) => text.len(),
             TextFieldItemKind::Edge(_) => 1,
             TextFieldItemKind::Unknown => 1,
         }
@@ -73,7 +79,9 @@ impl TextFieldItemKind {
 
     pub fn is_empty(&self) -> bool {
         match self {
-            TextFieldItemKind::Text(text) => text.is_empty(),
+            TextFieldItemKind::Text(text) | TextFieldItemKind::Formatted(text, _) => {
+                text.is_empty()
+            }
             TextFieldItemKind::Edge(_) => false,
             TextFieldItemKind::Unknown => false,
         }
@@ -81,7 +89,7 @@ impl TextFieldItemKind {
 
     pub fn string(&self) -> Option<&str> {
         match self {
-            TextFieldItemKind::Text(s) => Some(s),
+            TextFieldItemKind::Text(s) | TextFieldItemKind::Formatted(s, _) => Some(s),
             _ => None,
         }
     }
@@ -98,6 +106,9 @@ impl PartialEq<DataChunk> for TextFieldItemKind {
     fn eq(&self, other: &DataChunk) -> bool {
         match (self, other) {
             (TextFieldItemKind::Text(t1), DataChunk::Text(t2)) => t1 == t2,
+            (TextFieldItemKind::Formatted(t1, f1), DataChunk::Formatted(t2, f2)) => {
+                t1 == t2 && f1 == f2
+            }
             (TextFieldItemKind::Edge(e1), DataChunk::Edge(e2)) => e1 == e2,
             _ => false,
         }
@@ -198,13 +209,23 @@ impl TextItemsAndLip {
 }
 
 pub mod js {
+    use edvo_model::property::content::{FormatAttr, TextFormat};
     use js_sys::Reflect;
     use wasm_bindgen::prelude::*;
 
     #[wasm_bindgen(typescript_custom_section)]
     const IJsProperty: &'static str = r#"
+export interface TextFormat {
+  bold?: true;
+  italic?: true;
+  underline?: true;
+  code?: true;
+  link?: string;
+  color?: string;
+}
+
 export type TextFieldItem =
-  | {kind: "text", value: string}
+  | {kind: "text", value: string, format?: TextFormat}
   | {kind: "eid", value: string}
   | {kind: "unknown", value?: null};
 
@@ -241,6 +262,20 @@ export type VoidCallbackOnce = () => void;
             TextFieldItem::new("text", Some(&s.into()))
         }
 
+        pub fn formatted(s: &str, format: &TextFormat) -> TextFieldItem {
+            let item = TextFieldItem::text(s);
+            let obj = js_sys::Object::new();
+            for attr in format.attrs() {
+                let value = match &attr {
+                    FormatAttr::Link(value) | FormatAttr::Color(value) => value.into(),
+                    _ => JsValue::TRUE,
+                };
+                let _ = Reflect::set(&obj, &attr.name().into(), &value);
+            }
+            let _ = Reflect::set(&item.obj, &"format".into(), &obj);
+            item
+        }
+
         pub fn edge_id(eid: &str) -> TextFieldItem {
             TextFieldItem::new("eid", Some(&eid.into()))
         }
@@ -254,6 +289,7 @@ export type VoidCallbackOnce = () => void;
         fn from(value: &super::TextFieldItem) -> Self {
             match value.kind() {
                 super::TextFieldItemKind::Text(s) => Self::text(s),
+                super::TextFieldItemKind::Formatted(s, format) => Self::formatted(s, format),
                 super::TextFieldItemKind::Edge(eid) => Self::edge_id(eid),
                 super::TextFieldItemKind::Unknown => Self::unknown(),
             }
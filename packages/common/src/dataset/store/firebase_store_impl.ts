@@ -4,6 +4,7 @@ import * as Bindings from '@edvoapp/wasm-bindings';
 import { Store } from '../..';
 import { DocumentReference, DocumentSnapshot, Query, QuerySnapshot, Timestamp, Blob, UploadTask, User } from './db';
 import { ServerFunctions, collectionName } from './store_shared';
+import { arrayUnion } from '../../transaction';
 
 class FirebaseStoreImpl extends Store {
   constructor() {
@@ -152,7 +153,7 @@ class FirebaseStoreImpl extends Store {
   }
   pushToDbArray(value: Uint8Array): any {
     const blob = firebase.firestore.Blob.fromUint8Array(value);
-    return firebase.firestore.FieldValue.arrayUnion(blob as unknown as Blob);
+    return arrayUnion(blob);
   }
 
   async callServerFunction<T extends keyof ServerFunctions>(
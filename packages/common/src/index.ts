@@ -8,6 +8,7 @@ export { globalStore, QueryObservable, Store, DB } from './dataset';
 export { Query } from './dataset/store/db';
 export { trxWrap, trxWrapSync, subTrxWrap, subTrxWrapSync, FireBatch, TrxRef, asTransaction } from './transaction';
 export { firebaseNow } from './firebase';
+export { OfflineStore } from './offline-store';
 export * as MatchEntity from './model-util/match-entity';
 export * as Search from './helpers/search';
 export { config } from './utils/config';
@@ -312,7 +312,7 @@ export class Property extends Entity<PropertyData> implements Bindings.IJsProper
     };
 
     // HACK: inserts the initial updateArray into insertData
-    property.rustProperty.pushContent(UpdateContext.init(insertData));
+    property.rustProperty.pushContent(trx, UpdateContext.init(insertData));
 
     if (contentId) insertData.contentId = contentId;
     if (contentUrl) {
@@ -0,0 +1,71 @@
+const DB_NAME = 'edvo-offline-queue';
+const STORE_NAME = 'transactions';
+
+function promisify<T>(request: IDBRequest<T>): Promise<T> {
+  return new Promise((resolve, reject) => {
+    request.onsuccess = () => resolve(request.result);
+    request.onerror = () => reject(request.error);
+  });
+}
+
+/**
+ * IndexedDB backed key-value store of the offline transaction queue.
+ * Values are the JSON serialized transactions, keyed by their idempotency key.
+ */
+export class OfflineStore {
+  private db: Promise<IDBDatabase> | undefined;
+
+  private constructor(readonly name: string) {}
+  static open(name: string = DB_NAME): OfflineStore {
+    return new OfflineStore(name);
+  }
+
+  private database(): Promise<IDBDatabase> {
+    if (!this.db) {
+      const request = indexedDB.open(this.name, 1);
+      request.onupgradeneeded = () => request.result.createObjectStore(STORE_NAME);
+      this.db = promisify(request);
+      // let the next call try again
+      this.db.catch(() => (this.db = undefined));
+    }
+    return this.db;
+  }
+
+  private async store(mode: IDBTransactionMode): Promise<IDBObjectStore> {
+    const db = await this.database();
+    return db.transaction(STORE_NAME, mode).objectStore(STORE_NAME);
+  }
+
+  async put(key: string, value: string): Promise<void> {
+    await promisify((await this.store('readwrite')).put(value, key));
+  }
+
+  async delete(key: string): Promise<void> {
+    await promisify((await this.store('readwrite')).delete(key));
+  }
+
+  async values(): Promise<string[]> {
+    return promisify((await this.store('readonly')).getAll());
+  }
+
+  /**
+   * Claim the transaction `key` across the tabs sharing the store, through a Web Lock which is
+   * also released if the tab goes away. Resolves to the function releasing the claim, or to
+   * undefined if another tab holds it.
+   */
+  claim(key: string): Promise<(() => void) | undefined> {
+    if (!navigator.locks) {
+      console.warn('Web Locks are not available, the offline queue is not claimed across tabs');
+      return Promise.resolve(() => {});
+    }
+    return new Promise((resolve, reject) => {
+      navigator.locks
+        .request(`${this.name}/${key}`, { ifAvailable: true }, (lock) => {
+          if (!lock) return resolve(undefined);
+          // the lock is held until the returned promise settles
+          return new Promise<void>((release) => resolve(() => release()));
+        })
+        .catch(reject);
+    });
+  }
+}
@@ -53,8 +53,25 @@ export function trxWrapSync<Out, R = Awaited<Out>>(
   return out;
 }
 
+/**
+ * Run the supplied Op `fn` like `trxWrap` does, without committing what it writes.
+ * Useful from the developer console, to see what an action would write.
+ *
+ * @returns the plan of the writes `trxWrap` would commit, and how they would be batched
+ */
+export function trxDryRun(fn: TrxOp<unknown>, name?: string): ReturnType<Bindings.Transaction['dryRun']> {
+  const trx: Bindings.Transaction = globalStore.createTransaction(name);
+  const trxRef: Bindings.TrxRef = trx.get_ref()!;
+
+  return Guard.while({ trx, trxRef }, async ({ trx, trxRef }) => {
+    await fn(trxRef);
+    return trx.dryRun();
+  });
+}
+
 globalThis.trxWrap = trxWrap;
 globalThis.trxWrapSync = trxWrapSync;
+globalThis.trxDryRun = trxDryRun;
 
 /**
  * Nestable form of trxWrap which can be used with or without a transaction.
@@ -114,7 +131,70 @@ export type FireObjValue = {
   [k: string]: FireFieldValue;
 };
 
+// tags the Firestore values serialized by FireBatch.encodeOp
+const CODEC_TAG = '__edvoCodec';
+const APPLIED_COLLECTION = 'appliedTransactions';
+
+type BeforeImage = {
+  docRef: DocumentReference;
+  data: {} | undefined;
+};
+
+export type TrxPrecondition = { exists: true } | { updatedAt: number };
+
+type Write = {
+  docRef: DocumentReference;
+  // undefined for a delete
+  data: {} | undefined;
+  merge: boolean;
+};
+
+// elements of the array unions made by `arrayUnion`, which Firestore does not expose
+const arrayUnions = new WeakMap<firebase.firestore.FieldValue, unknown[]>();
+
+/**
+ * `FieldValue.arrayUnion`, whose elements can be read back by `arrayUnionElements`
+ */
+export function arrayUnion(...elements: unknown[]): firebase.firestore.FieldValue {
+  const fieldValue = firebase.firestore.FieldValue.arrayUnion(...elements);
+  arrayUnions.set(fieldValue, elements);
+  return fieldValue;
+}
+
+export function arrayUnionElements(value: unknown): unknown[] | undefined {
+  return value instanceof firebase.firestore.FieldValue ? arrayUnions.get(value) : undefined;
+}
+
+function toMillis(value: any): number | undefined {
+  if (typeof value === 'number') return value;
+  if (typeof value?.toMillis === 'function') return value.toMillis();
+  return undefined;
+}
+
+/**
+ * Error thrown by `FireBatch.commit` when a precondition does not hold
+ */
+function conflictError(
+  docRef: DocumentReference,
+  precondition: TrxPrecondition,
+  snapshot: firebase.firestore.DocumentSnapshot,
+) {
+  const current = snapshot.exists ? snapshot.data() : null;
+  const state = current ? 'was changed' : 'does not exist';
+  return Object.assign(new Error(`Document(${docRef.path}) ${state} since it was read`), {
+    code: 'failed-precondition',
+    path: docRef.path,
+    precondition,
+    current,
+  });
+}
+
 export class FireBatch {
+  private readonly docRefs = new Map<string, DocumentReference>();
+  private readonly writes: Write[] = [];
+  private readonly preconditions = new Map<string, TrxPrecondition>();
+  private beforeImages: BeforeImage[] | undefined;
+
   private constructor(readonly batch: firebase.firestore.WriteBatch) {}
   static create(): FireBatch {
     const db = firebase.firestore();
@@ -122,14 +202,209 @@ export class FireBatch {
   }
 
   set(docRef: DocumentReference, data: {}, merge: boolean) {
+    this.docRefs.set(docRef.path, docRef);
+    this.writes.push({ docRef, data, merge });
     this.batch.set(docRef as any, data, { merge });
   }
+  /**
+   * Merge-set `data`, the whole batch failing if `precondition` does not hold once committed
+   */
+  update(docRef: DocumentReference, data: {}, precondition: TrxPrecondition) {
+    this.preconditions.set(docRef.path, precondition);
+    this.set(docRef, data, true);
+  }
   delete(docRef: DocumentReference) {
+    this.docRefs.set(docRef.path, docRef);
+    this.writes.push({ docRef, data: undefined, merge: false });
     this.batch.delete(docRef as any);
   }
 
+  /**
+   * Commit the batch. With preconditions, the documents are read and written in a Firestore
+   * transaction instead, which throws the conflict of the first precondition which does not hold.
+   */
   commit(): Promise<void> {
-    return this.batch.commit();
+    if (this.preconditions.size === 0) return this.batch.commit();
+
+    return firebase.firestore().runTransaction(async (transaction) => {
+      for (const [path, precondition] of this.preconditions) {
+        const docRef = this.docRefs.get(path)!;
+        const snapshot = await transaction.get(docRef as any);
+        const holds =
+          'exists' in precondition
+            ? snapshot.exists
+            : snapshot.exists && toMillis(snapshot.get('updatedAt')) === precondition.updatedAt;
+        if (!holds) throw conflictError(docRef, precondition, snapshot);
+      }
+      for (const { docRef, data, merge } of this.writes) {
+        if (data === undefined) {
+          transaction.delete(docRef as any);
+        } else {
+          transaction.set(docRef as any, data, { merge });
+        }
+      }
+    });
+  }
+
+  static isFieldValue(value: unknown): boolean {
+    return value instanceof firebase.firestore.FieldValue;
+  }
+
+  /**
+   * Combine the field value `next` with the `current` value of the same field, as set by an
+   * earlier write of the same document, so both writes can be committed as one.
+   * Deletes and server timestamps replace the current value, array unions are combined.
+   *
+   * @returns undefined if the outcome depends on the stored document
+   */
+  static combineFieldValues(
+    current: unknown,
+    next: firebase.firestore.FieldValue,
+  ): firebase.firestore.FieldValue | undefined {
+    const FieldValue = firebase.firestore.FieldValue;
+    if (next.isEqual(FieldValue.delete()) || next.isEqual(FieldValue.serverTimestamp())) return next;
+
+    const currentElements = arrayUnionElements(current);
+    const nextElements = arrayUnionElements(next);
+    if (currentElements && nextElements) return arrayUnion(...currentElements, ...nextElements);
+    return undefined;
+  }
+
+  /**
+   * Serialize a set, or a delete if `data` is undefined, for the offline queue.
+   *
+   * @returns undefined if the data holds field values which can not be serialized
+   */
+  static encodeOp(
+    docRef: DocumentReference,
+    data: {} | undefined,
+    merge: boolean,
+    precondition?: TrxPrecondition,
+  ): string | undefined {
+    const FieldValue = firebase.firestore.FieldValue;
+    function replacer(this: any, key: string, value: any) {
+      const raw = this[key];
+      if (raw instanceof firebase.firestore.Timestamp) {
+        return { [CODEC_TAG]: 'timestamp', seconds: raw.seconds, nanoseconds: raw.nanoseconds };
+      }
+      if (raw instanceof firebase.firestore.DocumentReference) {
+        return { [CODEC_TAG]: 'ref', path: raw.path };
+      }
+      if (raw instanceof firebase.firestore.Blob) {
+        return { [CODEC_TAG]: 'bytes', base64: raw.toBase64() };
+      }
+      if (raw instanceof FieldValue) {
+        if (raw.isEqual(FieldValue.delete())) return { [CODEC_TAG]: 'delete' };
+        if (raw.isEqual(FieldValue.serverTimestamp())) return { [CODEC_TAG]: 'serverTimestamp' };
+        const elements = arrayUnionElements(raw);
+        if (elements) return { [CODEC_TAG]: 'arrayUnion', elements };
+        throw new Error('FieldValue can not be serialized');
+      }
+      return value;
+    }
+    try {
+      const isDelete = data === undefined;
+      return JSON.stringify({ path: docRef.path, data, merge, precondition, delete: isDelete }, replacer);
+    } catch (e) {
+      return undefined;
+    }
+  }
+
+  /**
+   * Inverse of `encodeOp`, the op being a delete only if it is marked as one
+   */
+  static decodeOp(encoded: string): {
+    docRef: DocumentReference;
+    data?: {};
+    merge: boolean;
+    precondition?: TrxPrecondition;
+    delete: boolean;
+  } {
+    const db = firebase.firestore();
+    const FieldValue = firebase.firestore.FieldValue;
+    const { path, data, merge, precondition, delete: isDelete } = JSON.parse(encoded, (_key, value) => {
+      switch (value?.[CODEC_TAG]) {
+        case 'timestamp':
+          return new firebase.firestore.Timestamp(value.seconds, value.nanoseconds);
+        case 'ref':
+          return db.doc(value.path);
+        case 'bytes':
+          return firebase.firestore.Blob.fromBase64String(value.base64);
+        case 'delete':
+          return FieldValue.delete();
+        case 'serverTimestamp':
+          return FieldValue.serverTimestamp();
+        case 'arrayUnion':
+          return arrayUnion(...value.elements);
+        default:
+          return value;
+      }
+    });
+    return {
+      docRef: db.doc(path) as unknown as DocumentReference,
+      data,
+      merge,
+      precondition,
+      delete: isDelete === true,
+    };
+  }
+
+  /**
+   * Document committed along with a transaction, recording that its idempotency key was applied
+   */
+  static appliedMarker(key: string): DocumentReference {
+    return firebase.firestore().collection(APPLIED_COLLECTION).doc(key) as unknown as DocumentReference;
+  }
+
+  static async isApplied(key: string): Promise<boolean> {
+    const snapshot = await firebase.firestore().collection(APPLIED_COLLECTION).doc(key).get();
+    return snapshot.exists;
+  }
+
+  /**
+   * Delete the applied marker of `key`, once its transaction left the offline queue
+   */
+  static async clearApplied(key: string): Promise<void> {
+    await firebase.firestore().collection(APPLIED_COLLECTION).doc(key).delete();
+  }
+
+  /**
+   * Read the current state of every document touched by this batch, before it is committed,
+   * so the batch can be reverted if a later batch of the same transaction fails.
+   */
+  async capture(): Promise<void> {
+    this.beforeImages = await Promise.all(
+      [...this.docRefs.values()].map(async (docRef) => {
+        const snapshot = await docRef.get();
+        return { docRef, data: snapshot.exists ? snapshot.data() : undefined };
+      }),
+    );
+  }
+
+  /**
+   * Restore the documents captured by `capture`.
+   *
+   * @returns the paths of the documents which could not be restored
+   */
+  async revert(): Promise<string[]> {
+    const beforeImages = this.beforeImages;
+    if (!beforeImages) return [...this.docRefs.keys()];
+
+    const batch = firebase.firestore().batch();
+    for (const { docRef, data } of beforeImages) {
+      if (data === undefined) {
+        batch.delete(docRef as any);
+      } else {
+        batch.set(docRef as any, data);
+      }
+    }
+    try {
+      await batch.commit();
+      return [];
+    } catch (e) {
+      console.error('FireBatch.revert', e);
+      return beforeImages.map(({ docRef }) => docRef.path);
+    }
   }
 }
 
@@ -0,0 +1,38 @@
+import { describe, expect, test } from '@jest/globals';
+import { firebase } from '../src/firebase';
+
+import { FireBatch, arrayUnion, arrayUnionElements } from '../src/transaction';
+
+const blob = (...bytes: number[]) => firebase.firestore.Blob.fromUint8Array(new Uint8Array(bytes));
+
+describe('FireBatch.combineFieldValues', () => {
+  test('coalesced content saves keep both updates', () => {
+    const [first, second] = [blob(1, 2), blob(3)];
+    const combined = FireBatch.combineFieldValues(arrayUnion(first), arrayUnion(second));
+
+    expect(arrayUnionElements(combined)).toEqual([first, second]);
+    expect(combined?.isEqual(firebase.firestore.FieldValue.arrayUnion(first, second))).toBe(true);
+  });
+
+  test('sentinels depending on the stored document are not combined', () => {
+    const FieldValue = firebase.firestore.FieldValue;
+
+    expect(FireBatch.combineFieldValues([blob(1)], arrayUnion(blob(2)))).toBeUndefined();
+    expect(FireBatch.combineFieldValues(FieldValue.increment(1), FieldValue.increment(1))).toBeUndefined();
+    const deleted = FieldValue.delete();
+    expect(FireBatch.combineFieldValues(arrayUnion(blob(1)), deleted)).toBe(deleted);
+  });
+});
+
+describe('FireBatch.encodeOp', () => {
+  test('content saves can be queued', () => {
+    const docRef = { path: 'notes/a' } as any;
+    const encoded = FireBatch.encodeOp(docRef, { content: arrayUnion(blob(1, 2)) }, true);
+
+    expect(encoded).toBeDefined();
+    expect(JSON.parse(encoded!).data.content).toEqual({
+      __edvoCodec: 'arrayUnion',
+      elements: [{ __edvoCodec: 'bytes', base64: blob(1, 2).toBase64() }],
+    });
+  });
+});
@@ -121,7 +121,7 @@ export const ExtensionPopup = ({ node }: Props) => {
   }, [sessionManager]);
 
   useObserveRs(sessionStatusObs);
-  const status: 'clean' | 'dirty' | 'init' = sessionStatusObs.get();
+  const status: 'clean' | 'dirty' | 'retrying' | 'offline' | 'error' | 'init' = sessionStatusObs.get();
 
   return (
     <ExtensionPopupSC ref={(r: HTMLElement | null) => node.safeBindDomElement(r)}>
@@ -140,7 +140,15 @@ export const ExtensionPopup = ({ node }: Props) => {
         </Button>
       </CardListRoot>
       <StatusIndicator>
-        {status === 'dirty' ? '⚠️ Saving...' : status === 'clean' ? '✅ Changes saved' : null}
+        {status === 'dirty'
+          ? '⚠️ Saving...'
+          : status === 'retrying'
+          ? '⚠️ Retrying...'
+          : status === 'offline'
+          ? '⚠️ Offline, changes will be saved once reconnected'
+          : status === 'clean'
+          ? '✅ Changes saved'
+          : null}
       </StatusIndicator>
     </ExtensionPopupSC>
   );